@@ -8,6 +8,8 @@ pub mod data_chunk;
 pub mod range;
 #[cfg(feature = "signatures")]
 pub mod signatures;
+#[cfg(feature = "semver")]
+pub mod version_policy;
 
 include!(concat!(env!("OUT_DIR"), "/messages.rs"));
 
@@ -2,7 +2,7 @@ use sha3::{Digest, Sha3_256};
 
 use subsquid_network_transport::{Keypair, PeerId, PublicKey};
 
-use crate::{Ping, ProstMsg, Query, QueryExecuted};
+use crate::{AssignmentSnapshot, Ping, ProstMsg, Query, QueryExecuted};
 
 pub fn msg_hash<M: ProstMsg>(msg: &M) -> Vec<u8> {
     let mut result = [0u8; 32];
@@ -59,6 +59,16 @@ impl SignedMessage for Query {
     }
 }
 
+impl SignedMessage for AssignmentSnapshot {
+    fn detach_signature(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.signature)
+    }
+
+    fn attach_signature(&mut self, signature: Vec<u8>) {
+        self.signature = signature;
+    }
+}
+
 impl SignedMessage for QueryExecuted {
     fn detach_signature(&mut self) -> Vec<u8> {
         std::mem::take(&mut self.signature)
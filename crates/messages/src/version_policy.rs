@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+
+/// Worker versions accepted by the network services, configurable without a rebuild.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WorkerVersionPolicy {
+    /// Versions which are accepted
+    pub supported: VersionReq,
+    /// Versions which are still accepted, but will be rejected after the deadline
+    #[serde(default)]
+    pub deprecated: Vec<VersionDeprecation>,
+    /// Feature name -> versions which support the feature.
+    /// Features not listed are considered supported by all versions.
+    #[serde(default)]
+    pub features: HashMap<String, VersionReq>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VersionDeprecation {
+    pub versions: VersionReq,
+    /// Unix timestamp (seconds) after which the versions are rejected
+    pub reject_after: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionStatus {
+    Supported,
+    Deprecated { reject_after: SystemTime },
+    Unsupported,
+}
+
+impl WorkerVersionPolicy {
+    pub fn new(supported: VersionReq) -> Self {
+        Self {
+            supported,
+            deprecated: Vec::new(),
+            features: HashMap::new(),
+        }
+    }
+
+    pub fn check(&self, version: &Version) -> VersionStatus {
+        if !self.supported.matches(version) {
+            return VersionStatus::Unsupported;
+        }
+        let reject_after = self
+            .deprecated
+            .iter()
+            .filter(|d| d.versions.matches(version))
+            .map(|d| UNIX_EPOCH + Duration::from_secs(d.reject_after))
+            .min();
+        match reject_after {
+            None => VersionStatus::Supported,
+            Some(t) if t <= SystemTime::now() => VersionStatus::Unsupported,
+            Some(reject_after) => VersionStatus::Deprecated { reject_after },
+        }
+    }
+
+    pub fn is_accepted(&self, version: &Version) -> bool {
+        self.check(version) != VersionStatus::Unsupported
+    }
+
+    pub fn supports_feature(&self, feature: &str, version: &Version) -> bool {
+        match self.features.get(feature) {
+            Some(versions) => versions.matches(version),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_policy() {
+        let mut policy = WorkerVersionPolicy::new(">=0.2.2".parse().unwrap());
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        policy.deprecated = vec![
+            VersionDeprecation {
+                versions: "<0.2.3".parse().unwrap(),
+                reject_after: now - 10,
+            },
+            VersionDeprecation {
+                versions: "<0.2.4".parse().unwrap(),
+                reject_after: now + 3600,
+            },
+        ];
+        policy
+            .features
+            .insert("feature".to_string(), ">=0.2.4".parse().unwrap());
+
+        let v = |s: &str| Version::parse(s).unwrap();
+        assert_eq!(policy.check(&v("0.2.1")), VersionStatus::Unsupported);
+        assert_eq!(policy.check(&v("0.2.2")), VersionStatus::Unsupported);
+        assert!(matches!(
+            policy.check(&v("0.2.3")),
+            VersionStatus::Deprecated { .. }
+        ));
+        assert_eq!(policy.check(&v("0.2.4")), VersionStatus::Supported);
+        assert!(!policy.supports_feature("feature", &v("0.2.3")));
+        assert!(policy.supports_feature("feature", &v("0.2.4")));
+        assert!(policy.supports_feature("other", &v("0.2.3")));
+    }
+}
@@ -0,0 +1,425 @@
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, TimestampMilliSeconds};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::Mutex;
+
+use subsquid_network_transport::PeerId;
+
+use crate::scheduling_unit::UnitId;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AssignmentAction {
+    Assigned,
+    Unassigned,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AssignmentCause {
+    /// Regular assignment of missing replicas
+    Scheduling,
+    /// Unit pinned to the worker by the operator
+    Pinned,
+    /// Worker already stored data of the unit
+    StoredData,
+    /// Replica removed to make room for a pinned one
+    ReplacedByPinned,
+    /// Random rebalancing of replicas
+    Mixing,
+    /// Another worker of the same operator holds a replica of the unit
+    Colocated,
+    /// Capacity reported by the worker shrunk
+    OverCapacity,
+    /// Unit grew and the worker had no capacity left for it
+    UnitResized,
+    /// Chunks of the unit were rewritten in the bucket
+    UnitInvalidated,
+    /// Units were bundled again with different limits
+    Rebundled,
+    /// Dataset was removed from the config
+    DatasetRemoved,
+    /// Unit forbidden on the worker by the operator
+    Forbidden,
+    /// Worker was jailed, with the kind of the reason
+    Jailed(String),
+    /// Worker is no longer registered
+    Unregistered,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AssignmentEvent {
+    #[serde_as(as = "TimestampMilliSeconds")]
+    pub timestamp: SystemTime,
+    pub epoch: u32,
+    pub unit_id: UnitId,
+    pub worker_id: PeerId,
+    pub action: AssignmentAction,
+    pub cause: AssignmentCause,
+}
+
+/// Assignment changes made by the scheduler, waiting to be appended to the audit log.
+/// Only the current epoch is persisted with the scheduler state.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct AuditBuffer {
+    epoch: u32,
+    #[serde(skip)]
+    events: Vec<AssignmentEvent>,
+}
+
+impl AuditBuffer {
+    pub fn set_epoch(&mut self, epoch: u32) {
+        self.epoch = epoch;
+    }
+
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    fn record(
+        &mut self,
+        action: AssignmentAction,
+        unit_id: UnitId,
+        worker_id: PeerId,
+        cause: AssignmentCause,
+    ) {
+        self.events.push(AssignmentEvent {
+            timestamp: SystemTime::now(),
+            epoch: self.epoch,
+            unit_id,
+            worker_id,
+            action,
+            cause,
+        })
+    }
+
+    pub fn assigned(&mut self, unit_id: UnitId, worker_id: PeerId, cause: AssignmentCause) {
+        self.record(AssignmentAction::Assigned, unit_id, worker_id, cause)
+    }
+
+    pub fn unassigned(&mut self, unit_id: UnitId, worker_id: PeerId, cause: AssignmentCause) {
+        self.record(AssignmentAction::Unassigned, unit_id, worker_id, cause)
+    }
+
+    pub fn take(&mut self) -> Vec<AssignmentEvent> {
+        std::mem::take(&mut self.events)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuditQuery {
+    pub worker: Option<PeerId>,
+    pub unit: Option<UnitId>,
+    pub limit: Option<usize>,
+}
+
+const DEFAULT_QUERY_LIMIT: usize = 1000;
+
+/// Append-only log of assignment events, stored as JSON lines. When the file grows
+/// over `max_bytes`, it is moved to `<path>.<timestamp>` and a new one is started.
+/// Rotated files are never overwritten or deleted.
+#[derive(Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+    max_bytes: u64,
+    file: Arc<Mutex<LogFile>>,
+}
+
+struct LogFile {
+    file: File,
+    size: u64,
+}
+
+impl AuditLog {
+    pub async fn open(path: impl AsRef<Path>, max_bytes: u64) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = Self::open_file(&path).await?;
+        Ok(Self {
+            path,
+            max_bytes,
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    async fn open_file(path: &Path) -> anyhow::Result<LogFile> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        let size = file.metadata().await?.len();
+        Ok(LogFile { file, size })
+    }
+
+    fn rotated_path(&self, timestamp: u128) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{timestamp}"));
+        path.into()
+    }
+
+    /// Paths of the rotated files, from the oldest to the newest
+    async fn rotated_paths(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let prefix = match self.path.file_name() {
+            Some(name) => format!("{}.", name.to_string_lossy()),
+            None => return Ok(Vec::new()),
+        };
+        let mut rotated = Vec::new();
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let timestamp = name
+                .to_str()
+                .and_then(|name| name.strip_prefix(&prefix))
+                .and_then(|suffix| suffix.parse::<u128>().ok());
+            if let Some(timestamp) = timestamp {
+                rotated.push((timestamp, entry.path()));
+            }
+        }
+        rotated.sort();
+        Ok(rotated.into_iter().map(|(_, path)| path).collect())
+    }
+
+    async fn rotate(&self, file: &mut LogFile) -> anyhow::Result<()> {
+        let mut timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time after epoch")
+            .as_millis();
+        while tokio::fs::try_exists(self.rotated_path(timestamp)).await? {
+            timestamp += 1;
+        }
+        let rotated_path = self.rotated_path(timestamp);
+        tokio::fs::rename(&self.path, &rotated_path).await?;
+        log::info!("Rotated audit log to {}", rotated_path.display());
+        *file = Self::open_file(&self.path).await?;
+        Ok(())
+    }
+
+    pub async fn append(&self, events: &[AssignmentEvent]) -> anyhow::Result<()> {
+        if events.is_empty() {
+            return Ok(());
+        }
+        let mut bytes = Vec::new();
+        for event in events {
+            serde_json::to_writer(&mut bytes, event)?;
+            bytes.push(b'\n');
+        }
+        let mut file = self.file.lock().await;
+        if file.size > 0 && file.size + bytes.len() as u64 > self.max_bytes {
+            self.rotate(&mut file).await?;
+        }
+        file.file.write_all(&bytes).await?;
+        file.file.flush().await?;
+        file.size += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Find the most recent events matching the query, in chronological order.
+    /// Files are read from the newest one back until enough events are found.
+    pub async fn query(&self, query: &AuditQuery) -> anyhow::Result<Vec<AssignmentEvent>> {
+        let limit = query.limit.unwrap_or(DEFAULT_QUERY_LIMIT);
+        let mut paths = self.rotated_paths().await?;
+        paths.push(self.path.clone());
+        let mut result = VecDeque::new();
+        for path in paths.iter().rev() {
+            if result.len() >= limit {
+                break;
+            }
+            let events = Self::query_file(path, query, limit - result.len()).await?;
+            for event in events.into_iter().rev() {
+                result.push_front(event);
+            }
+        }
+        Ok(result.into())
+    }
+
+    /// The last `limit` events in the file matching the query
+    async fn query_file(
+        path: &Path,
+        query: &AuditQuery,
+        limit: usize,
+    ) -> anyhow::Result<VecDeque<AssignmentEvent>> {
+        let worker = query.worker.map(|id| id.to_string());
+        let unit = query.unit.map(|id| id.to_string());
+        let mut result = VecDeque::with_capacity(limit.min(DEFAULT_QUERY_LIMIT));
+        let file = match File::open(path).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(result),
+            Err(e) => return Err(e.into()),
+        };
+        let mut lines = BufReader::new(file).lines();
+        while let Some(line) = lines.next_line().await? {
+            // Filter before parsing, most lines don't match
+            if worker.as_ref().is_some_and(|id| !line.contains(id))
+                || unit.as_ref().is_some_and(|id| !line.contains(id))
+            {
+                continue;
+            }
+            let event: AssignmentEvent = match serde_json::from_str(&line) {
+                Ok(event) => event,
+                Err(e) => {
+                    log::warn!("Skipping invalid audit log line in {}: {e}", path.display());
+                    continue;
+                }
+            };
+            if query.worker.is_some_and(|id| id != event.worker_id)
+                || query.unit.is_some_and(|id| id != event.unit_id)
+            {
+                continue;
+            }
+            if result.len() == limit {
+                result.pop_front();
+            }
+            result.push_back(event);
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_chunk::DataChunk;
+
+    fn unit_id(index: u32) -> UnitId {
+        let first_block = index * 1000;
+        let chunk_str = format!(
+            "0000000000/{first_block:010}-{:010}-00000000",
+            first_block + 999
+        );
+        DataChunk::new("dataset", &chunk_str, 0).unwrap().id()
+    }
+
+    /// Path of a new file in the temporary directory
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("audit-{}.log", rand::random::<u64>()))
+    }
+
+    fn test_events(buffer: &mut AuditBuffer, unit_id: UnitId, workers: &[PeerId]) {
+        for worker_id in workers {
+            buffer.assigned(unit_id, *worker_id, AssignmentCause::Scheduling);
+        }
+    }
+
+    #[test]
+    fn test_record() {
+        let mut buffer = AuditBuffer::default();
+        buffer.set_epoch(5);
+        let unit_id = unit_id(0);
+        let worker_id = PeerId::random();
+        buffer.assigned(unit_id, worker_id, AssignmentCause::Pinned);
+        buffer.unassigned(
+            unit_id,
+            worker_id,
+            AssignmentCause::Jailed("stale".to_string()),
+        );
+
+        let events = buffer.take();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].epoch, 5);
+        assert_eq!(events[0].action, AssignmentAction::Assigned);
+        assert_eq!(events[0].cause, AssignmentCause::Pinned);
+        assert_eq!(events[1].action, AssignmentAction::Unassigned);
+        assert!(buffer.take().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_query() {
+        let log = AuditLog::open(temp_path(), 1 << 20).await.unwrap();
+        let workers = [PeerId::random(), PeerId::random()];
+        let mut buffer = AuditBuffer::default();
+        for i in 0..3 {
+            test_events(&mut buffer, unit_id(i), &workers);
+        }
+        log.append(&buffer.take()).await.unwrap();
+        // Broken lines are skipped
+        log.file.lock().await.file.write_all(b"{\n").await.unwrap();
+
+        let query = |worker, unit, limit| AuditQuery {
+            worker,
+            unit,
+            limit,
+        };
+        let events = log.query(&query(None, None, None)).await.unwrap();
+        assert_eq!(events.len(), 6);
+
+        let events = log
+            .query(&query(Some(workers[1]), None, None))
+            .await
+            .unwrap();
+        assert_eq!(events.len(), 3);
+        assert!(events.iter().all(|e| e.worker_id == workers[1]));
+
+        let events = log
+            .query(&query(Some(workers[0]), Some(unit_id(1)), None))
+            .await
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].unit_id, unit_id(1));
+
+        // The most recent events are returned
+        let events = log.query(&query(None, None, Some(2))).await.unwrap();
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|e| e.unit_id == unit_id(2)));
+        assert!(log
+            .query(&query(None, None, Some(0)))
+            .await
+            .unwrap()
+            .is_empty());
+
+        tokio::fs::remove_file(&log.path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rotation() {
+        let path = temp_path();
+        let log = AuditLog::open(&path, 500).await.unwrap();
+        let workers = [PeerId::random()];
+        let mut buffer = AuditBuffer::default();
+        for i in 0..10 {
+            test_events(&mut buffer, unit_id(i), &workers);
+            log.append(&buffer.take()).await.unwrap();
+        }
+        assert!(tokio::fs::metadata(&path).await.unwrap().len() <= 500);
+        // All rotated files are kept, so no events are lost
+        let rotated = log.rotated_paths().await.unwrap();
+        assert!(rotated.len() > 1);
+        let events = log
+            .query(&AuditQuery {
+                worker: None,
+                unit: None,
+                limit: None,
+            })
+            .await
+            .unwrap();
+        let unit_ids: Vec<UnitId> = events.iter().map(|e| e.unit_id).collect();
+        assert_eq!(unit_ids, (0..10).map(unit_id).collect::<Vec<_>>());
+
+        // Limited queries only need the newest files
+        let events = log
+            .query(&AuditQuery {
+                worker: None,
+                unit: None,
+                limit: Some(3),
+            })
+            .await
+            .unwrap();
+        let unit_ids: Vec<UnitId> = events.iter().map(|e| e.unit_id).collect();
+        assert_eq!(unit_ids, (7..10).map(unit_id).collect::<Vec<_>>());
+
+        tokio::fs::remove_file(&path).await.unwrap();
+        for path in rotated {
+            tokio::fs::remove_file(path).await.unwrap();
+        }
+    }
+}
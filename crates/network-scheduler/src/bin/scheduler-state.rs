@@ -0,0 +1,155 @@
+//! Offline tool for inspecting and repairing the persisted scheduler state.
+
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::{Parser, Subcommand};
+use env_logger::Env;
+
+use network_scheduler::cli::Config;
+use network_scheduler::scheduler::Scheduler;
+use network_scheduler::storage::S3Storage;
+
+#[derive(Parser)]
+#[command(version, about = "Inspect and repair the scheduler state")]
+struct Cli {
+    #[arg(
+        short,
+        long,
+        env = "CONFIG_PATH",
+        help = "Path to config file",
+        default_value = "config.yml"
+    )]
+    config: PathBuf,
+
+    #[arg(
+        long,
+        help = "Read the state from a local file instead of S3",
+        conflicts_with = "scheduler_id"
+    )]
+    file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Peer ID of the scheduler whose state is stored in S3",
+        required_unless_present = "file"
+    )]
+    scheduler_id: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print assigned bytes per worker, replication per dataset and jailed workers
+    Summary,
+    /// Check the state for inconsistencies. Exits with status 1 if any are found.
+    Check,
+    /// Fix inconsistencies and save the state, keeping a backup of the original
+    Repair {
+        #[arg(long, help = "Only print what would be fixed")]
+        dry_run: bool,
+        #[arg(long, help = "Save the state even if a running scheduler holds the lease")]
+        force: bool,
+    },
+}
+
+enum Backend {
+    File(PathBuf),
+    S3(S3Storage),
+}
+
+impl Backend {
+    async fn load(&self) -> anyhow::Result<Vec<u8>> {
+        match self {
+            Backend::File(path) => Ok(tokio::fs::read(path).await?),
+            Backend::S3(storage) => storage
+                .load_state()
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("Scheduler state not found")),
+        }
+    }
+
+    async fn save(&self, original: Vec<u8>, state: Vec<u8>, force: bool) -> anyhow::Result<()> {
+        match self {
+            Backend::File(path) => {
+                let timestamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .expect("time after epoch")
+                    .as_millis();
+                let mut backup_path = path.clone().into_os_string();
+                backup_path.push(format!(".backup-{timestamp}"));
+                tokio::fs::write(&backup_path, original).await?;
+                log::info!("Original state saved to {}", backup_path.to_string_lossy());
+                tokio::fs::write(path, state).await?;
+            }
+            Backend::S3(storage) => {
+                if let Some(holder) = storage.lease_holder().await? {
+                    anyhow::ensure!(
+                        force,
+                        "Scheduler instance {holder} holds the lease and would overwrite the state. \
+                        Stop it or use --force."
+                    );
+                    log::warn!("Overwriting state while {holder} holds the lease");
+                }
+                let backup_key = storage.save_state_backup(original).await?;
+                log::info!("Original state saved to {backup_key}");
+                storage.save_state(state).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    env_logger::Builder::from_env(Env::default().default_filter_or("info, aws_config=warn"))
+        .init();
+    let args: Cli = Cli::parse();
+    Config::init(&args.config).await?;
+
+    let backend = match (args.file, args.scheduler_id) {
+        (Some(path), _) => Backend::File(path),
+        (None, Some(scheduler_id)) => {
+            Backend::S3(S3Storage::new(scheduler_id, "scheduler-state".to_string()).await)
+        }
+        (None, None) => unreachable!("enforced by clap"),
+    };
+    let original = backend.load().await?;
+    // Load the state as is, without the migrations done on scheduler startup
+    let mut scheduler: Scheduler = serde_json::from_slice(&original)?;
+
+    match args.command {
+        Command::Summary => {
+            println!("{}", serde_json::to_string_pretty(&scheduler.summary())?);
+        }
+        Command::Check => {
+            let issues = scheduler.check_consistency();
+            for issue in issues.iter() {
+                println!("{issue}");
+            }
+            if !issues.is_empty() {
+                log::warn!("Found {} inconsistencies", issues.len());
+                std::process::exit(1);
+            }
+            log::info!("State is consistent");
+        }
+        Command::Repair { dry_run, force } => {
+            let issues = scheduler.repair();
+            for issue in issues.iter() {
+                println!("{issue}");
+            }
+            if issues.is_empty() {
+                log::info!("State is consistent, nothing to repair");
+            } else if dry_run {
+                log::info!("Dry run, {} inconsistencies not fixed", issues.len());
+            } else {
+                let state = serde_json::to_vec(&scheduler)?;
+                backend.save(original, state, force).await?;
+                log::info!("Fixed {} inconsistencies", issues.len());
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,55 +1,501 @@
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::path::PathBuf;
-use std::time::Duration;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use clap::Parser;
+use clap::{Args, Parser, ValueEnum};
 use contract_client::RpcArgs;
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DurationSeconds};
 use tokio::sync::OnceCell;
 
+use subsquid_messages::version_policy::WorkerVersionPolicy;
 use subsquid_network_transport::cli::TransportArgs;
 
-static CONFIG: OnceCell<Config> = OnceCell::const_new();
+use crate::scheduling_unit::UnitLimits;
+
+static CONFIG: RwLock<Option<Arc<Config>>> = RwLock::new(None);
+static CONFIG_PATH: OnceCell<PathBuf> = OnceCell::const_new();
+
+fn default_required_files() -> Vec<String> {
+    vec!["blocks.parquet".to_string()]
+}
+
+fn default_reorg_check_depth() -> usize {
+    10
+}
+
+fn default_listing_concurrency() -> usize {
+    8
+}
+
+fn default_retirement_min_replicas() -> usize {
+    1
+}
+
+fn default_worker_versions() -> WorkerVersionPolicy {
+    let mut policy = WorkerVersionPolicy::new(">=0.2.2, <=0.2.3".parse().unwrap());
+    policy
+        .features
+        .insert("jail_reason".to_string(), ">=0.2.2".parse().unwrap());
+    policy
+}
+
+fn default_bad_chunk_min_workers() -> usize {
+    3
+}
+
+fn default_bad_chunk_cooldown() -> Duration {
+    Duration::from_secs(3600)
+}
+
+fn default_worker_max_sync_time() -> Duration {
+    Duration::from_secs(24 * 3600)
+}
+
+fn default_incremental_schedule_interval() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_assignment_snapshot_interval() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_leader_lease_duration() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_jail_backoff_base() -> Duration {
+    Duration::from_secs(3600)
+}
+
+fn default_jail_backoff_max() -> Duration {
+    Duration::from_secs(7 * 24 * 3600)
+}
+
+fn default_jail_backoff_window() -> Duration {
+    Duration::from_secs(7 * 24 * 3600)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DatasetManifest {
+    /// Files which must be present for a chunk to be scheduled
+    #[serde(default = "default_required_files")]
+    pub required_files: Vec<String>,
+    /// Compare object checksums (ETags) between listings to detect chunks rewritten in place
+    #[serde(default)]
+    pub verify_checksums: bool,
+    /// Block ranges permanently missing from the dataset. Ingestion continues past them.
+    #[serde(default)]
+    pub acknowledged_gaps: Vec<(u32, u32)>,
+}
+
+/// Dataset which no longer receives new units. Its existing replicas are served until
+/// the deadline passes or any of its units drops below `min_replicas`, then it's removed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DatasetRetirement {
+    /// Unix timestamp (seconds) after which the dataset is removed
+    #[serde(default)]
+    pub remove_after: Option<u64>,
+    /// Remove the dataset once any of its units has fewer replicas. Lost replicas are not
+    /// reassigned. 0 means only the deadline applies.
+    #[serde(default = "default_retirement_min_replicas")]
+    pub min_replicas: usize,
+}
+
+impl DatasetRetirement {
+    pub fn deadline_passed(&self) -> bool {
+        match self.remove_after {
+            Some(t) => UNIX_EPOCH + Duration::from_secs(t) <= SystemTime::now(),
+            None => false,
+        }
+    }
+}
+
+impl DatasetManifest {
+    pub fn is_gap_acknowledged(&self, first_block: u32, last_block: u32) -> bool {
+        self.acknowledged_gaps
+            .iter()
+            .any(|(begin, end)| *begin <= first_block && last_block <= *end)
+    }
+}
+
+impl Default for DatasetManifest {
+    fn default() -> Self {
+        Self {
+            required_files: default_required_files(),
+            verify_checksums: false,
+            acknowledged_gaps: Vec::new(),
+        }
+    }
+}
 
 #[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub schedule_interval_epochs: u32,
     #[serde_as(as = "DurationSeconds")]
+    #[serde(
+        rename = "incremental_schedule_interval_sec",
+        default = "default_incremental_schedule_interval"
+    )]
+    pub incremental_schedule_interval: Duration,
+    #[serde_as(as = "DurationSeconds")]
+    #[serde(
+        rename = "assignment_snapshot_interval_sec",
+        default = "default_assignment_snapshot_interval"
+    )]
+    pub assignment_snapshot_interval: Duration,
+    #[serde_as(as = "DurationSeconds")]
     #[serde(rename = "worker_inactive_timeout_sec")]
     pub worker_inactive_timeout: Duration,
     #[serde_as(as = "DurationSeconds")]
     #[serde(rename = "worker_stale_timeout_sec")]
     pub worker_stale_timeout: Duration,
     #[serde_as(as = "DurationSeconds")]
+    #[serde(
+        rename = "worker_max_sync_time_sec",
+        default = "default_worker_max_sync_time"
+    )]
+    pub worker_max_sync_time: Duration,
+    /// Number of workers failing to download a chunk for it to be considered bad data
+    #[serde(default = "default_bad_chunk_min_workers")]
+    pub bad_chunk_min_workers: usize,
+    /// Time a bad chunk stays flagged after it stops failing on enough workers
+    #[serde_as(as = "DurationSeconds")]
+    #[serde(
+        rename = "bad_chunk_cooldown_sec",
+        default = "default_bad_chunk_cooldown"
+    )]
+    pub bad_chunk_cooldown: Duration,
+    #[serde_as(as = "DurationSeconds")]
     #[serde(rename = "worker_unreachable_timeout_sec")]
     pub worker_unreachable_timeout: Duration,
     #[serde_as(as = "DurationSeconds")]
+    #[serde(
+        rename = "jail_backoff_base_sec",
+        default = "default_jail_backoff_base"
+    )]
+    pub jail_backoff_base: Duration,
+    #[serde_as(as = "DurationSeconds")]
+    #[serde(rename = "jail_backoff_max_sec", default = "default_jail_backoff_max")]
+    pub jail_backoff_max: Duration,
+    #[serde_as(as = "DurationSeconds")]
+    #[serde(
+        rename = "jail_backoff_window_sec",
+        default = "default_jail_backoff_window"
+    )]
+    pub jail_backoff_window: Duration,
+    #[serde_as(as = "DurationSeconds")]
+    #[serde(
+        rename = "leader_lease_duration_sec",
+        default = "default_leader_lease_duration"
+    )]
+    pub leader_lease_duration: Duration,
+    #[serde_as(as = "DurationSeconds")]
     #[serde(rename = "failed_dial_retry_sec")]
     pub failed_dial_retry: Duration,
     #[serde_as(as = "DurationSeconds")]
     #[serde(rename = "successful_dial_retry_sec")]
     pub successful_dial_retry: Duration,
     pub replication_factor: usize,
+    /// Keep replicas of a unit on workers with distinct owner addresses. Replicas aren't spread
+    /// across network subnets, the transport doesn't expose addresses of the peers.
+    #[serde(default)]
+    pub spread_replicas_across_operators: bool,
+    /// Maximum number of chunks in a scheduling unit
     pub scheduling_unit_size: usize,
+    /// Target size of a scheduling unit in bytes. If not set, units are bundled by chunk count only.
+    #[serde(default)]
+    pub scheduling_unit_bytes: Option<u64>,
     pub worker_storage_bytes: u64,
+    #[serde(default)]
+    pub max_worker_storage_bytes: Option<u64>,
+    /// Maximum bytes newly assigned to a single worker in one epoch
+    #[serde(default)]
+    pub max_assigned_bytes_per_worker: Option<u64>,
+    /// Maximum bytes newly assigned to all workers in one epoch
+    #[serde(default)]
+    pub max_assigned_bytes: Option<u64>,
     pub mixed_units_ratio: f64,
     pub mixing_recent_unit_weight: f64,
     pub s3_endpoint: String,
     pub dataset_buckets: Vec<String>,
+    /// Bucket -> retirement policy of datasets being phased out
+    #[serde(default)]
+    pub retiring_datasets: HashMap<String, DatasetRetirement>,
+    #[serde(default)]
+    pub dataset_manifests: HashMap<String, DatasetManifest>,
+    #[serde(default = "default_reorg_check_depth")]
+    pub reorg_check_depth: usize,
+    /// Number of top-level prefixes of a bucket listed in parallel
+    #[serde(default = "default_listing_concurrency")]
+    pub listing_concurrency: usize,
     pub scheduler_state_bucket: String,
+    #[serde(default = "default_worker_versions")]
+    pub worker_versions: WorkerVersionPolicy,
 }
 
 impl Config {
     #[inline(always)]
-    pub fn get() -> &'static Self {
-        CONFIG.get().expect("Config not initialized")
+    pub fn get() -> Arc<Self> {
+        CONFIG
+            .read()
+            .expect("Config lock poisoned")
+            .clone()
+            .expect("Config not initialized")
+    }
+
+    fn set(config: Self) {
+        *CONFIG.write().expect("Config lock poisoned") = Some(Arc::new(config));
+    }
+
+    /// Install the default config with `update` applied. Tests depending on the config
+    /// hold the returned guard, so that they don't interfere with each other.
+    #[cfg(test)]
+    pub(crate) fn set_for_test(
+        update: impl FnOnce(&mut Self),
+    ) -> std::sync::MutexGuard<'static, ()> {
+        static TEST_CONFIG_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+        let guard = TEST_CONFIG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut config: Self = serde_yaml::from_str(include_str!("../config.yml")).unwrap();
+        update(&mut config);
+        Self::set(config);
+        guard
+    }
+
+    /// Load the config file and make it available through `Config::get`
+    pub async fn init(path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let config = Self::load(&path).await?;
+        CONFIG_PATH.set(path.as_ref().to_path_buf())?;
+        Self::set(config);
+        Ok(())
+    }
+
+    async fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let file_contents = tokio::fs::read(path).await?;
+        let config: Self = serde_yaml::from_slice(file_contents.as_slice())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.schedule_interval_epochs > 0,
+            "schedule_interval_epochs must be positive"
+        );
+        anyhow::ensure!(
+            self.replication_factor > 0,
+            "replication_factor must be positive"
+        );
+        anyhow::ensure!(
+            self.scheduling_unit_size > 0,
+            "scheduling_unit_size must be positive"
+        );
+        anyhow::ensure!(
+            self.max_assigned_bytes_per_worker != Some(0) && self.max_assigned_bytes != Some(0),
+            "assignment limits must be positive"
+        );
+        anyhow::ensure!(
+            self.bad_chunk_min_workers > 0,
+            "bad_chunk_min_workers must be positive"
+        );
+        anyhow::ensure!(
+            self.listing_concurrency > 0,
+            "listing_concurrency must be positive"
+        );
+        anyhow::ensure!(
+            self.scheduling_unit_bytes != Some(0),
+            "scheduling_unit_bytes must be positive"
+        );
+        anyhow::ensure!(
+            (0.0..=1.0).contains(&self.mixed_units_ratio),
+            "mixed_units_ratio must be between 0 and 1"
+        );
+        anyhow::ensure!(
+            self.mixing_recent_unit_weight >= 1.0,
+            "mixing_recent_unit_weight must be at least 1"
+        );
+        anyhow::ensure!(
+            !self.worker_inactive_timeout.is_zero()
+                && !self.worker_stale_timeout.is_zero()
+                && !self.worker_unreachable_timeout.is_zero(),
+            "worker timeouts must be positive"
+        );
+        anyhow::ensure!(
+            !self.leader_lease_duration.is_zero(),
+            "leader_lease_duration must be positive"
+        );
+        anyhow::ensure!(
+            self.jail_backoff_max >= self.jail_backoff_base,
+            "jail_backoff_max can't be less than jail_backoff_base"
+        );
+        anyhow::ensure!(
+            self.reorg_check_depth > 0,
+            "reorg_check_depth must be positive"
+        );
+        for (bucket, retirement) in self.retiring_datasets.iter() {
+            anyhow::ensure!(
+                !self.dataset_buckets.contains(bucket),
+                "retiring dataset {bucket} can't be in dataset_buckets"
+            );
+            anyhow::ensure!(
+                retirement.min_replicas <= self.replication_factor,
+                "min_replicas of retiring dataset {bucket} exceeds replication_factor"
+            );
+        }
+        Ok(())
+    }
+
+    /// Re-read the config file. Values which are used on the fly (timeouts, replication
+    /// and mixing parameters) take effect immediately. Periods of the background tasks
+    /// are only updated on restart.
+    pub async fn reload() -> anyhow::Result<()> {
+        let path = CONFIG_PATH.get().expect("Config not initialized");
+        let config = Self::load(path).await?;
+        let current = Self::get();
+        anyhow::ensure!(
+            config.s3_endpoint == current.s3_endpoint
+                && config.scheduler_state_bucket == current.scheduler_state_bucket,
+            "s3_endpoint and scheduler_state_bucket can't be changed without restart"
+        );
+        for ((name, old), (_, new)) in current
+            .task_periods()
+            .into_iter()
+            .zip(config.task_periods())
+        {
+            if old != new {
+                log::warn!("{name} changed from {old:?} to {new:?}, it will be applied on restart");
+            }
+        }
+        Self::set(config);
+        log::info!("Config reloaded from {}", path.display());
+        Ok(())
+    }
+
+    pub fn dataset_manifest(&self, bucket: &str) -> DatasetManifest {
+        self.dataset_manifests
+            .get(bucket)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Retirement policy of the dataset with the given URL, if it's being retired
+    pub fn dataset_retirement(&self, dataset_url: &str) -> Option<&DatasetRetirement> {
+        let bucket = dataset_url.strip_prefix("s3://").unwrap_or(dataset_url);
+        self.retiring_datasets.get(bucket)
+    }
+
+    pub fn unit_limits(&self) -> UnitLimits {
+        UnitLimits {
+            max_chunks: self.scheduling_unit_size,
+            max_bytes: self.scheduling_unit_bytes,
+        }
+    }
+
+    /// Values which set the periods of the background tasks
+    fn task_periods(&self) -> [(&'static str, Duration); 6] {
+        [
+            (
+                "incremental_schedule_interval",
+                self.incremental_schedule_interval,
+            ),
+            (
+                "assignment_snapshot_interval",
+                self.assignment_snapshot_interval,
+            ),
+            ("leader_lease_duration", self.leader_lease_duration),
+            ("worker_inactive_timeout", self.worker_inactive_timeout),
+            ("worker_stale_timeout", self.worker_stale_timeout),
+            (
+                "worker_unreachable_timeout",
+                self.worker_unreachable_timeout,
+            ),
+        ]
     }
 
     pub fn worker_monitoring_interval(&self) -> Duration {
         self.worker_inactive_timeout / 2
     }
+
+    /// Upper bound for the storage capacity reported by a worker.
+    /// Defaults to `worker_storage_bytes`, so workers can only report less.
+    pub fn max_worker_storage_bytes(&self) -> u64 {
+        self.max_worker_storage_bytes
+            .unwrap_or(self.worker_storage_bytes)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MetricsSinkKind {
+    Stdout,
+    File,
+    Clickhouse,
+    Webhook,
+}
+
+#[derive(Args)]
+pub struct MetricsArgs {
+    #[arg(
+        long,
+        env,
+        help = "Where to write metrics. Defaults to file if metrics path is set, stdout otherwise."
+    )]
+    pub metrics_sink: Option<MetricsSinkKind>,
+
+    #[arg(long, env, help = "Path to save metrics (file sink)")]
+    pub metrics_path: Option<PathBuf>,
+
+    #[arg(
+        long,
+        env,
+        help = "Rotate metrics file when it exceeds the given size (bytes)"
+    )]
+    pub metrics_rotate_size: Option<u64>,
+
+    #[arg(long, env, help = "Rotate metrics file after the given time (seconds)")]
+    pub metrics_rotate_interval_sec: Option<u64>,
+
+    #[arg(long, env, help = "Compress rotated metrics files with gzip")]
+    pub metrics_compress: bool,
+
+    #[arg(long, env, help = "ClickHouse URL (clickhouse sink)")]
+    pub metrics_clickhouse_url: Option<String>,
+
+    #[arg(long, env, default_value = "default")]
+    pub metrics_clickhouse_database: String,
+
+    #[arg(long, env, default_value = "default")]
+    pub metrics_clickhouse_user: String,
+
+    #[arg(long, env, default_value = "")]
+    pub metrics_clickhouse_password: String,
+
+    #[arg(
+        long,
+        env,
+        help = "URL to which metrics are POSTed as JSON arrays (webhook sink)"
+    )]
+    pub metrics_webhook_url: Option<String>,
+
+    #[arg(
+        long,
+        env,
+        help = "Maximum number of metrics waiting to be written. When full, new metrics are dropped.",
+        default_value = "10000"
+    )]
+    pub metrics_buffer_size: usize,
+
+    #[arg(
+        long,
+        env,
+        help = "Choose which metrics should be printed.",
+        value_delimiter = ',',
+        num_args = 0..,
+        default_value = "QuerySubmitted,QueryFinished,WorkersSnapshot"
+    )]
+    pub metrics: Vec<String>,
 }
 
 #[derive(Parser)]
@@ -72,19 +518,34 @@ pub struct Cli {
     #[arg(
         long,
         env,
-        help = "Path to save metrics. If not present, stdout is used."
+        help = "Bearer token required by the admin API. If not present, the API is disabled."
     )]
-    pub metrics_path: Option<PathBuf>,
+    pub admin_token: Option<String>,
 
     #[arg(
         long,
         env,
-        help = "Choose which metrics should be printed.",
-        value_delimiter = ',',
-        num_args = 0..,
-        default_value = "QuerySubmitted,QueryFinished,WorkersSnapshot"
+        help = "Path of the assignment audit log. If not present, assignment changes are not recorded."
     )]
-    pub metrics: Vec<String>,
+    pub audit_log_path: Option<PathBuf>,
+
+    #[arg(
+        long,
+        env,
+        help = "Size of the audit log after which it is moved to <path>.<timestamp> and a new one is started.",
+        default_value = "1073741824"
+    )]
+    pub audit_log_max_bytes: u64,
+
+    #[arg(
+        long,
+        env = "HOSTNAME",
+        help = "Identifier of this instance used in leader election. Random if not present."
+    )]
+    pub instance_id: Option<String>,
+
+    #[command(flatten)]
+    pub metrics_args: MetricsArgs,
 
     #[arg(
         short,
@@ -98,9 +559,39 @@ pub struct Cli {
 
 impl Cli {
     pub async fn read_config(&self) -> anyhow::Result<()> {
-        let file_contents = tokio::fs::read(&self.config).await?;
-        let config = serde_yaml::from_slice(file_contents.as_slice())?;
-        CONFIG.set(config)?;
-        Ok(())
+        Config::init(&self.config).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_config() {
+        let mut config: Config = serde_yaml::from_str(include_str!("../config.yml")).unwrap();
+        assert!(config.validate().is_ok());
+
+        config.mixed_units_ratio = 1.5;
+        assert!(config.validate().is_err());
+        config.mixed_units_ratio = 0.1;
+
+        let retirement = DatasetRetirement {
+            remove_after: None,
+            min_replicas: 1,
+        };
+        let bucket = config.dataset_buckets[0].clone();
+        config.retiring_datasets.insert(bucket, retirement);
+        assert!(config.validate().is_err());
+        config.retiring_datasets.clear();
+
+        config.jail_backoff_max = config.jail_backoff_base / 2;
+        assert!(config.validate().is_err());
+        config.jail_backoff_max = config.jail_backoff_base;
+
+        config.reorg_check_depth = 0;
+        assert!(config.validate().is_err());
+        config.reorg_check_depth = 1;
+        assert!(config.validate().is_ok());
     }
 }
@@ -18,6 +18,29 @@ impl Display for ChunkId {
     }
 }
 
+impl ChunkId {
+    /// Distinct ID derived from this one, e.g. for a new version of the same data
+    pub fn with_generation(&self, generation: u32) -> Self {
+        let mut result = [0u8; 32];
+        let mut hasher = Sha3_256::default();
+        hasher.update(self.0);
+        hasher.update(generation.to_be_bytes());
+        Digest::finalize_into(hasher, result.as_mut_slice().into());
+        Self(result)
+    }
+}
+
+impl FromStr for ChunkId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut result = [0u8; 32];
+        hex::decode_to_slice(s, &mut result)
+            .map_err(|_| anyhow::anyhow!("Invalid chunk ID: {s}"))?;
+        Ok(Self(result))
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct DataChunk {
     pub dataset_url: String,
@@ -86,6 +109,10 @@ mod tests {
                 0x52, 0xea, 0x53, 0x90
             ])
         );
+        assert_eq!(
+            chunk.id().to_string().parse::<ChunkId>().unwrap(),
+            chunk.id()
+        );
     }
 
     #[test]
@@ -0,0 +1,15 @@
+pub mod audit_log;
+pub mod cli;
+pub mod data_chunk;
+mod messages;
+pub mod metrics;
+pub mod metrics_server;
+pub mod prometheus_metrics;
+pub mod scheduler;
+pub mod scheduling_unit;
+pub mod server;
+pub mod storage;
+pub mod worker_state;
+
+pub const PING_TOPIC: &str = "worker_ping";
+pub const ASSIGNMENT_TOPIC: &str = "assignment_snapshot";
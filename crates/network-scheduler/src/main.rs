@@ -4,23 +4,13 @@ use prometheus_client::registry::Registry;
 
 use subsquid_network_transport::transport::P2PTransportBuilder;
 
-use crate::cli::Cli;
-use crate::metrics::MetricsWriter;
-use crate::server::Server;
-use crate::storage::S3Storage;
-
-mod cli;
-mod data_chunk;
-mod messages;
-mod metrics;
-mod metrics_server;
-mod scheduler;
-mod scheduling_unit;
-mod server;
-mod storage;
-mod worker_state;
-
-const PING_TOPIC: &str = "worker_ping";
+use network_scheduler::audit_log::AuditLog;
+use network_scheduler::cli::Cli;
+use network_scheduler::metrics::MetricsWriter;
+use network_scheduler::prometheus_metrics;
+use network_scheduler::server::Server;
+use network_scheduler::storage::S3Storage;
+use network_scheduler::PING_TOPIC;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -34,27 +24,42 @@ async fn main() -> anyhow::Result<()> {
 
     // Open file for writing metrics
     let metrics_writer = MetricsWriter::from_cli(&args).await?;
+    let audit_log = match &args.audit_log_path {
+        Some(path) => Some(AuditLog::open(path, args.audit_log_max_bytes).await?),
+        None => None,
+    };
     let mut metrics_registry = Registry::default();
+    prometheus_metrics::register_metrics(&mut metrics_registry);
 
     // Build P2P transport
     let mut transport_builder = P2PTransportBuilder::from_cli(args.transport).await?;
     transport_builder.with_registry(&mut metrics_registry);
     let local_peer_id = transport_builder.local_peer_id();
+    let keypair = transport_builder.keypair();
     let (incoming_messages, transport_handle) = transport_builder.run().await?;
 
     // Subscribe to receive worker pings
     transport_handle.subscribe(PING_TOPIC).await?;
 
     // Get scheduling units
-    let storage = S3Storage::new(local_peer_id).await;
-    let incoming_units = storage.get_incoming_units().await;
+    let instance_id = args
+        .instance_id
+        .clone()
+        .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+    let storage = S3Storage::new(local_peer_id, instance_id)
+        .await
+        .with_audit_log(audit_log.clone());
     let scheduler = storage.load_scheduler().await?;
+    let incoming_units = storage
+        .get_incoming_units(&scheduler.listing_resume())
+        .await;
     let contract_client = contract_client::get_client(&args.rpc).await?;
 
     Server::new(
         incoming_messages,
         incoming_units,
         transport_handle,
+        keypair,
         scheduler,
         metrics_writer,
     )
@@ -63,6 +68,8 @@ async fn main() -> anyhow::Result<()> {
         storage,
         args.http_listen_addr,
         metrics_registry,
+        args.admin_token,
+        audit_log,
     )
     .await
 }
@@ -1,17 +1,21 @@
-use std::pin::Pin;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 use derive_enum_from_into::EnumFrom;
 use serde::Serialize;
 use serde_with::{serde_as, TimestampMilliSeconds};
-use tokio::fs::OpenOptions;
-use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::task::JoinHandle;
 
 use subsquid_messages::{Ping, QueryFinished, QuerySubmitted};
+use subsquid_network_transport::task_manager::CancellationToken;
 use subsquid_network_transport::PeerId;
 
 use crate::cli::Cli;
+use crate::prometheus_metrics;
 use crate::worker_state::WorkerState;
+use sinks::MetricsSink;
+
+mod sinks;
 
 #[serde_as]
 #[derive(Debug, Clone, Serialize)]
@@ -75,29 +79,52 @@ impl From<Vec<WorkerState>> for MetricsEvent {
     }
 }
 
+// Maximum number of metrics written to the sink at once
+const MAX_BATCH_SIZE: usize = 1000;
+const WRITE_RETRIES: usize = 3;
+const WRITE_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Metrics are buffered in a bounded queue and written to the sink by a background task,
+/// so a slow sink never blocks message handling. When the queue is full, new metrics are
+/// dropped.
 pub struct MetricsWriter {
-    output: Pin<Box<dyn AsyncWrite + Send + Sync>>,
+    sender: mpsc::Sender<Metrics>,
     enabled_metrics: Vec<String>,
+    stop_token: CancellationToken,
+    write_task: Option<JoinHandle<()>>,
 }
 
 impl MetricsWriter {
     pub async fn from_cli(cli: &Cli) -> anyhow::Result<Self> {
-        let output: Pin<Box<dyn AsyncWrite + Send + Sync>> = match &cli.metrics_path {
-            Some(path) => {
-                let metrics_file = OpenOptions::new()
-                    .create(true)
-                    .append(true)
-                    .open(path)
-                    .await?;
-                Box::pin(metrics_file)
-            }
-            None => Box::pin(tokio::io::stdout()),
-        };
-        let enabled_metrics = cli.metrics.clone();
-        Ok(Self {
-            output,
+        let args = &cli.metrics_args;
+        let sink = sinks::from_args(args).await?;
+        Ok(Self::new(
+            sink,
+            args.metrics_buffer_size,
+            args.metrics.clone(),
+        ))
+    }
+
+    fn new(sink: Box<dyn MetricsSink>, buffer_size: usize, enabled_metrics: Vec<String>) -> Self {
+        let (sender, receiver) = mpsc::channel(buffer_size);
+        let stop_token = CancellationToken::new();
+        let write_task = tokio::spawn(write_metrics_task(receiver, sink, stop_token.clone()));
+        Self {
+            sender,
             enabled_metrics,
-        })
+            stop_token,
+            write_task: Some(write_task),
+        }
+    }
+
+    /// Stop accepting new metrics and wait until the queued ones are written to the sink
+    pub async fn flush(&mut self) {
+        self.stop_token.cancel();
+        if let Some(write_task) = self.write_task.take() {
+            write_task
+                .await
+                .unwrap_or_else(|e| log::error!("Metrics writer task failed: {e:?}"));
+        }
     }
 
     fn metric_enabled(&self, event: &MetricsEvent) -> bool {
@@ -113,9 +140,94 @@ impl MetricsWriter {
         let peer_id = peer_id.map(|id| id.to_string());
         let metrics = Metrics::new(peer_id, msg)?;
         if self.metric_enabled(&metrics.event) {
-            let json_line = metrics.to_json_line()?;
-            self.output.write_all(json_line.as_slice()).await?;
+            match self.sender.try_send(metrics) {
+                Ok(()) => {}
+                Err(TrySendError::Full(metrics)) => {
+                    // Dropped metrics are counted, logging each of them would flood the logs
+                    log::debug!("Metrics buffer full. Dropping {}", metrics.event.name());
+                    prometheus_metrics::metrics_dropped();
+                }
+                Err(TrySendError::Closed(_)) => anyhow::bail!("Metrics writer stopped"),
+            }
         }
         Ok(())
     }
 }
+
+async fn write_metrics_task(
+    mut receiver: mpsc::Receiver<Metrics>,
+    mut sink: Box<dyn MetricsSink>,
+    stop_token: CancellationToken,
+) {
+    let mut batch = Vec::with_capacity(MAX_BATCH_SIZE);
+    let mut stopping = false;
+    loop {
+        let num_received = tokio::select! {
+            n = receiver.recv_many(&mut batch, MAX_BATCH_SIZE) => n,
+            _ = stop_token.cancelled(), if !stopping => {
+                // Closed receiver still yields the queued metrics, so they get written
+                log::info!("Flushing metrics queue");
+                receiver.close();
+                stopping = true;
+                continue;
+            }
+        };
+        if num_received == 0 {
+            break;
+        }
+        for attempt in 1..=WRITE_RETRIES {
+            match sink.write(&batch).await {
+                Ok(()) => break,
+                Err(e) if attempt < WRITE_RETRIES => {
+                    log::warn!("Error writing metrics (attempt {attempt}): {e:?}");
+                    tokio::time::sleep(WRITE_RETRY_INTERVAL).await;
+                }
+                Err(e) => {
+                    log::error!(
+                        "Error writing metrics. Dropping {} entries: {e:?}",
+                        batch.len()
+                    );
+                    prometheus_metrics::metrics_dropped_by(batch.len());
+                }
+            }
+        }
+        batch.clear();
+    }
+    log::info!("Metrics writer stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use async_trait::async_trait;
+
+    use super::*;
+
+    /// Sink which takes a while to write each batch and remembers the number of written metrics
+    struct SlowSink(Arc<Mutex<usize>>);
+
+    #[async_trait]
+    impl MetricsSink for SlowSink {
+        async fn write(&mut self, metrics: &[Metrics]) -> anyhow::Result<()> {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            *self.0.lock().unwrap() += metrics.len();
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flush() {
+        let written = Arc::new(Mutex::new(0));
+        let sink = Box::new(SlowSink(written.clone()));
+        let mut writer = MetricsWriter::new(sink, 100, vec!["WorkersSnapshot".to_string()]);
+        for _ in 0..10 {
+            writer.write_metrics(None, vec![]).await.unwrap();
+            tokio::task::yield_now().await;
+        }
+
+        writer.flush().await;
+        assert_eq!(*written.lock().unwrap(), 10);
+        assert!(writer.write_metrics(None, vec![]).await.is_err());
+    }
+}
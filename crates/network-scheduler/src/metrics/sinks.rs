@@ -0,0 +1,293 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use clickhouse::{Client, Row};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::Serialize;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use crate::cli::{MetricsArgs, MetricsSinkKind};
+use crate::metrics::Metrics;
+
+const METRICS_TABLE: &str = "scheduler_metrics";
+const METRICS_TABLE_DEFINITION: &str = "
+CREATE TABLE IF NOT EXISTS scheduler_metrics
+(
+    timestamp DateTime64(3) NOT NULL CODEC(DoubleDelta, ZSTD),
+    event LowCardinality(String) NOT NULL,
+    payload String NOT NULL
+)
+ENGINE = MergeTree
+PARTITION BY toYYYYMM(timestamp)
+ORDER BY (event, timestamp);
+";
+
+#[async_trait]
+pub trait MetricsSink: Send {
+    async fn write(&mut self, metrics: &[Metrics]) -> anyhow::Result<()>;
+}
+
+pub async fn from_args(args: &MetricsArgs) -> anyhow::Result<Box<dyn MetricsSink>> {
+    let kind = args.metrics_sink.unwrap_or(match args.metrics_path {
+        Some(_) => MetricsSinkKind::File,
+        None => MetricsSinkKind::Stdout,
+    });
+    let sink: Box<dyn MetricsSink> = match kind {
+        MetricsSinkKind::Stdout => Box::new(StdoutSink),
+        MetricsSinkKind::File => {
+            let path = args
+                .metrics_path
+                .clone()
+                .ok_or_else(|| anyhow::anyhow!("Metrics path required for file sink"))?;
+            let sink = RotatingFileSink::open(
+                path,
+                args.metrics_rotate_size,
+                args.metrics_rotate_interval_sec.map(Duration::from_secs),
+                args.metrics_compress,
+            )
+            .await?;
+            Box::new(sink)
+        }
+        MetricsSinkKind::Clickhouse => {
+            let url = args
+                .metrics_clickhouse_url
+                .clone()
+                .ok_or_else(|| anyhow::anyhow!("ClickHouse URL required for clickhouse sink"))?;
+            let client = Client::default()
+                .with_url(url)
+                .with_database(&args.metrics_clickhouse_database)
+                .with_user(&args.metrics_clickhouse_user)
+                .with_password(&args.metrics_clickhouse_password);
+            Box::new(ClickhouseSink::new(client).await)
+        }
+        MetricsSinkKind::Webhook => {
+            let url = args
+                .metrics_webhook_url
+                .clone()
+                .ok_or_else(|| anyhow::anyhow!("Webhook URL required for webhook sink"))?;
+            Box::new(WebhookSink::new(url))
+        }
+    };
+    Ok(sink)
+}
+
+async fn write_json_lines(
+    output: &mut (impl AsyncWrite + Unpin),
+    metrics: &[Metrics],
+) -> anyhow::Result<u64> {
+    let mut bytes = Vec::new();
+    for m in metrics {
+        bytes.extend(m.to_json_line()?);
+    }
+    output.write_all(&bytes).await?;
+    output.flush().await?;
+    Ok(bytes.len() as u64)
+}
+
+struct StdoutSink;
+
+#[async_trait]
+impl MetricsSink for StdoutSink {
+    async fn write(&mut self, metrics: &[Metrics]) -> anyhow::Result<()> {
+        write_json_lines(&mut tokio::io::stdout(), metrics).await?;
+        Ok(())
+    }
+}
+
+/// Appends metrics to a file. When the file gets too big or too old, it's renamed
+/// to `<path>.<timestamp>` (optionally gzipped) and a new one is started.
+struct RotatingFileSink {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    opened: Instant,
+    max_size: Option<u64>,
+    max_age: Option<Duration>,
+    compress: bool,
+}
+
+impl RotatingFileSink {
+    async fn open(
+        path: PathBuf,
+        max_size: Option<u64>,
+        max_age: Option<Duration>,
+        compress: bool,
+    ) -> anyhow::Result<Self> {
+        let file = Self::open_file(&path).await?;
+        let size = file.metadata().await?.len();
+        Ok(Self {
+            path,
+            file,
+            size,
+            opened: Instant::now(),
+            max_size,
+            max_age,
+            compress,
+        })
+    }
+
+    async fn open_file(path: &Path) -> anyhow::Result<File> {
+        Ok(OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?)
+    }
+
+    fn should_rotate(&self) -> bool {
+        self.size > 0
+            && (self.max_size.is_some_and(|max_size| self.size >= max_size)
+                || self
+                    .max_age
+                    .is_some_and(|max_age| self.opened.elapsed() >= max_age))
+    }
+
+    async fn rotate(&mut self) -> anyhow::Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time after epoch")
+            .as_millis();
+        let mut rotated_path = self.path.clone().into_os_string();
+        rotated_path.push(format!(".{timestamp}"));
+        let rotated_path = PathBuf::from(rotated_path);
+        self.file.flush().await?;
+        tokio::fs::rename(&self.path, &rotated_path).await?;
+        log::info!("Rotated metrics file to {}", rotated_path.display());
+
+        self.file = Self::open_file(&self.path).await?;
+        self.size = 0;
+        self.opened = Instant::now();
+
+        if self.compress {
+            // Compression runs in the background, it shouldn't hold back writing
+            tokio::task::spawn_blocking(move || {
+                compress_file(&rotated_path)
+                    .unwrap_or_else(|e| log::error!("Error compressing metrics file: {e:?}"))
+            });
+        }
+        Ok(())
+    }
+}
+
+fn compress_file(path: &Path) -> anyhow::Result<()> {
+    let mut gz_path = path.to_path_buf().into_os_string();
+    gz_path.push(".gz");
+    let mut input = std::fs::File::open(path)?;
+    let output = std::fs::File::create(&gz_path)?;
+    let mut encoder = GzEncoder::new(output, Compression::default());
+    std::io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.flush()?;
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+#[async_trait]
+impl MetricsSink for RotatingFileSink {
+    async fn write(&mut self, metrics: &[Metrics]) -> anyhow::Result<()> {
+        if self.should_rotate() {
+            self.rotate().await?;
+        }
+        self.size += write_json_lines(&mut self.file, metrics).await?;
+        Ok(())
+    }
+}
+
+#[derive(Row, Serialize)]
+struct MetricsRow {
+    timestamp: u64,
+    event: &'static str,
+    payload: String,
+}
+
+impl TryFrom<&Metrics> for MetricsRow {
+    type Error = anyhow::Error;
+
+    fn try_from(metrics: &Metrics) -> Result<Self, Self::Error> {
+        let timestamp = metrics
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .expect("time after epoch")
+            .as_millis() as u64;
+        Ok(Self {
+            timestamp,
+            event: metrics.event.name(),
+            payload: serde_json::to_string(metrics)?,
+        })
+    }
+}
+
+/// Inserts metrics into ClickHouse. If the server is unavailable at startup, the scheduler
+/// still starts: errors are logged and creating the table is retried with the next batch.
+struct ClickhouseSink {
+    client: Client,
+    table_created: bool,
+}
+
+impl ClickhouseSink {
+    async fn new(client: Client) -> Self {
+        let mut sink = Self {
+            client,
+            table_created: false,
+        };
+        sink.create_table()
+            .await
+            .unwrap_or_else(|e| log::error!("Error creating metrics table: {e:?}"));
+        sink
+    }
+
+    async fn create_table(&mut self) -> anyhow::Result<()> {
+        if !self.table_created {
+            self.client
+                .query(METRICS_TABLE_DEFINITION)
+                .execute()
+                .await?;
+            self.table_created = true;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MetricsSink for ClickhouseSink {
+    async fn write(&mut self, metrics: &[Metrics]) -> anyhow::Result<()> {
+        self.create_table().await?;
+        let mut insert = self.client.insert(METRICS_TABLE)?;
+        for m in metrics {
+            insert.write(&MetricsRow::try_from(m)?).await?;
+        }
+        insert.end().await?;
+        Ok(())
+    }
+}
+
+/// Sends each batch of metrics as a JSON array in a POST request
+struct WebhookSink {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl WebhookSink {
+    fn new(url: String) -> Self {
+        Self {
+            url,
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl MetricsSink for WebhookSink {
+    async fn write(&mut self, metrics: &[Metrics]) -> anyhow::Result<()> {
+        self.client
+            .post(&self.url)
+            .json(metrics)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
@@ -1,32 +1,28 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use std::ops::Deref;
 use std::sync::Arc;
 
+use axum::extract::{Path, Query};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
 use axum::routing::get;
 use axum::{Extension, Json, Router, Server};
-use itertools::Itertools;
 use prometheus_client::registry::Registry;
 use serde::Serialize;
 use tokio::sync::RwLock;
 
-use subsquid_messages::RangeSet;
 use subsquid_network_transport::task_manager::CancellationToken;
 use subsquid_network_transport::PeerId;
 
+use crate::audit_log::{AuditLog, AuditQuery};
 use crate::cli::Config;
-use crate::data_chunk::{chunks_to_worker_state, DataChunk};
-use crate::scheduler::Scheduler;
+use crate::scheduler::{DatasetLifecycle, Scheduler};
+use crate::storage::S3Storage;
 use crate::worker_state::WorkerState;
 
-#[derive(Debug, Clone, Serialize)]
-struct ChunkStatus {
-    begin: u32,
-    end: u32,
-    size_bytes: u64,
-    assigned_to: Vec<String>,
-    downloaded_by: Vec<String>,
-}
+mod admin;
+mod chunks;
 
 async fn active_workers(
     Extension(scheduler): Extension<Arc<RwLock<Scheduler>>>,
@@ -34,71 +30,62 @@ async fn active_workers(
     Json(scheduler.read().await.active_workers())
 }
 
-async fn chunks(
+async fn worker_status(
+    Path(worker_id): Path<PeerId>,
+    Extension(scheduler): Extension<Arc<RwLock<Scheduler>>>,
+) -> Response {
+    match scheduler.read().await.worker_status(&worker_id) {
+        Some(status) => Json(status).into_response(),
+        None => (
+            StatusCode::NOT_FOUND,
+            format!("Unknown worker: {worker_id}"),
+        )
+            .into_response(),
+    }
+}
+
+async fn jail_history(
+    Path(worker_id): Path<PeerId>,
     Extension(scheduler): Extension<Arc<RwLock<Scheduler>>>,
-) -> Json<HashMap<String, Vec<ChunkStatus>>> {
-    let workers = scheduler.read().await.all_workers();
-    let units = scheduler.read().await.known_units();
-    let assigned_ranges = workers
-        .iter()
-        .flat_map(|w| {
-            let chunks = w
-                .assigned_units
-                .iter()
-                .flat_map(|unit_id| units.get(unit_id).unwrap().clone());
-            chunks_to_worker_state(chunks)
-                .datasets
-                .into_iter()
-                .map(|(dataset, ranges)| (dataset, (w.peer_id, ranges)))
-        })
-        .into_group_map();
-    let stored_ranges = workers
-        .iter()
-        .flat_map(|w| {
-            w.stored_ranges
-                .iter()
-                .map(|(dataset, ranges)| (dataset.clone(), (w.peer_id, ranges.clone())))
-        })
-        .into_group_map();
-    let chunk_statuses = units
-        .into_values()
-        .flatten()
-        .map(|chunk| {
-            let assigned_to = find_workers_with_chunk(&chunk, &assigned_ranges);
-            let downloaded_by = find_workers_with_chunk(&chunk, &stored_ranges);
-            let chunk_status = ChunkStatus {
-                begin: chunk.block_range.begin,
-                end: chunk.block_range.end,
-                size_bytes: chunk.size_bytes,
-                assigned_to,
-                downloaded_by,
-            };
-            (chunk.dataset_url, chunk_status)
-        })
-        .into_group_map();
-    Json(chunk_statuses)
+) -> Response {
+    match scheduler.read().await.worker_jail_history(&worker_id) {
+        Some(history) => Json(history).into_response(),
+        None => (
+            StatusCode::NOT_FOUND,
+            format!("Unknown worker: {worker_id}"),
+        )
+            .into_response(),
+    }
 }
 
-fn find_workers_with_chunk(
-    chunk: &DataChunk,
-    ranges: &HashMap<String, Vec<(PeerId, RangeSet)>>,
-) -> Vec<String> {
-    let ranges = match ranges.get(&chunk.dataset_url) {
-        Some(ranges) => ranges,
-        None => return vec![],
+async fn assignment_history(
+    Query(query): Query<AuditQuery>,
+    Extension(audit_log): Extension<Option<AuditLog>>,
+) -> Response {
+    let audit_log = match audit_log {
+        Some(audit_log) => audit_log,
+        None => return (StatusCode::NOT_FOUND, "Audit log disabled").into_response(),
     };
-    ranges
-        .iter()
-        .filter_map(|(worker_id, ranget_set)| {
-            ranget_set
-                .includes(chunk.block_range)
-                .then_some(worker_id.to_string())
-        })
-        .collect()
+    match audit_log.query(&query).await {
+        Ok(events) => Json(events).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
 }
 
-async fn get_config() -> Json<Config> {
-    Json(Config::get().clone())
+#[derive(Serialize)]
+struct ConfigResponse {
+    #[serde(flatten)]
+    config: Config,
+    dataset_lifecycle: BTreeMap<String, DatasetLifecycle>,
+}
+
+async fn get_config(
+    Extension(scheduler): Extension<Arc<RwLock<Scheduler>>>,
+) -> Json<ConfigResponse> {
+    Json(ConfigResponse {
+        config: Config::get().as_ref().clone(),
+        dataset_lifecycle: scheduler.read().await.dataset_lifecycle(),
+    })
 }
 
 async fn get_metrics(Extension(metrics_registry): Extension<Arc<RwLock<Registry>>>) -> String {
@@ -110,18 +97,29 @@ async fn get_metrics(Extension(metrics_registry): Extension<Arc<RwLock<Registry>
 
 pub async fn run_server(
     scheduler: Arc<RwLock<Scheduler>>,
+    storage_client: S3Storage,
     addr: SocketAddr,
     metrics_registry: Registry,
+    admin_token: Option<String>,
+    audit_log: Option<AuditLog>,
     cancel_token: CancellationToken,
 ) -> anyhow::Result<()> {
     log::info!("Starting HTTP server listening on {addr}");
     let metrics_registry = Arc::new(RwLock::new(metrics_registry));
     let app = Router::new()
         .route("/workers/pings", get(active_workers))
-        .route("/chunks", get(chunks))
+        .route("/workers/:worker_id", get(worker_status))
+        .route("/workers/:worker_id/jail_history", get(jail_history))
+        .route("/chunks", get(chunks::chunks))
+        .route("/assignments", get(assignment_history))
         .route("/config", get(get_config))
         .route("/metrics", get(get_metrics))
+        .merge(admin::router())
         .layer(Extension(scheduler))
+        .layer(Extension(storage_client))
+        .layer(Extension(chunks::ChunksCache::default()))
+        .layer(Extension(audit_log))
+        .layer(Extension(admin::AdminToken(admin_token)))
         .layer(Extension(metrics_registry));
     Server::bind(&addr)
         .serve(app.into_make_service())
@@ -129,3 +127,90 @@ pub async fn run_server(
         .await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    use contract_client::Worker;
+    use subsquid_messages::{DatasetRanges, Ping, Range};
+
+    use super::*;
+    use crate::data_chunk::DataChunk;
+    use crate::scheduling_unit::SchedulingUnit;
+
+    async fn get_worker_status(
+        scheduler: &Arc<RwLock<Scheduler>>,
+        worker_id: PeerId,
+    ) -> (StatusCode, Vec<u8>) {
+        let app = Router::new()
+            .route("/workers/:worker_id", get(worker_status))
+            .layer(Extension(scheduler.clone()));
+        let request = Request::builder()
+            .uri(format!("/workers/{worker_id}"))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, body.to_vec())
+    }
+
+    #[test]
+    fn test_worker_status() {
+        let _config = Config::set_for_test(|config| {
+            config.dataset_buckets = vec!["dataset".to_string()];
+            config.replication_factor = 1;
+        });
+        let chunks = [
+            DataChunk::new("dataset", "0000000000/0000000000-0000000999-00000000", 100).unwrap(),
+            DataChunk::new("dataset", "0000000000/0000001000-0000001999-00000000", 200).unwrap(),
+            DataChunk::new("dataset", "0000000000/0000002000-0000002999-00000000", 300).unwrap(),
+        ];
+        let unit = SchedulingUnit::from_slice(&chunks);
+        let unit_id = unit.id();
+        let worker_id = PeerId::random();
+        let mut scheduler = Scheduler::default();
+        scheduler.update_workers(vec![Worker {
+            peer_id: worker_id,
+            onchain_id: Default::default(),
+            address: Default::default(),
+            bond: Default::default(),
+            registered_at: 0,
+            deregistered_at: None,
+        }]);
+        scheduler.new_unit(unit);
+
+        // The worker has downloaded the first two chunks
+        let ping = Ping {
+            version: Some("0.2.3".to_string()),
+            stored_ranges: vec![DatasetRanges {
+                url: "s3://dataset".to_string(),
+                ranges: vec![Range::new(0, 1999)],
+            }],
+            ..Default::default()
+        };
+        scheduler.ping(worker_id, ping, true);
+        scheduler.pin_unit(unit_id, worker_id).unwrap();
+        let scheduler = Arc::new(RwLock::new(scheduler));
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let (status, body) = runtime.block_on(get_worker_status(&scheduler, worker_id));
+        assert_eq!(status, StatusCode::OK);
+        let report: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(report["assigned_chunks_count"], 3);
+        assert_eq!(report["assigned_chunks_size"], 600);
+        assert_eq!(report["downloaded_chunks_count"], 2);
+        assert_eq!(report["downloaded_chunks_size"], 300);
+        assert_eq!(
+            report["missing_chunks"],
+            serde_json::json!([chunks[2].to_string()])
+        );
+        assert_eq!(report["jail_reason_message"], serde_json::Value::Null);
+
+        let (status, _) = runtime.block_on(get_worker_status(&scheduler, PeerId::random()));
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
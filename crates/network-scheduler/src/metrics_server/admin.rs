@@ -0,0 +1,348 @@
+use std::sync::Arc;
+
+use axum::extract::{Extension, Path};
+use axum::http::{header, Request, StatusCode};
+use axum::middleware::{from_fn, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post, put};
+use axum::{Json, Router};
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+use tokio::sync::RwLock;
+
+use subsquid_network_transport::PeerId;
+
+use crate::cli::Config;
+use crate::scheduler::{NotFound, Scheduler, UnitOverrides};
+use crate::scheduling_unit::UnitId;
+use crate::server;
+use crate::storage::S3Storage;
+
+#[derive(Debug, Clone)]
+pub struct AdminToken(pub Option<String>);
+
+async fn authenticate<B>(req: Request<B>, next: Next<B>) -> Response {
+    let expected_token = match req.extensions().get::<AdminToken>() {
+        Some(AdminToken(Some(token))) => token,
+        _ => return (StatusCode::FORBIDDEN, "Admin API is disabled").into_response(),
+    };
+    let authorized = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| token.as_bytes().ct_eq(expected_token.as_bytes()).into());
+    if !authorized {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    next.run(req).await
+}
+
+/// Apply the update to scheduler state and persist it
+async fn update_scheduler(
+    scheduler: Arc<RwLock<Scheduler>>,
+    storage: S3Storage,
+    update: impl FnOnce(&mut Scheduler) -> anyhow::Result<()>,
+) -> Response {
+    if !storage.is_leader() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "This instance is not the leader",
+        )
+            .into_response();
+    }
+    let mut scheduler = scheduler.write().await;
+    match update(&mut scheduler) {
+        Ok(()) => {
+            storage.save_scheduler(scheduler).await;
+            StatusCode::OK.into_response()
+        }
+        Err(e) if e.is::<NotFound>() => (StatusCode::NOT_FOUND, e.to_string()).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    }
+}
+
+fn parse_unit_id(unit_id: &str) -> Result<UnitId, (StatusCode, String)> {
+    unit_id
+        .parse()
+        .map_err(|e: anyhow::Error| (StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct JailRequest {
+    message: String,
+}
+
+async fn jail_worker(
+    Path(worker_id): Path<PeerId>,
+    Extension(scheduler): Extension<Arc<RwLock<Scheduler>>>,
+    Extension(storage): Extension<S3Storage>,
+    Json(JailRequest { message }): Json<JailRequest>,
+) -> Response {
+    update_scheduler(scheduler, storage, |s| s.jail_worker(worker_id, message)).await
+}
+
+async fn release_worker(
+    Path(worker_id): Path<PeerId>,
+    Extension(scheduler): Extension<Arc<RwLock<Scheduler>>>,
+    Extension(storage): Extension<S3Storage>,
+) -> Response {
+    update_scheduler(scheduler, storage, |s| s.release_worker(worker_id)).await
+}
+
+async fn unit_overrides(
+    Extension(scheduler): Extension<Arc<RwLock<Scheduler>>>,
+) -> Json<UnitOverrides> {
+    Json(scheduler.read().await.unit_overrides())
+}
+
+async fn pin_unit(
+    Path((unit_id, worker_id)): Path<(String, PeerId)>,
+    Extension(scheduler): Extension<Arc<RwLock<Scheduler>>>,
+    Extension(storage): Extension<S3Storage>,
+) -> Response {
+    let unit_id = match parse_unit_id(&unit_id) {
+        Ok(unit_id) => unit_id,
+        Err(e) => return e.into_response(),
+    };
+    update_scheduler(scheduler, storage, |s| s.pin_unit(unit_id, worker_id)).await
+}
+
+async fn unpin_unit(
+    Path((unit_id, worker_id)): Path<(String, PeerId)>,
+    Extension(scheduler): Extension<Arc<RwLock<Scheduler>>>,
+    Extension(storage): Extension<S3Storage>,
+) -> Response {
+    let unit_id = match parse_unit_id(&unit_id) {
+        Ok(unit_id) => unit_id,
+        Err(e) => return e.into_response(),
+    };
+    update_scheduler(scheduler, storage, |s| s.unpin_unit(unit_id, worker_id)).await
+}
+
+async fn forbid_unit(
+    Path((unit_id, worker_id)): Path<(String, PeerId)>,
+    Extension(scheduler): Extension<Arc<RwLock<Scheduler>>>,
+    Extension(storage): Extension<S3Storage>,
+) -> Response {
+    let unit_id = match parse_unit_id(&unit_id) {
+        Ok(unit_id) => unit_id,
+        Err(e) => return e.into_response(),
+    };
+    update_scheduler(scheduler, storage, |s| s.forbid_unit(unit_id, worker_id)).await
+}
+
+async fn unforbid_unit(
+    Path((unit_id, worker_id)): Path<(String, PeerId)>,
+    Extension(scheduler): Extension<Arc<RwLock<Scheduler>>>,
+    Extension(storage): Extension<S3Storage>,
+) -> Response {
+    let unit_id = match parse_unit_id(&unit_id) {
+        Ok(unit_id) => unit_id,
+        Err(e) => return e.into_response(),
+    };
+    update_scheduler(scheduler, storage, |s| s.unforbid_unit(unit_id, worker_id)).await
+}
+
+async fn reload_config(
+    Extension(scheduler): Extension<Arc<RwLock<Scheduler>>>,
+    Extension(storage): Extension<S3Storage>,
+) -> Response {
+    match server::reload_config(&scheduler, &storage).await {
+        Ok(()) => Json(Config::get().as_ref().clone()).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, format!("{e:#}")).into_response(),
+    }
+}
+
+/// Routes for manual intervention. All of them require `Authorization: Bearer <admin_token>`.
+pub fn router() -> Router {
+    Router::new()
+        .route("/admin/workers/:worker_id/jail", post(jail_worker))
+        .route("/admin/workers/:worker_id/release", post(release_worker))
+        .route("/admin/config/reload", post(reload_config))
+        .route("/admin/units/overrides", get(unit_overrides))
+        .route(
+            "/admin/units/:unit_id/pinned/:worker_id",
+            put(pin_unit).delete(unpin_unit),
+        )
+        .route(
+            "/admin/units/:unit_id/forbidden/:worker_id",
+            put(forbid_unit).delete(unforbid_unit),
+        )
+        .route_layer(from_fn(authenticate))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::http::Method;
+    use tower::ServiceExt;
+
+    use contract_client::Worker;
+
+    use super::*;
+    use crate::data_chunk::DataChunk;
+    use crate::scheduling_unit::SchedulingUnit;
+
+    const TOKEN: &str = "secret";
+
+    /// Run the test holding the config, which can't be held across await points
+    fn run_test(test: impl std::future::Future<Output = ()>) {
+        let _config = Config::set_for_test(|config| {
+            config.dataset_buckets = vec!["dataset".to_string()];
+        });
+        tokio::runtime::Runtime::new().unwrap().block_on(test)
+    }
+
+    struct TestApi {
+        scheduler: Arc<RwLock<Scheduler>>,
+        storage: S3Storage,
+        unit_id: UnitId,
+        worker_id: PeerId,
+    }
+
+    impl TestApi {
+        /// Scheduler with a single unit and a single worker
+        fn new(leader: bool) -> Self {
+            let mut scheduler = Scheduler::default();
+            let worker_id = PeerId::random();
+            scheduler.update_workers(vec![Worker {
+                peer_id: worker_id,
+                onchain_id: Default::default(),
+                address: Default::default(),
+                bond: Default::default(),
+                registered_at: 0,
+                deregistered_at: None,
+            }]);
+            let chunk = DataChunk::new("dataset", "0000000000/0000000000-0000000999-00000000", 100)
+                .unwrap();
+            let unit = SchedulingUnit::from_slice(&[chunk]);
+            let unit_id = unit.id();
+            scheduler.new_unit(unit);
+            Self {
+                scheduler: Arc::new(RwLock::new(scheduler)),
+                storage: S3Storage::for_test(leader),
+                unit_id,
+                worker_id,
+            }
+        }
+
+        async fn request(&self, method: Method, uri: &str, token: Option<&str>) -> StatusCode {
+            let app = router()
+                .layer(Extension(self.scheduler.clone()))
+                .layer(Extension(self.storage.clone()))
+                .layer(Extension(AdminToken(Some(TOKEN.to_string()))));
+            let mut request = Request::builder().method(method).uri(uri);
+            if let Some(token) = token {
+                request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+            }
+            let response = app
+                .oneshot(request.body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            response.status()
+        }
+
+        fn override_uri(&self, kind: &str, worker_id: PeerId) -> String {
+            format!("/admin/units/{}/{kind}/{worker_id}", self.unit_id)
+        }
+    }
+
+    #[test]
+    fn test_authentication() {
+        run_test(async {
+            let api = TestApi::new(true);
+            let uri = "/admin/units/overrides";
+            assert_eq!(
+                api.request(Method::GET, uri, None).await,
+                StatusCode::UNAUTHORIZED
+            );
+            assert_eq!(
+                api.request(Method::GET, uri, Some("wrong")).await,
+                StatusCode::UNAUTHORIZED
+            );
+            assert_eq!(
+                api.request(Method::GET, uri, Some(TOKEN)).await,
+                StatusCode::OK
+            );
+
+            // Without a configured token the admin API is disabled
+            let app = router().layer(Extension(AdminToken(None)));
+            let request = Request::builder()
+                .uri(uri)
+                .header(header::AUTHORIZATION, format!("Bearer {TOKEN}"))
+                .body(Body::empty())
+                .unwrap();
+            let response = app.oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        });
+    }
+
+    #[test]
+    fn test_follower_writes() {
+        run_test(async {
+            let api = TestApi::new(false);
+            let uri = format!("/admin/workers/{}/release", api.worker_id);
+            assert_eq!(
+                api.request(Method::POST, &uri, Some(TOKEN)).await,
+                StatusCode::SERVICE_UNAVAILABLE
+            );
+            let uri = api.override_uri("forbidden", api.worker_id);
+            assert_eq!(
+                api.request(Method::PUT, &uri, Some(TOKEN)).await,
+                StatusCode::SERVICE_UNAVAILABLE
+            );
+            assert!(api.scheduler.read().await.unit_overrides().is_empty());
+
+            // Reads are served by followers too
+            assert_eq!(
+                api.request(Method::GET, "/admin/units/overrides", Some(TOKEN))
+                    .await,
+                StatusCode::OK
+            );
+        });
+    }
+
+    #[test]
+    fn test_remove_overrides() {
+        run_test(async {
+            let api = TestApi::new(true);
+            for kind in ["pinned", "forbidden"] {
+                // Unknown worker, unknown unit and a missing override
+                let uri = api.override_uri(kind, PeerId::random());
+                assert_eq!(
+                    api.request(Method::DELETE, &uri, Some(TOKEN)).await,
+                    StatusCode::NOT_FOUND
+                );
+                let uri = format!("/admin/units/{}/{kind}/{}", "00".repeat(32), api.worker_id);
+                assert_eq!(
+                    api.request(Method::DELETE, &uri, Some(TOKEN)).await,
+                    StatusCode::NOT_FOUND
+                );
+                let uri = api.override_uri(kind, api.worker_id);
+                assert_eq!(
+                    api.request(Method::DELETE, &uri, Some(TOKEN)).await,
+                    StatusCode::NOT_FOUND
+                );
+
+                assert_eq!(
+                    api.request(Method::PUT, &uri, Some(TOKEN)).await,
+                    StatusCode::OK
+                );
+                assert!(!api.scheduler.read().await.unit_overrides().is_empty());
+                assert_eq!(
+                    api.request(Method::DELETE, &uri, Some(TOKEN)).await,
+                    StatusCode::OK
+                );
+                assert!(api.scheduler.read().await.unit_overrides().is_empty());
+            }
+
+            // Releasing an unknown worker
+            let uri = format!("/admin/workers/{}/release", PeerId::random());
+            assert_eq!(
+                api.request(Method::POST, &uri, Some(TOKEN)).await,
+                StatusCode::NOT_FOUND
+            );
+        });
+    }
+}
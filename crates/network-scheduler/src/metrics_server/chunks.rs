@@ -0,0 +1,266 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::extract::{Extension, Query};
+use axum::http::HeaderMap;
+use axum::response::IntoResponse;
+use axum::Json;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, RwLock};
+
+use subsquid_messages::RangeSet;
+use subsquid_network_transport::PeerId;
+
+use crate::cli::Config;
+use crate::data_chunk::{chunks_to_worker_state, DataChunk};
+use crate::scheduler::Scheduler;
+
+// Chunk statuses are rebuilt at most this often
+const INDEX_TTL: Duration = Duration::from_secs(10);
+const DEFAULT_PAGE_SIZE: usize = 1000;
+const MAX_PAGE_SIZE: usize = 10000;
+
+#[derive(Debug, Clone, Serialize)]
+struct ChunkStatus {
+    begin: u32,
+    end: u32,
+    size_bytes: u64,
+    assigned_to: Vec<String>,
+    downloaded_by: Vec<String>,
+    colocated_replicas: bool,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ReplicationState {
+    /// Downloaded by fewer workers than the replication factor
+    UnderReplicated,
+    /// Not downloaded by any worker
+    Missing,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChunksQuery {
+    dataset: Option<String>,
+    from_block: Option<u32>,
+    to_block: Option<u32>,
+    state: Option<ReplicationState>,
+    worker: Option<PeerId>,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+/// Statuses of all chunks, precomputed from the scheduler state
+struct ChunksIndex {
+    built_at: Instant,
+    replication_factor: usize,
+    // dataset -> chunks sorted by block range
+    datasets: BTreeMap<String, Vec<ChunkStatus>>,
+    // worker -> dataset -> positions of chunks assigned to or downloaded by the worker
+    workers: HashMap<PeerId, BTreeMap<String, Vec<usize>>>,
+}
+
+#[derive(Clone, Default)]
+pub struct ChunksCache(Arc<Mutex<Option<Arc<ChunksIndex>>>>);
+
+impl ChunksCache {
+    async fn get(&self, scheduler: &RwLock<Scheduler>) -> Arc<ChunksIndex> {
+        let mut cached = self.0.lock().await;
+        match cached.as_ref() {
+            Some(index) if index.built_at.elapsed() < INDEX_TTL => index.clone(),
+            _ => {
+                let index = Arc::new(ChunksIndex::build(&*scheduler.read().await));
+                *cached = Some(index.clone());
+                index
+            }
+        }
+    }
+}
+
+impl ChunksIndex {
+    fn build(scheduler: &Scheduler) -> Self {
+        let workers = scheduler.all_workers();
+        let units = scheduler.known_units();
+        let colocated_units = scheduler.colocated_units();
+        let assigned_ranges = workers
+            .iter()
+            .flat_map(|w| {
+                let chunks = w
+                    .assigned_units
+                    .iter()
+                    .flat_map(|unit_id| units.get(unit_id).unwrap().clone());
+                chunks_to_worker_state(chunks)
+                    .datasets
+                    .into_iter()
+                    .map(|(dataset, ranges)| (dataset, (w.peer_id, ranges)))
+            })
+            .into_group_map();
+        let stored_ranges = workers
+            .iter()
+            .flat_map(|w| {
+                w.stored_ranges
+                    .iter()
+                    .map(|(dataset, ranges)| (dataset.clone(), (w.peer_id, ranges.clone())))
+            })
+            .into_group_map();
+
+        let mut datasets: BTreeMap<String, Vec<ChunkStatus>> = units
+            .into_iter()
+            .flat_map(|(unit_id, unit)| {
+                let colocated_replicas = colocated_units.contains(&unit_id);
+                unit.into_iter()
+                    .map(move |chunk| (chunk, colocated_replicas))
+            })
+            .map(|(chunk, colocated_replicas)| {
+                let assigned_to = find_workers_with_chunk(&chunk, &assigned_ranges);
+                let downloaded_by = find_workers_with_chunk(&chunk, &stored_ranges);
+                let chunk_status = ChunkStatus {
+                    begin: chunk.block_range.begin,
+                    end: chunk.block_range.end,
+                    size_bytes: chunk.size_bytes,
+                    assigned_to,
+                    downloaded_by,
+                    colocated_replicas,
+                };
+                (chunk.dataset_url, chunk_status)
+            })
+            .into_group_map()
+            .into_iter()
+            .collect();
+
+        let mut workers: HashMap<PeerId, BTreeMap<String, Vec<usize>>> = HashMap::new();
+        for (dataset, chunks) in datasets.iter_mut() {
+            chunks.sort_by_key(|chunk| chunk.begin);
+            for (pos, chunk) in chunks.iter().enumerate() {
+                let holders = chunk.assigned_to.iter().chain(&chunk.downloaded_by);
+                for worker_id in holders.unique() {
+                    let worker_id = worker_id.parse().expect("Valid peer ID");
+                    let positions = workers
+                        .entry(worker_id)
+                        .or_default()
+                        .entry(dataset.clone())
+                        .or_default();
+                    if positions.last() != Some(&pos) {
+                        positions.push(pos);
+                    }
+                }
+            }
+        }
+
+        Self {
+            built_at: Instant::now(),
+            replication_factor: Config::get().replication_factor,
+            datasets,
+            workers,
+        }
+    }
+
+    /// Chunks matching the query (except for pagination), ordered by dataset and block range
+    fn find<'a>(
+        &'a self,
+        query: &'a ChunksQuery,
+    ) -> Box<dyn Iterator<Item = (&'a str, &'a ChunkStatus)> + 'a> {
+        let dataset = query.dataset.as_ref().map(|dataset| {
+            if dataset.starts_with("s3://") {
+                dataset.clone()
+            } else {
+                format!("s3://{dataset}")
+            }
+        });
+        let in_dataset = move |url: &String| dataset.is_none() || dataset.as_ref() == Some(url);
+
+        // Candidate positions for each dataset, narrowed down by worker and block range
+        let candidates: Box<dyn Iterator<Item = (&'a str, &'a ChunkStatus)>> = match &query.worker {
+            Some(worker_id) => Box::new(
+                self.workers
+                    .get(worker_id)
+                    .into_iter()
+                    .flatten()
+                    .filter(move |(url, _)| in_dataset(url))
+                    .flat_map(move |(url, positions)| {
+                        let chunks = &self.datasets[url];
+                        let range = self.block_range(chunks, query);
+                        let first = positions.partition_point(|pos| *pos < range.start);
+                        let last = positions.partition_point(|pos| *pos < range.end);
+                        positions[first..last]
+                            .iter()
+                            .map(move |pos| (url.as_str(), &chunks[*pos]))
+                    }),
+            ),
+            None => Box::new(
+                self.datasets
+                    .iter()
+                    .filter(move |(url, _)| in_dataset(url))
+                    .flat_map(move |(url, chunks)| {
+                        chunks[self.block_range(chunks, query)]
+                            .iter()
+                            .map(move |chunk| (url.as_str(), chunk))
+                    }),
+            ),
+        };
+        let replication_factor = self.replication_factor;
+        Box::new(candidates.filter(move |(_, chunk)| match query.state {
+            None => true,
+            Some(ReplicationState::UnderReplicated) => {
+                chunk.downloaded_by.len() < replication_factor
+            }
+            Some(ReplicationState::Missing) => chunk.downloaded_by.is_empty(),
+        }))
+    }
+
+    /// Range of positions of the chunks overlapping with the queried block range
+    fn block_range(&self, chunks: &[ChunkStatus], query: &ChunksQuery) -> std::ops::Range<usize> {
+        let first = query
+            .from_block
+            .map_or(0, |block| chunks.partition_point(|chunk| chunk.end < block));
+        let last = query.to_block.map_or(chunks.len(), |block| {
+            chunks.partition_point(|chunk| chunk.begin <= block)
+        });
+        first..last.max(first)
+    }
+}
+
+fn find_workers_with_chunk(
+    chunk: &DataChunk,
+    ranges: &HashMap<String, Vec<(PeerId, RangeSet)>>,
+) -> Vec<String> {
+    let ranges = match ranges.get(&chunk.dataset_url) {
+        Some(ranges) => ranges,
+        None => return vec![],
+    };
+    ranges
+        .iter()
+        .filter_map(|(worker_id, ranget_set)| {
+            ranget_set
+                .includes(chunk.block_range)
+                .then_some(worker_id.to_string())
+        })
+        .collect()
+}
+
+/// Chunk statuses grouped by dataset. The total number of matching chunks
+/// is returned in the `X-Total-Count` header.
+pub async fn chunks(
+    Query(query): Query<ChunksQuery>,
+    Extension(scheduler): Extension<Arc<RwLock<Scheduler>>>,
+    Extension(cache): Extension<ChunksCache>,
+) -> impl IntoResponse {
+    let index = cache.get(&scheduler).await;
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+    let mut total_count = 0;
+    let mut page: HashMap<String, Vec<ChunkStatus>> = HashMap::new();
+    for (pos, (dataset, chunk)) in index.find(&query).enumerate() {
+        total_count += 1;
+        if pos >= query.offset && pos < query.offset.saturating_add(limit) {
+            page.entry(dataset.to_string())
+                .or_default()
+                .push(chunk.clone());
+        }
+    }
+    let mut headers = HeaderMap::new();
+    headers.insert("X-Total-Count", total_count.into());
+    (headers, Json(page))
+}
@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::sync::atomic::AtomicU64;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::registry::Registry;
+
+type Labels = Vec<(&'static str, String)>;
+
+lazy_static! {
+    static ref DATASET_GAPS: Family<Labels, Gauge> = Default::default();
+    static ref REASSIGNED_UNITS: Counter = Default::default();
+    static ref SAVED_DOWNLOAD_BYTES: Counter = Default::default();
+    static ref UNITS_BY_REPLICAS: Family<Labels, Gauge> = Default::default();
+    static ref UNDER_REPLICATED_BYTES: Family<Labels, Gauge> = Default::default();
+    static ref JAILED_WORKERS: Family<Labels, Gauge> = Default::default();
+    static ref ASSIGNED_BYTES: Gauge = Default::default();
+    static ref STORED_BYTES: Gauge = Default::default();
+    static ref SCHEDULE_DURATION: Gauge<f64, AtomicU64> = Default::default();
+    static ref INCREMENTAL_SCHEDULE_DURATION: Gauge<f64, AtomicU64> = Default::default();
+    static ref SCHEDULE_ROUNDS: Family<Labels, Counter> = Default::default();
+    static ref SCHEDULE_TRIGGERS: Family<Labels, Counter> = Default::default();
+    static ref PENDING_UNITS: Gauge = Default::default();
+    static ref UNASSIGNED_UNITS: Family<Labels, Counter> = Default::default();
+    static ref DROPPED_METRICS: Counter = Default::default();
+    static ref IS_LEADER: Gauge = Default::default();
+    static ref WORKERS_BY_VERSION: Family<Labels, Gauge> = Default::default();
+    static ref NEWLY_ASSIGNED_BYTES: Counter = Default::default();
+    static ref PACED_UNITS: Gauge = Default::default();
+    static ref BAD_CHUNKS: Gauge = Default::default();
+}
+
+pub fn register_metrics(registry: &mut Registry) {
+    registry.register(
+        "dataset_gap_blocks",
+        "Number of blocks missing from the dataset. Chunks after the gap are held back until it is filled or acknowledged",
+        DATASET_GAPS.clone(),
+    );
+    registry.register(
+        "reassigned_units",
+        "Number of units assigned to workers which already stored their data",
+        REASSIGNED_UNITS.clone(),
+    );
+    registry.register(
+        "saved_download_bytes",
+        "Bytes of data which didn't have to be downloaded thanks to reassigning units to workers storing them",
+        SAVED_DOWNLOAD_BYTES.clone(),
+    );
+    registry.register(
+        "units_by_replicas",
+        "Number of scheduling units with the given number of assigned replicas",
+        UNITS_BY_REPLICAS.clone(),
+    );
+    registry.register(
+        "under_replicated_bytes",
+        "Total size of replicas missing to reach the replication factor",
+        UNDER_REPLICATED_BYTES.clone(),
+    );
+    registry.register(
+        "jailed_workers",
+        "Number of jailed workers",
+        JAILED_WORKERS.clone(),
+    );
+    registry.register(
+        "assigned_bytes",
+        "Total size of data assigned to active workers",
+        ASSIGNED_BYTES.clone(),
+    );
+    registry.register(
+        "stored_bytes",
+        "Total size of data stored by active workers",
+        STORED_BYTES.clone(),
+    );
+    registry.register(
+        "schedule_duration_seconds",
+        "Duration of the last scheduling round",
+        SCHEDULE_DURATION.clone(),
+    );
+    registry.register(
+        "incremental_schedule_duration_seconds",
+        "Duration of the last incremental scheduling round",
+        INCREMENTAL_SCHEDULE_DURATION.clone(),
+    );
+    registry.register(
+        "schedule_rounds",
+        "Number of scheduling rounds (full or incremental)",
+        SCHEDULE_ROUNDS.clone(),
+    );
+    registry.register(
+        "schedule_triggers",
+        "Number of events which requested assignment of units between full scheduling rounds",
+        SCHEDULE_TRIGGERS.clone(),
+    );
+    registry.register(
+        "pending_units",
+        "Number of new or resized units waiting for the next scheduling round",
+        PENDING_UNITS.clone(),
+    );
+    registry.register(
+        "unassigned_units",
+        "Number of unit replicas taken away from workers",
+        UNASSIGNED_UNITS.clone(),
+    );
+    registry.register(
+        "dropped_metrics",
+        "Number of metrics entries which couldn't be written",
+        DROPPED_METRICS.clone(),
+    );
+    registry.register(
+        "is_leader",
+        "Whether this instance holds the leader lease (1) or follows (0)",
+        IS_LEADER.clone(),
+    );
+    registry.register(
+        "workers_by_version",
+        "Number of active workers running the given version",
+        WORKERS_BY_VERSION.clone(),
+    );
+    registry.register(
+        "newly_assigned_bytes",
+        "Bytes of unit replicas workers need to download after being assigned",
+        NEWLY_ASSIGNED_BYTES.clone(),
+    );
+    registry.register(
+        "paced_units",
+        "Number of units held back in the last assignment because of the epoch limits",
+        PACED_UNITS.clone(),
+    );
+    registry.register(
+        "bad_chunks",
+        "Number of chunks which many workers fail to download",
+        BAD_CHUNKS.clone(),
+    );
+}
+
+pub fn dataset_gap(bucket: &str, num_missing_blocks: u32) {
+    DATASET_GAPS
+        .get_or_create(&vec![("dataset", bucket.to_string())])
+        .set(num_missing_blocks as i64);
+}
+
+pub fn reassignment_saved_bytes(num_units: u64, saved_bytes: u64) {
+    REASSIGNED_UNITS.inc_by(num_units);
+    SAVED_DOWNLOAD_BYTES.inc_by(saved_bytes);
+}
+
+/// Number of units with each replica count and missing replica bytes, per dataset
+pub fn replication(
+    units_by_replicas: HashMap<(String, usize), i64>,
+    missing_bytes: HashMap<String, u64>,
+) {
+    UNITS_BY_REPLICAS.clear();
+    for ((dataset, replicas), num_units) in units_by_replicas {
+        let labels = vec![("dataset", dataset), ("replicas", replicas.to_string())];
+        UNITS_BY_REPLICAS.get_or_create(&labels).set(num_units);
+    }
+    UNDER_REPLICATED_BYTES.clear();
+    for (dataset, bytes) in missing_bytes {
+        let labels = vec![("dataset", dataset)];
+        UNDER_REPLICATED_BYTES
+            .get_or_create(&labels)
+            .set(bytes as i64);
+    }
+}
+
+pub fn jailed_workers(by_reason: HashMap<&'static str, i64>) {
+    JAILED_WORKERS.clear();
+    for (reason, num_workers) in by_reason {
+        let labels = vec![("reason", reason.to_string())];
+        JAILED_WORKERS.get_or_create(&labels).set(num_workers);
+    }
+}
+
+pub fn workers_data(assigned_bytes: u64, stored_bytes: u64) {
+    ASSIGNED_BYTES.set(assigned_bytes as i64);
+    STORED_BYTES.set(stored_bytes as i64);
+}
+
+pub fn workers_by_version(by_version: HashMap<String, i64>) {
+    WORKERS_BY_VERSION.clear();
+    for (version, num_workers) in by_version {
+        let labels = vec![("version", version)];
+        WORKERS_BY_VERSION.get_or_create(&labels).set(num_workers);
+    }
+}
+
+pub fn schedule_duration(duration: Duration) {
+    SCHEDULE_DURATION.set(duration.as_secs_f64());
+    schedule_round("full");
+}
+
+pub fn incremental_schedule_duration(duration: Duration) {
+    INCREMENTAL_SCHEDULE_DURATION.set(duration.as_secs_f64());
+    schedule_round("incremental");
+}
+
+fn schedule_round(kind: &'static str) {
+    let labels = vec![("kind", kind.to_string())];
+    SCHEDULE_ROUNDS.get_or_create(&labels).inc();
+}
+
+pub fn schedule_triggered(trigger: &'static str) {
+    let labels = vec![("trigger", trigger.to_string())];
+    SCHEDULE_TRIGGERS.get_or_create(&labels).inc();
+}
+
+pub fn assignment_paced(newly_assigned_bytes: u64, paced_units: usize) {
+    NEWLY_ASSIGNED_BYTES.inc_by(newly_assigned_bytes);
+    PACED_UNITS.set(paced_units as i64);
+}
+
+pub fn bad_chunks(num_chunks: usize) {
+    BAD_CHUNKS.set(num_chunks as i64);
+}
+
+pub fn pending_units(num_units: usize) {
+    PENDING_UNITS.set(num_units as i64);
+}
+
+pub fn units_unassigned(reason: &'static str, num_units: usize) {
+    let labels = vec![("reason", reason.to_string())];
+    UNASSIGNED_UNITS
+        .get_or_create(&labels)
+        .inc_by(num_units as u64);
+}
+
+pub fn metrics_dropped() {
+    DROPPED_METRICS.inc();
+}
+
+pub fn metrics_dropped_by(num_entries: usize) {
+    DROPPED_METRICS.inc_by(num_entries as u64);
+}
+
+pub fn leader(is_leader: bool) {
+    IS_LEADER.set(is_leader as i64);
+}
@@ -1,26 +1,258 @@
-use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 use iter_num_tools::lin_space;
 use itertools::Itertools;
-use lazy_static::lazy_static;
 use rand::prelude::SliceRandom;
-use rand::{thread_rng, Rng};
+use rand::thread_rng;
 use random_choice::random_choice;
-use semver::{Version, VersionReq};
 use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, TimestampMilliSeconds};
 
 use contract_client::Worker;
-use subsquid_messages::{pong::Status as WorkerStatus, Ping};
+use subsquid_messages::version_policy::VersionStatus;
+use subsquid_messages::{
+    pong::Status as WorkerStatus, AssignmentSnapshot, DatasetAssignment, Ping, Pong, Range,
+    UnitAssignment, WorkerStateDelta,
+};
 use subsquid_network_transport::PeerId;
 
-use crate::cli::Config;
-use crate::data_chunk::chunks_to_worker_state;
-use crate::scheduling_unit::{SchedulingUnit, UnitId};
-use crate::worker_state::{JailReason, WorkerState};
+use crate::audit_log::{AssignmentCause, AssignmentEvent, AuditBuffer};
+use crate::cli::{Config, DatasetRetirement};
+use crate::data_chunk::{ChunkId, DataChunk};
+use crate::prometheus_metrics;
+use crate::scheduling_unit::{
+    bundle, ListingCursor, ListingResume, SchedulingUnit, UnitId, UnitLimits,
+};
+use crate::worker_state::{JailReason, JailRecord, WorkerState};
 
-lazy_static! {
-    pub static ref SUPPORTED_WORKER_VERSIONS: VersionReq = ">=0.2.2, <=0.2.3".parse().unwrap();
+mod state_check;
+
+pub use state_check::{Inconsistency, StateSummary};
+
+#[serde_as]
+#[derive(Debug, Clone, Serialize)]
+pub struct JailHistory {
+    jailed: bool,
+    #[serde_as(as = "Option<TimestampMilliSeconds>")]
+    release_time: Option<SystemTime>,
+    history: Vec<JailRecord>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WorkerStatusReport {
+    #[serde(flatten)]
+    state: WorkerState,
+    jail_reason_message: Option<String>,
+    assigned_chunks_count: usize,
+    assigned_chunks_size: u64,
+    downloaded_chunks_count: usize,
+    downloaded_chunks_size: u64,
+    missing_chunks: Vec<String>,
+}
+
+/// Assignment of a single worker in the form sent in pongs
+struct CachedAssignment {
+    version: u64,
+    chunks: BTreeSet<(String, Range)>,
+    state: subsquid_messages::WorkerState,
+}
+
+impl CachedAssignment {
+    fn new(version: u64, chunks: impl IntoIterator<Item = DataChunk>) -> Self {
+        let chunks: BTreeSet<(String, Range)> = chunks
+            .into_iter()
+            .map(|chunk| (chunk.dataset_url, chunk.block_range))
+            .collect();
+        let state = ranges_to_worker_state(chunks.iter().cloned());
+        Self {
+            version,
+            chunks,
+            state,
+        }
+    }
+
+    /// Chunks added and removed since the `older` assignment
+    fn delta(&self, older: &Self) -> WorkerStateDelta {
+        let added = self.chunks.difference(&older.chunks).cloned();
+        let removed = older.chunks.difference(&self.chunks).cloned();
+        WorkerStateDelta {
+            added: Some(ranges_to_worker_state(added)),
+            removed: Some(ranges_to_worker_state(removed)),
+        }
+    }
+}
+
+fn ranges_to_worker_state(
+    ranges: impl IntoIterator<Item = (String, Range)>,
+) -> subsquid_messages::WorkerState {
+    let datasets = ranges.into_iter().into_grouping_map().collect();
+    subsquid_messages::WorkerState { datasets }
+}
+
+/// The current assignment and the previous one, which workers most likely have
+#[derive(Default)]
+struct AssignmentCache {
+    current: Option<CachedAssignment>,
+    previous: Option<CachedAssignment>,
+}
+
+/// Admin action refers to a unit, worker or override which doesn't exist
+#[derive(Debug)]
+pub struct NotFound(pub String);
+
+impl std::fmt::Display for NotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for NotFound {}
+
+/// Manual assignment constraints set by the operator through the admin API
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UnitOverrides {
+    pinned: HashMap<UnitId, HashSet<PeerId>>,
+    forbidden: HashMap<UnitId, HashSet<PeerId>>,
+}
+
+impl UnitOverrides {
+    pub fn is_empty(&self) -> bool {
+        self.pinned.is_empty() && self.forbidden.is_empty()
+    }
+
+    fn is_pinned(&self, unit_id: &UnitId, worker_id: &PeerId) -> bool {
+        self.pinned
+            .get(unit_id)
+            .is_some_and(|workers| workers.contains(worker_id))
+    }
+
+    fn is_forbidden(&self, unit_id: &UnitId, worker_id: &PeerId) -> bool {
+        self.forbidden
+            .get(unit_id)
+            .is_some_and(|workers| workers.contains(worker_id))
+    }
+
+    /// Returns false if there was no such override
+    fn remove(
+        map: &mut HashMap<UnitId, HashSet<PeerId>>,
+        unit_id: &UnitId,
+        worker_id: &PeerId,
+    ) -> bool {
+        let Some(workers) = map.get_mut(unit_id) else {
+            return false;
+        };
+        let removed = workers.remove(worker_id);
+        if workers.is_empty() {
+            map.remove(unit_id);
+        }
+        removed
+    }
+
+    fn pin(&mut self, unit_id: UnitId, worker_id: PeerId) {
+        Self::remove(&mut self.forbidden, &unit_id, &worker_id);
+        self.pinned.entry(unit_id).or_default().insert(worker_id);
+    }
+
+    fn unpin(&mut self, unit_id: &UnitId, worker_id: &PeerId) -> bool {
+        Self::remove(&mut self.pinned, unit_id, worker_id)
+    }
+
+    fn forbid(&mut self, unit_id: UnitId, worker_id: PeerId) {
+        Self::remove(&mut self.pinned, &unit_id, &worker_id);
+        self.forbidden.entry(unit_id).or_default().insert(worker_id);
+    }
+
+    fn unforbid(&mut self, unit_id: &UnitId, worker_id: &PeerId) -> bool {
+        Self::remove(&mut self.forbidden, unit_id, worker_id)
+    }
+
+    fn remove_unit(&mut self, unit_id: &UnitId) {
+        self.pinned.remove(unit_id);
+        self.forbidden.remove(unit_id);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DatasetState {
+    Active,
+    /// No new units or replicas, existing replicas are still served
+    Retiring,
+    /// Retirement finished and all units were removed
+    Removed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DatasetLifecycle {
+    pub state: DatasetState,
+    pub num_units: usize,
+    /// Lowest number of replicas of a unit
+    pub min_replicas: usize,
+    /// Deadline of a retiring dataset (unix timestamp in seconds)
+    pub remove_after: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct DatasetStats {
+    num_units: usize,
+    min_replicas: usize,
+}
+
+/// Bytes newly assigned in the current epoch, limited so that workers don't all start
+/// downloading at once. The rest is assigned in the following epochs. The first unit
+/// of an epoch is always allowed, so that units bigger than the limits get assigned too.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct AssignmentBudget {
+    epoch: u32,
+    per_worker: HashMap<PeerId, u64>,
+    total: u64,
+    #[serde(skip)]
+    max_per_worker: Option<u64>,
+    #[serde(skip)]
+    max_total: Option<u64>,
+}
+
+impl AssignmentBudget {
+    /// Take the current limits from the config and reset the budget if a new epoch started
+    fn refresh(&mut self, epoch: u32, config: &Config) {
+        if epoch != self.epoch {
+            self.epoch = epoch;
+            self.per_worker.clear();
+            self.total = 0;
+        }
+        self.max_per_worker = config.max_assigned_bytes_per_worker;
+        self.max_total = config.max_assigned_bytes;
+    }
+
+    fn allows(&self, worker_id: &PeerId, bytes: u64) -> bool {
+        let within_limit = |limit: Option<u64>, spent: u64| match limit {
+            Some(limit) => spent == 0 || spent + bytes <= limit,
+            None => true,
+        };
+        let worker_spent = self.per_worker.get(worker_id).copied().unwrap_or_default();
+        within_limit(self.max_per_worker, worker_spent) && within_limit(self.max_total, self.total)
+    }
+
+    fn spend(&mut self, worker_id: PeerId, bytes: u64) {
+        *self.per_worker.entry(worker_id).or_default() += bytes;
+        self.total += bytes;
+    }
+
+    fn worker_exhausted(&self, worker_id: &PeerId) -> bool {
+        let spent = self.per_worker.get(worker_id).copied().unwrap_or_default();
+        matches!(self.max_per_worker, Some(limit) if spent >= limit)
+    }
+
+    fn exhausted(&self) -> bool {
+        matches!(self.max_total, Some(limit) if self.total >= limit)
+    }
 }
+
+/// Maximum number of units in a single assignment snapshot message
+const MAX_SNAPSHOT_UNITS: usize = 5000;
+
 #[derive(Default, Serialize, Deserialize)]
 pub struct Scheduler {
     known_units: HashMap<UnitId, SchedulingUnit>,
@@ -28,6 +260,33 @@ pub struct Scheduler {
     worker_states: HashMap<PeerId, WorkerState>,
     #[serde(default)]
     last_schedule_epoch: u32,
+    #[serde(default)]
+    unit_overrides: UnitOverrides,
+    #[serde(default)]
+    audit: AuditBuffer,
+    // Limits the known units were bundled with. Not present in states saved by older versions.
+    #[serde(default)]
+    unit_limits: Option<UnitLimits>,
+    // Dataset URL -> position after which listing of the bucket is resumed
+    #[serde(default)]
+    listing_cursors: HashMap<String, ListingCursor>,
+    #[serde(default)]
+    assignment_budget: AssignmentBudget,
+    // Replicas removed by mixing, not given back to the same workers as long as they still
+    // store some of the data. Otherwise the next round would undo the mixing.
+    #[serde(default)]
+    mixed_replicas: HashSet<(UnitId, PeerId)>,
+    #[serde(skip)]
+    assignment_cache: HashMap<PeerId, AssignmentCache>,
+    // New or resized units which need replicas before the next full scheduling round
+    #[serde(skip)]
+    pending_units: HashSet<UnitId>,
+    // Chunks which many workers fail to download, workers aren't penalized for missing them
+    #[serde(skip)]
+    bad_chunks: HashSet<ChunkId>,
+    // Last time each bad chunk was failing on enough workers
+    #[serde(skip)]
+    bad_chunks_seen: HashMap<ChunkId, SystemTime>,
 }
 
 impl Scheduler {
@@ -35,12 +294,42 @@ impl Scheduler {
         self.last_schedule_epoch
     }
 
+    pub fn set_epoch(&mut self, epoch: u32) {
+        self.audit.set_epoch(epoch);
+    }
+
+    /// Assignment changes since the last call, to be appended to the audit log
+    pub fn take_audit_events(&mut self) -> Vec<AssignmentEvent> {
+        self.audit.take()
+    }
+
+    /// Remove units of the datasets which are no longer configured, and of the retiring
+    /// datasets which passed their deadline or lost too many replicas.
     pub fn clear_deprecated_units(&mut self) {
-        let dataset_urls: HashSet<String> = Config::get()
+        let config = Config::get();
+        let mut dataset_urls: HashSet<String> = config
             .dataset_buckets
             .iter()
             .map(|bucket| format!("s3://{bucket}"))
             .collect();
+        for (dataset_url, stats) in self.dataset_stats() {
+            let retirement = match config.dataset_retirement(&dataset_url) {
+                Some(retirement) => retirement,
+                None => continue,
+            };
+            if retirement.deadline_passed() {
+                log::info!("Retiring dataset {dataset_url} reached its deadline");
+            } else if stats.min_replicas < retirement.min_replicas {
+                log::info!(
+                    "Retiring dataset {dataset_url} dropped to {} replicas",
+                    stats.min_replicas
+                );
+            } else {
+                dataset_urls.insert(dataset_url);
+            }
+        }
+        self.listing_cursors
+            .retain(|dataset_url, _| dataset_urls.contains(dataset_url));
         let deprecated_unit_ids: Vec<UnitId> = self
             .known_units
             .iter()
@@ -51,6 +340,8 @@ impl Scheduler {
         for unit_id in deprecated_unit_ids.iter() {
             let unit = self.known_units.remove(unit_id).expect("unknown unit");
             log::info!("Removing deprecated scheduling unit {unit}");
+            self.pending_units.remove(unit_id);
+            self.unit_overrides.remove_unit(unit_id);
             let unit_size = unit.size_bytes();
             self.units_assignments
                 .remove(unit_id)
@@ -60,36 +351,240 @@ impl Scheduler {
                     self.worker_states
                         .get_mut(&worker_id)
                         .expect("unknown worker")
-                        .remove_unit(unit_id, unit_size)
+                        .remove_unit(unit_id, unit_size);
+                    self.audit
+                        .unassigned(*unit_id, worker_id, AssignmentCause::DatasetRemoved);
                 });
         }
     }
 
-    /// Register ping msg from a worker. Returns worker status if ping was accepted, otherwise None
-    pub fn ping(&mut self, worker_id: PeerId, msg: Ping) -> WorkerStatus {
-        let version = msg.sem_version();
-        if !SUPPORTED_WORKER_VERSIONS.matches(&version) {
-            log::debug!("Worker {worker_id} version not supported: {}", version);
-            return WorkerStatus::UnsupportedVersion(());
+    /// Bundle the known units again if they were created with different limits, so that they
+    /// match the units produced by the bundler. Each new unit is assigned back to the workers
+    /// which were assigned the most of its data, overrides are carried over to all units
+    /// overlapping the old one.
+    pub fn rebundle_units(&mut self, limits: UnitLimits) {
+        if self.unit_limits == Some(limits) {
+            return;
         }
+        let new_units: HashMap<UnitId, SchedulingUnit> = self
+            .known_units
+            .values()
+            .into_group_map_by(|unit| unit.dataset_url().to_string())
+            .into_values()
+            .flat_map(|mut units| {
+                units.sort_by_key(|unit| unit.begin());
+                let chunks = units
+                    .into_iter()
+                    .flat_map(|unit| unit.chunks.iter().cloned());
+                bundle(chunks, limits)
+            })
+            .map(|unit| (unit.id(), unit))
+            .collect();
+        self.unit_limits = Some(limits);
+        if new_units == self.known_units {
+            return;
+        }
+        log::info!(
+            "Re-bundling {} scheduling units into {} units with {limits:?}",
+            self.known_units.len(),
+            new_units.len()
+        );
+
+        // Listing can only be resumed after the end of a unit
+        let unit_ends: HashSet<(&str, u32)> = new_units
+            .values()
+            .map(|unit| (unit.dataset_url(), unit.end()))
+            .collect();
+        self.listing_cursors.retain(|dataset_url, cursor| {
+            unit_ends.contains(&(dataset_url.as_str(), cursor.last_block))
+        });
+
+        // Unassign all the old units, remembering which worker had which chunks
+        let old_units = std::mem::take(&mut self.known_units);
+        let old_assignments = std::mem::take(&mut self.units_assignments);
+        let mut chunk_units: HashMap<ChunkId, UnitId> = HashMap::new();
+        for (unit_id, unit) in old_units.iter() {
+            for worker_id in old_assignments.get(unit_id).into_iter().flatten() {
+                self.get_worker(worker_id)
+                    .remove_unit(unit_id, unit.size_bytes());
+                self.audit
+                    .unassigned(*unit_id, *worker_id, AssignmentCause::Rebundled);
+            }
+            chunk_units.extend(unit.chunks.iter().map(|chunk| (chunk.id(), *unit_id)));
+        }
+
+        let rep_factor = Config::get().replication_factor;
+        let old_overrides = std::mem::take(&mut self.unit_overrides);
+        for (unit_id, unit) in new_units {
+            let mut holders: HashMap<PeerId, u64> = HashMap::new();
+            let mut old_unit_ids: HashSet<UnitId> = HashSet::new();
+            for chunk in unit.chunks.iter() {
+                let old_unit_id = chunk_units[&chunk.id()];
+                old_unit_ids.insert(old_unit_id);
+                for worker_id in old_assignments.get(&old_unit_id).into_iter().flatten() {
+                    *holders.entry(*worker_id).or_default() += chunk.size_bytes;
+                }
+            }
+            for old_unit_id in old_unit_ids {
+                for worker_id in old_overrides.pinned.get(&old_unit_id).into_iter().flatten() {
+                    self.unit_overrides.pin(unit_id, *worker_id);
+                }
+                for worker_id in old_overrides
+                    .forbidden
+                    .get(&old_unit_id)
+                    .into_iter()
+                    .flatten()
+                {
+                    self.unit_overrides.forbid(unit_id, *worker_id);
+                }
+            }
+
+            let unit_size = unit.size_bytes();
+            let mut holder_ids = Vec::with_capacity(rep_factor);
+            for (worker_id, _) in holders
+                .into_iter()
+                .sorted_by_key(|(_, bytes)| Reverse(*bytes))
+            {
+                if holder_ids.len() >= rep_factor {
+                    break;
+                }
+                if !self.unit_overrides.is_forbidden(&unit_id, &worker_id)
+                    && self
+                        .get_worker(&worker_id)
+                        .try_assign_unit(unit_id, unit_size)
+                {
+                    self.audit
+                        .assigned(unit_id, worker_id, AssignmentCause::Rebundled);
+                    holder_ids.push(worker_id);
+                }
+            }
+            self.known_units.insert(unit_id, unit);
+            self.units_assignments.insert(unit_id, holder_ids);
+            if self.num_replicas(&unit_id) < rep_factor {
+                self.unit_pending(unit_id, "rebundled_unit");
+            }
+        }
+    }
+
+    /// Register ping msg from a worker. Returns pong (without the ping hash) with worker status
+    /// and version of the assignment (if the worker is active). If the worker already has
+    /// the latest or the previous version of its assignment, only the changes are sent.
+    ///
+    /// Followers only know the state from the last reload, so they don't change it and only
+    /// reply to active workers which haven't applied a newer assignment than they know.
+    /// Otherwise the worker would be sent back to an older assignment.
+    pub fn ping(&mut self, worker_id: PeerId, msg: Ping, is_leader: bool) -> Option<Pong> {
+        let version = msg.sem_version();
+        let version_policy = &Config::get().worker_versions;
+        let warning = match version_policy.check(&version) {
+            VersionStatus::Supported => None,
+            VersionStatus::Deprecated { reject_after } => Some(format!(
+                "Worker version {version} is deprecated and will not be supported after {}. Please upgrade.",
+                humantime::format_rfc3339_seconds(reject_after)
+            )),
+            VersionStatus::Unsupported => {
+                log::debug!("Worker {worker_id} version not supported: {}", version);
+                return Some(Pong {
+                    status: Some(WorkerStatus::UnsupportedVersion(())),
+                    ..Default::default()
+                });
+            }
+        };
+        let pong = |status, assignment_version| {
+            Some(Pong {
+                ping_hash: Vec::new(),
+                status: Some(status),
+                assignment_version,
+                warning,
+            })
+        };
         let worker_state = match self.worker_states.get_mut(&worker_id) {
+            None if !is_leader => return None,
             None => {
                 log::debug!("Worker {worker_id} not registered");
-                return WorkerStatus::NotRegistered(());
+                return pong(WorkerStatus::NotRegistered(()), None);
             }
             Some(worker_state) => worker_state,
         };
-        worker_state.ping(msg);
-        if worker_state.jailed {
-            // Legacy worker version handling
-            return if version < Version::new(0, 2, 2) {
-                WorkerStatus::JailedV1(())
-            } else {
-                WorkerStatus::Jailed(worker_state.jail_reason_str())
-            };
+        let applied_version = msg.assignment_version;
+        if !is_leader {
+            let behind = matches!(applied_version, Some(v) if v > worker_state.assignment_version);
+            if worker_state.jailed || behind {
+                return None;
+            }
+        } else {
+            if worker_state.ping(msg, &self.known_units) {
+                self.update_bad_chunks();
+            }
+            let worker_state = &self.worker_states[&worker_id];
+            if worker_state.jailed {
+                // Legacy worker version handling
+                let status = if version_policy.supports_feature("jail_reason", &version) {
+                    WorkerStatus::Jailed(worker_state.jail_reason_str())
+                } else {
+                    WorkerStatus::JailedV1(())
+                };
+                return pong(status, None);
+            }
+            if worker_state.is_over_capacity() {
+                self.release_excess_units(worker_id);
+            }
         }
-        let state = chunks_to_worker_state(worker_state.assigned_chunks(&self.known_units));
-        WorkerStatus::Active(state)
+
+        let worker_state = &self.worker_states[&worker_id];
+        let cache = self.assignment_cache.entry(worker_id).or_default();
+        let assignment_version = worker_state.assignment_version;
+        if cache.current.as_ref().map(|a| a.version) != Some(assignment_version) {
+            let chunks = worker_state.assigned_chunks(&self.known_units);
+            let assignment = CachedAssignment::new(assignment_version, chunks);
+            cache.previous = cache.current.replace(assignment);
+        }
+        let current = cache.current.as_ref().expect("assignment cached");
+        let status = match (applied_version, &cache.previous) {
+            (Some(v), _) if v == current.version => WorkerStatus::AssignmentUnchanged(()),
+            (Some(v), Some(previous)) if v == previous.version => {
+                WorkerStatus::AssignmentDelta(current.delta(previous))
+            }
+            _ => WorkerStatus::Active(current.state.clone()),
+        };
+        pong(status, Some(assignment_version))
+    }
+
+    /// Worker's storage capacity dropped below assigned bytes. Unassign units until
+    /// the rest fits, starting with the largest units the worker hasn't downloaded yet.
+    fn release_excess_units(&mut self, worker_id: PeerId) {
+        let worker = self
+            .worker_states
+            .get_mut(&worker_id)
+            .expect("Unknown worker");
+        let mut units: Vec<(bool, Reverse<u64>, UnitId)> = worker
+            .assigned_units
+            .iter()
+            .map(|unit_id| {
+                let unit = self.known_units.get(unit_id).expect("Unknown unit");
+                (worker.has_unit(unit), Reverse(unit.size_bytes()), *unit_id)
+            })
+            .collect();
+        units.sort();
+
+        let mut num_released_units = 0;
+        for (_, Reverse(unit_size), unit_id) in units {
+            if !worker.is_over_capacity() {
+                break;
+            }
+            worker.remove_unit(&unit_id, unit_size);
+            self.units_assignments
+                .get_mut(&unit_id)
+                .expect("Unit assignment missing")
+                .retain(|id| *id != worker_id);
+            self.audit
+                .unassigned(unit_id, worker_id, AssignmentCause::OverCapacity);
+            num_released_units += 1;
+        }
+        log::info!(
+            "Worker {worker_id} capacity shrunk to {} bytes. Released {num_released_units} units",
+            worker.storage_capacity()
+        );
     }
 
     pub fn workers_to_dial(&self) -> Vec<PeerId> {
@@ -121,11 +616,56 @@ impl Scheduler {
         }
     }
 
+    /// Where to resume listing of each dataset, together with the generations of rewritten
+    /// units after the cursor, so the bundler gives them the same IDs again
+    pub fn listing_resume(&self) -> HashMap<String, ListingResume> {
+        let mut resume: HashMap<String, ListingResume> = self
+            .listing_cursors
+            .iter()
+            .map(|(dataset_url, cursor)| {
+                let resume = ListingResume {
+                    cursor: Some(cursor.clone()),
+                    generations: Default::default(),
+                };
+                (dataset_url.clone(), resume)
+            })
+            .collect();
+        for unit in self.known_units.values() {
+            if unit.generation == 0 {
+                continue;
+            }
+            let dataset_resume = resume.entry(unit.dataset_url().to_string()).or_default();
+            let after_cursor = match &dataset_resume.cursor {
+                Some(cursor) => unit.begin() > cursor.last_block,
+                None => true,
+            };
+            if after_cursor {
+                dataset_resume
+                    .generations
+                    .insert(unit.begin(), unit.generation);
+            }
+        }
+        resume
+    }
+
+    pub fn update_listing_cursor(&mut self, dataset_url: String, cursor: ListingCursor) {
+        log::debug!(
+            "Listing of {dataset_url} checkpointed at {}",
+            cursor.last_key
+        );
+        self.listing_cursors.insert(dataset_url, cursor);
+    }
+
     pub fn known_units(&self) -> HashMap<UnitId, SchedulingUnit> {
         self.known_units.clone()
     }
 
     pub fn new_unit(&mut self, unit: SchedulingUnit) {
+        // Dataset could have been removed from config while the unit was on its way
+        let bucket = unit.dataset_url().trim_start_matches("s3://");
+        if !Config::get().dataset_buckets.iter().any(|b| b == bucket) {
+            return log::debug!("Dropping unit of deprecated dataset: {unit}");
+        }
         let unit_id = unit.id();
         let unit_size = unit.size_bytes();
         let unit_str = unit.to_string();
@@ -137,6 +677,7 @@ impl Scheduler {
                     unit_id,
                     Vec::with_capacity(Config::get().replication_factor),
                 );
+                self.unit_pending(unit_id, "new_unit");
             }
             Some(old_unit) => {
                 // New chunks added to an existing unit
@@ -151,13 +692,304 @@ impl Scheduler {
                     .get_mut(&unit_id)
                     .expect("No assignment entry for unit")
                     .retain(|worker_id| {
-                        self.worker_states
+                        let expanded = self
+                            .worker_states
                             .get_mut(worker_id)
                             .expect("Unknown worker")
-                            .try_expand_unit(&unit_id, old_size, unit_size)
+                            .try_expand_unit(&unit_id, old_size, unit_size);
+                        if !expanded {
+                            self.audit.unassigned(
+                                unit_id,
+                                *worker_id,
+                                AssignmentCause::UnitResized,
+                            );
+                        }
+                        expanded
                     });
+                if self.num_replicas(&unit_id) < Config::get().replication_factor {
+                    self.unit_pending(unit_id, "resized_unit");
+                }
+            }
+        }
+    }
+
+    /// Flag chunks which at least `bad_chunk_min_workers` active workers fail to download.
+    /// A flagged chunk stays bad until it hasn't been failing for `bad_chunk_cooldown`,
+    /// so that workers aren't penalized when some of the failing ones go quiet for a while.
+    fn update_bad_chunks(&mut self) {
+        let config = Config::get();
+        let mut failures: HashMap<ChunkId, (&str, HashSet<PeerId>)> = HashMap::new();
+        for worker in self
+            .worker_states
+            .values()
+            .filter(|w| w.is_active() && !w.jailed)
+        {
+            for error in worker.download_errors.iter() {
+                failures
+                    .entry(error.chunk_id)
+                    .or_insert_with(|| (&error.chunk, HashSet::new()))
+                    .1
+                    .insert(worker.peer_id);
+            }
+        }
+        let now = SystemTime::now();
+        for (chunk_id, (chunk, workers)) in failures {
+            let num_workers = workers.len();
+            if num_workers < config.bad_chunk_min_workers {
+                continue;
+            }
+            if !self.bad_chunks.contains(&chunk_id) {
+                log::warn!("Chunk {chunk} fails to download on {num_workers} workers. Marking it as bad data");
+            }
+            self.bad_chunks_seen.insert(chunk_id, now);
+        }
+        self.bad_chunks_seen.retain(|_, last_seen| {
+            now.duration_since(*last_seen)
+                .is_ok_and(|d| d < config.bad_chunk_cooldown)
+        });
+
+        let bad_chunks: HashSet<ChunkId> = self.bad_chunks_seen.keys().copied().collect();
+        if bad_chunks == self.bad_chunks {
+            return;
+        }
+        let num_recovered = self.bad_chunks.difference(&bad_chunks).count();
+        if num_recovered > 0 {
+            log::info!("{num_recovered} bad chunks are no longer failing");
+        }
+        prometheus_metrics::bad_chunks(bad_chunks.len());
+        self.bad_chunks = bad_chunks;
+        // Missing chunk counts are compared between checks, so they have to be
+        // counted against the same set of bad chunks
+        for worker in self.worker_states.values_mut() {
+            worker.recount_missing_chunks(&self.known_units, &self.bad_chunks);
+        }
+    }
+
+    fn unit_pending(&mut self, unit_id: UnitId, trigger: &'static str) {
+        if self.pending_units.insert(unit_id) {
+            prometheus_metrics::schedule_triggered(trigger);
+            prometheus_metrics::pending_units(self.pending_units.len());
+        }
+    }
+
+    /// Pending units aren't saved in the state. After loading it, all under-replicated units
+    /// are pending, so that they don't wait for the next full scheduling round.
+    pub fn restore_pending_units(&mut self) {
+        let rep_factor = Config::get().replication_factor;
+        let under_replicated: Vec<UnitId> = self
+            .known_units
+            .keys()
+            .filter(|unit_id| self.num_replicas(unit_id) < rep_factor)
+            .copied()
+            .collect();
+        self.pending_units.extend(under_replicated);
+        prometheus_metrics::pending_units(self.pending_units.len());
+    }
+
+    pub fn has_pending_units(&self) -> bool {
+        !self.pending_units.is_empty()
+    }
+
+    /// Chunks of the unit starting from `rewritten_from` block were rewritten in the bucket.
+    /// Remove it together with all assignments, the new version will be sent by the bundler.
+    pub fn invalidate_unit(&mut self, unit_id: UnitId, rewritten_from: u32) {
+        let unit = match self.known_units.remove(&unit_id) {
+            Some(unit) => unit,
+            None => return log::debug!("Unknown unit invalidated: {unit_id}"),
+        };
+        let holder_ids = self.units_assignments.remove(&unit_id).unwrap_or_default();
+        self.pending_units.remove(&unit_id);
+        log::info!(
+            "Scheduling unit {unit} invalidated. Unassigned from {} workers",
+            holder_ids.len()
+        );
+        for worker_id in holder_ids {
+            self.get_worker(&worker_id)
+                .invalidate_unit(&unit, rewritten_from);
+            self.audit
+                .unassigned(unit_id, worker_id, AssignmentCause::UnitInvalidated);
+        }
+    }
+
+    /// Jail the worker on operator's request. Manually jailed workers are never released
+    /// automatically.
+    pub fn jail_worker(&mut self, worker_id: PeerId, message: String) -> anyhow::Result<()> {
+        let worker = self
+            .worker_states
+            .get_mut(&worker_id)
+            .ok_or_else(|| NotFound(format!("Unknown worker: {worker_id}")))?;
+        let reason = JailReason::Manual(message);
+        let cause = AssignmentCause::Jailed(reason.kind().to_string());
+        let units = worker.jail(reason);
+        log::info!(
+            "Worker {worker_id} jailed manually. Unassigned {} units",
+            units.len()
+        );
+        for unit_id in units.iter() {
+            self.units_assignments
+                .get_mut(unit_id)
+                .expect("Unit assignment missing")
+                .retain(|id| *id != worker_id);
+            self.audit.unassigned(*unit_id, worker_id, cause.clone());
+        }
+        if !units.is_empty() {
+            self.assign_units();
+        }
+        self.update_metrics();
+        Ok(())
+    }
+
+    /// Release the worker from jail on operator's request, regardless of the jail reason.
+    pub fn release_worker(&mut self, worker_id: PeerId) -> anyhow::Result<()> {
+        let worker = self
+            .worker_states
+            .get_mut(&worker_id)
+            .ok_or_else(|| NotFound(format!("Unknown worker: {worker_id}")))?;
+        anyhow::ensure!(worker.jailed, "Worker {worker_id} is not jailed");
+        worker.release();
+        self.update_metrics();
+        Ok(())
+    }
+
+    pub fn unit_overrides(&self) -> UnitOverrides {
+        self.unit_overrides.clone()
+    }
+
+    fn ensure_known(&self, unit_id: &UnitId, worker_id: &PeerId) -> anyhow::Result<()> {
+        if !self.known_units.contains_key(unit_id) {
+            anyhow::bail!(NotFound(format!("Unknown unit: {unit_id}")));
+        }
+        if !self.worker_states.contains_key(worker_id) {
+            anyhow::bail!(NotFound(format!("Unknown worker: {worker_id}")));
+        }
+        Ok(())
+    }
+
+    /// Always keep a replica of the unit on the given worker
+    pub fn pin_unit(&mut self, unit_id: UnitId, worker_id: PeerId) -> anyhow::Result<()> {
+        self.ensure_known(&unit_id, &worker_id)?;
+        log::info!("Pinning unit {unit_id} to worker {worker_id}");
+        self.unit_overrides.pin(unit_id, worker_id);
+        self.assign_units();
+        Ok(())
+    }
+
+    pub fn unpin_unit(&mut self, unit_id: UnitId, worker_id: PeerId) -> anyhow::Result<()> {
+        if !self.unit_overrides.unpin(&unit_id, &worker_id) {
+            anyhow::bail!(NotFound(format!(
+                "Unit {unit_id} is not pinned to worker {worker_id}"
+            )));
+        }
+        log::info!("Unpinned unit {unit_id} from worker {worker_id}");
+        Ok(())
+    }
+
+    /// Never assign the unit to the given worker
+    pub fn forbid_unit(&mut self, unit_id: UnitId, worker_id: PeerId) -> anyhow::Result<()> {
+        self.ensure_known(&unit_id, &worker_id)?;
+        log::info!("Forbidding unit {unit_id} on worker {worker_id}");
+        self.unit_overrides.forbid(unit_id, worker_id);
+        let holder_ids = self
+            .units_assignments
+            .get_mut(&unit_id)
+            .expect("No assignment entry for unit");
+        if let Some(idx) = holder_ids.iter().position(|id| *id == worker_id) {
+            holder_ids.remove(idx);
+            let unit_size = self.known_units[&unit_id].size_bytes();
+            self.get_worker(&worker_id).remove_unit(&unit_id, unit_size);
+            self.audit
+                .unassigned(unit_id, worker_id, AssignmentCause::Forbidden);
+            self.assign_units();
+        }
+        Ok(())
+    }
+
+    pub fn unforbid_unit(&mut self, unit_id: UnitId, worker_id: PeerId) -> anyhow::Result<()> {
+        if !self.unit_overrides.unforbid(&unit_id, &worker_id) {
+            anyhow::bail!(NotFound(format!(
+                "Unit {unit_id} is not forbidden on worker {worker_id}"
+            )));
+        }
+        log::info!("Allowed unit {unit_id} on worker {worker_id}");
+        Ok(())
+    }
+
+    pub fn worker_jail_history(&self, worker_id: &PeerId) -> Option<JailHistory> {
+        self.worker_states.get(worker_id).map(|w| JailHistory {
+            jailed: w.jailed,
+            release_time: w.release_time(),
+            history: w.jail_history.clone(),
+        })
+    }
+
+    pub fn worker_status(&self, worker_id: &PeerId) -> Option<WorkerStatusReport> {
+        let worker = self.worker_states.get(worker_id)?;
+        let (downloaded, missing): (Vec<DataChunk>, Vec<DataChunk>) = worker
+            .assigned_chunks(&self.known_units)
+            .partition(|chunk| worker.has_chunk(chunk));
+        Some(WorkerStatusReport {
+            state: worker.clone(),
+            jail_reason_message: worker.jailed.then(|| worker.jail_reason_str()),
+            assigned_chunks_count: downloaded.len() + missing.len(),
+            assigned_chunks_size: worker.assigned_bytes,
+            downloaded_chunks_count: downloaded.len(),
+            downloaded_chunks_size: downloaded.iter().map(|c| c.size_bytes).sum(),
+            missing_chunks: missing.iter().map(ToString::to_string).collect(),
+        })
+    }
+
+    /// Current assignment of all units in a compact form, broadcast to gateways.
+    /// It's split into parts of a single dataset with at most `MAX_SNAPSHOT_UNITS` units,
+    /// which gateways put together by the common timestamp. Workers are referred to
+    /// by their index in `worker_ids` of the part.
+    pub fn assignment_snapshots(&self) -> Vec<AssignmentSnapshot> {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time after epoch")
+            .as_millis() as u64;
+        let grouped_units = self
+            .known_units
+            .iter()
+            .into_group_map_by(|(_, unit)| unit.dataset_url());
+        let mut snapshots = Vec::new();
+        for (url, mut dataset_units) in grouped_units {
+            dataset_units.sort_by_key(|(_, unit)| unit.begin());
+            for part in dataset_units.chunks(MAX_SNAPSHOT_UNITS) {
+                let mut worker_ids = Vec::new();
+                let mut worker_idxs: HashMap<PeerId, u32> = HashMap::new();
+                let units = part
+                    .iter()
+                    .map(|(unit_id, unit)| {
+                        let workers = self
+                            .units_assignments
+                            .get(unit_id)
+                            .into_iter()
+                            .flatten()
+                            .map(|worker_id| {
+                                *worker_idxs.entry(*worker_id).or_insert_with(|| {
+                                    worker_ids.push(worker_id.to_string());
+                                    (worker_ids.len() - 1) as u32
+                                })
+                            })
+                            .collect();
+                        UnitAssignment {
+                            range: Some(Range::new(unit.begin(), unit.end())),
+                            workers,
+                        }
+                    })
+                    .collect();
+                snapshots.push(AssignmentSnapshot {
+                    timestamp_ms,
+                    worker_ids,
+                    datasets: vec![DatasetAssignment {
+                        url: url.to_string(),
+                        units,
+                    }],
+                    signature: Vec::new(),
+                });
             }
         }
+        snapshots
     }
 
     pub fn all_workers(&self) -> Vec<WorkerState> {
@@ -178,6 +1010,86 @@ impl Scheduler {
             .expect("Unknown worker")
     }
 
+    /// Check if assigning the unit to the worker would put two replicas
+    /// on workers owned by the same operator.
+    fn shares_operator_with_replica(&self, unit_id: &UnitId, worker_id: &PeerId) -> bool {
+        if !Config::get().spread_replicas_across_operators {
+            return false;
+        }
+        let address = &self.worker_states[worker_id].address;
+        self.units_assignments
+            .get(unit_id)
+            .into_iter()
+            .flatten()
+            .any(|holder_id| {
+                holder_id != worker_id && self.worker_states[holder_id].address == *address
+            })
+    }
+
+    /// Units with more than one replica on workers owned by the same operator.
+    pub fn colocated_units(&self) -> HashSet<UnitId> {
+        if !Config::get().spread_replicas_across_operators {
+            return HashSet::new();
+        }
+        self.units_assignments
+            .iter()
+            .filter_map(|(unit_id, holder_ids)| {
+                let num_operators = holder_ids
+                    .iter()
+                    .map(|id| self.worker_states[id].address)
+                    .unique()
+                    .count();
+                (num_operators < holder_ids.len()).then_some(*unit_id)
+            })
+            .collect()
+    }
+
+    /// Number of units and the lowest replication of a unit for each dataset with known units
+    fn dataset_stats(&self) -> HashMap<String, DatasetStats> {
+        let mut stats: HashMap<String, DatasetStats> = HashMap::new();
+        for (unit_id, unit) in self.known_units.iter() {
+            let num_replicas = self.num_replicas(unit_id);
+            let entry = stats
+                .entry(unit.dataset_url().to_string())
+                .or_insert(DatasetStats {
+                    num_units: 0,
+                    min_replicas: num_replicas,
+                });
+            entry.num_units += 1;
+            entry.min_replicas = entry.min_replicas.min(num_replicas);
+        }
+        stats
+    }
+
+    /// State of every configured dataset, by bucket
+    pub fn dataset_lifecycle(&self) -> BTreeMap<String, DatasetLifecycle> {
+        let config = Config::get();
+        let mut stats = self.dataset_stats();
+        let mut lifecycle = |bucket: &String, retirement: Option<&DatasetRetirement>| {
+            let stats = stats.remove(&format!("s3://{bucket}"));
+            let state = match (retirement, &stats) {
+                (None, _) => DatasetState::Active,
+                (Some(_), Some(_)) => DatasetState::Retiring,
+                (Some(_), None) => DatasetState::Removed,
+            };
+            let stats = stats.unwrap_or_default();
+            DatasetLifecycle {
+                state,
+                num_units: stats.num_units,
+                min_replicas: stats.min_replicas,
+                remove_after: retirement.and_then(|r| r.remove_after),
+            }
+        };
+        let mut result = BTreeMap::new();
+        for bucket in config.dataset_buckets.iter() {
+            result.insert(bucket.clone(), lifecycle(bucket, None));
+        }
+        for (bucket, retirement) in config.retiring_datasets.iter() {
+            result.insert(bucket.clone(), lifecycle(bucket, Some(retirement)));
+        }
+        result
+    }
+
     fn num_replicas(&self, unit_id: &UnitId) -> usize {
         self.units_assignments
             .get(unit_id)
@@ -186,15 +1098,100 @@ impl Scheduler {
     }
 
     pub fn schedule(&mut self, epoch: u32) {
+        let start = Instant::now();
         log::info!(
             "Starting scheduling. Total registered workers: {} Total units: {}",
             self.worker_states.len(),
             self.known_units.len()
         );
         self.release_jailed_workers();
+        self.clear_deprecated_units();
+        self.spread_colocated_replicas();
         self.mix_random_units();
         self.assign_units();
         self.last_schedule_epoch = epoch;
+        prometheus_metrics::schedule_duration(start.elapsed());
+        self.update_metrics();
+    }
+
+    /// Assign replicas of the units which arrived or grew since the last round, without
+    /// rebalancing. Download progress is only reset for workers whose assignment changed,
+    /// so that stale workers are still detected.
+    pub fn schedule_incremental(&mut self) {
+        let start = Instant::now();
+        log::info!(
+            "Starting incremental scheduling. Pending units: {}",
+            self.pending_units.len()
+        );
+        let versions: HashMap<PeerId, u64> = self
+            .worker_states
+            .iter()
+            .map(|(worker_id, w)| (*worker_id, w.assignment_version))
+            .collect();
+        let pending_units = std::mem::take(&mut self.pending_units);
+        self.assign_missing_replicas(Some(pending_units));
+        self.worker_states
+            .values_mut()
+            .filter(|w| versions.get(&w.peer_id) != Some(&w.assignment_version))
+            .for_each(|w| {
+                w.reset_download_progress(&self.known_units, &self.bad_chunks);
+                log::info!("{w}")
+            });
+        prometheus_metrics::incremental_schedule_duration(start.elapsed());
+        self.update_metrics();
+    }
+
+    /// Number of units with each replica count and bytes of replicas missing to reach
+    /// the replication factor, per dataset
+    fn replication_stats(&self) -> (HashMap<(String, usize), i64>, HashMap<String, u64>) {
+        let rep_factor = Config::get().replication_factor;
+        let mut units_by_replicas: HashMap<(String, usize), i64> = HashMap::new();
+        let mut missing_bytes: HashMap<String, u64> = HashMap::new();
+        for (unit_id, unit) in self.known_units.iter() {
+            let dataset = unit.dataset_url().to_string();
+            let num_replicas = self.num_replicas(unit_id);
+            *units_by_replicas
+                .entry((dataset.clone(), num_replicas))
+                .or_default() += 1;
+            let num_missing = rep_factor.saturating_sub(num_replicas) as u64;
+            *missing_bytes.entry(dataset).or_default() += num_missing * unit.size_bytes();
+        }
+        (units_by_replicas, missing_bytes)
+    }
+
+    fn jailed_workers_by_reason(&self) -> HashMap<&'static str, i64> {
+        let mut jailed_workers: HashMap<&'static str, i64> = HashMap::new();
+        for worker in self.worker_states.values() {
+            if let (true, Some(reason)) = (worker.jailed, &worker.jail_reason) {
+                *jailed_workers.entry(reason.kind()).or_default() += 1;
+            }
+        }
+        jailed_workers
+    }
+
+    pub fn update_metrics(&self) {
+        let (units_by_replicas, missing_bytes) = self.replication_stats();
+        prometheus_metrics::replication(units_by_replicas, missing_bytes);
+        prometheus_metrics::jailed_workers(self.jailed_workers_by_reason());
+
+        let mut workers_by_version: HashMap<String, i64> = HashMap::new();
+        let (mut assigned_bytes, mut stored_bytes) = (0, 0);
+        for worker in self.worker_states.values() {
+            let jailed = worker.jailed && worker.jail_reason.is_some();
+            if !jailed && worker.is_active() {
+                assigned_bytes += worker.assigned_bytes;
+                stored_bytes += worker.stored_bytes;
+            }
+            if worker.is_active() {
+                let version = worker
+                    .version
+                    .clone()
+                    .unwrap_or_else(|| "unknown".to_string());
+                *workers_by_version.entry(version).or_default() += 1;
+            }
+        }
+        prometheus_metrics::workers_by_version(workers_by_version);
+        prometheus_metrics::workers_data(assigned_bytes, stored_bytes);
     }
 
     pub fn update_workers(&mut self, workers: Vec<Worker>) {
@@ -205,9 +1202,10 @@ impl Scheduler {
         self.worker_states = workers
             .into_iter()
             .map(|w| {
-                let worker_state = old_workers
+                let mut worker_state = old_workers
                     .remove(&w.peer_id)
                     .unwrap_or_else(|| WorkerState::new(w.peer_id, w.address));
+                worker_state.address = w.address;
                 (w.peer_id, worker_state)
             })
             .collect();
@@ -215,11 +1213,14 @@ impl Scheduler {
         // Workers which remained in the map are no longer registered
         for (_, worker) in old_workers {
             log::info!("Worker unregistered: {worker:?}");
+            self.assignment_cache.remove(&worker.peer_id);
             for unit_id in worker.assigned_units {
                 self.units_assignments
                     .get_mut(&unit_id)
                     .expect("unknown unit")
                     .retain(|id| *id != worker.peer_id);
+                self.audit
+                    .unassigned(unit_id, worker.peer_id, AssignmentCause::Unregistered);
             }
         }
     }
@@ -228,35 +1229,40 @@ impl Scheduler {
         log::info!("Releasing jailed workers");
         self.worker_states
             .values_mut()
-            .filter(|w| w.jailed && w.is_active() && !w.is_unreachable())
+            .filter(|w| {
+                w.jailed
+                    && !w.is_jailed_manually()
+                    && w.can_be_released()
+                    && w.is_active()
+                    && !w.is_unreachable()
+            })
             .for_each(|w| w.release());
     }
 
     /// Jail workers which don't send pings.
     pub fn jail_inactive_workers(&mut self) -> bool {
         log::info!("Jailing inactive workers");
-        self.jail_workers(|w| !w.is_active(), JailReason::Inactive)
+        self.jail_workers(|w| (!w.is_active()).then_some(JailReason::Inactive))
     }
 
-    /// Jail workers which don't make download progress.
+    /// Jail workers which don't make download progress, or are too slow to ever sync.
     pub fn jail_stale_workers(&mut self) -> bool {
         log::info!("Jailing stale workers");
+        self.update_bad_chunks();
         let known_units = self.known_units.clone();
-        self.jail_workers(
-            |w| !w.check_download_progress(&known_units),
-            JailReason::Stale,
-        )
+        let bad_chunks = self.bad_chunks.clone();
+        self.jail_workers(|w| w.check_download_progress(&known_units, &bad_chunks))
     }
 
     pub fn jail_unreachable_workers(&mut self) -> bool {
         log::info!("Jailing unreachable workers");
-        self.jail_workers(|w| w.is_unreachable(), JailReason::Unreachable)
+        self.jail_workers(|w| w.is_unreachable().then_some(JailReason::Unreachable))
     }
 
+    /// Jail workers for which `criterion` returns a reason.
     fn jail_workers(
         &mut self,
-        mut criterion: impl FnMut(&mut WorkerState) -> bool,
-        reason: JailReason,
+        mut criterion: impl FnMut(&mut WorkerState) -> Option<JailReason>,
     ) -> bool {
         let mut num_jailed_workers: usize = 0;
         let mut num_unassigned_units = 0;
@@ -265,10 +1271,12 @@ impl Scheduler {
             .values_mut()
             .filter(|w| !w.jailed)
             .for_each(|w| {
-                if !criterion(w) {
-                    return;
-                }
+                let reason = match criterion(w) {
+                    Some(reason) => reason,
+                    None => return,
+                };
 
+                let cause = AssignmentCause::Jailed(reason.kind().to_string());
                 let units = w.jail(reason);
                 num_jailed_workers += 1;
                 num_unassigned_units += units.len();
@@ -276,25 +1284,69 @@ impl Scheduler {
                     self.units_assignments
                         .get_mut(&unit_id)
                         .expect("Unit assignment missing")
-                        .retain(|id| *id != w.peer_id)
+                        .retain(|id| *id != w.peer_id);
+                    self.audit.unassigned(unit_id, w.peer_id, cause.clone());
                 }
             });
 
         log::info!("Jailed {num_jailed_workers} workers. Unassigned {num_unassigned_units} units");
+        prometheus_metrics::units_unassigned("jailing", num_unassigned_units);
         if num_unassigned_units > 0 {
             self.assign_units();
         }
+        self.update_metrics();
         num_jailed_workers > 0
     }
 
+    /// Keep only one replica per operator for each unit. The removed replicas
+    /// will be assigned to other workers.
+    fn spread_colocated_replicas(&mut self) {
+        // Replicas of retiring datasets wouldn't be assigned again, so they are kept
+        let config = Config::get();
+        let mut colocated_units = self.colocated_units();
+        colocated_units.retain(|unit_id| {
+            config
+                .dataset_retirement(self.known_units[unit_id].dataset_url())
+                .is_none()
+        });
+        if colocated_units.is_empty() {
+            return;
+        }
+        log::info!("Spreading replicas of {} units", colocated_units.len());
+        for unit_id in colocated_units {
+            let unit_size = self.known_units[&unit_id].size_bytes();
+            let holder_ids = self
+                .units_assignments
+                .get_mut(&unit_id)
+                .expect("Unit assignment missing");
+            let mut operators = HashSet::new();
+            holder_ids.retain(|holder_id| {
+                let worker = self
+                    .worker_states
+                    .get_mut(holder_id)
+                    .expect("Unknown worker");
+                if operators.insert(worker.address) {
+                    return true;
+                }
+                worker.remove_unit(&unit_id, unit_size);
+                self.audit
+                    .unassigned(unit_id, *holder_id, AssignmentCause::Colocated);
+                false
+            });
+        }
+    }
+
     fn mix_random_units(&mut self) {
         log::info!("Mixing random units");
 
-        // Group units by dataset and unassign random fraction of units for each dataset
+        // Group units by dataset and unassign random fraction of units for each dataset.
+        // Replicas of retiring datasets wouldn't be assigned again, so they are not mixed.
+        let config = Config::get();
         let grouped_units = self
             .known_units
             .iter()
             .filter(|(unit_id, _)| self.num_replicas(unit_id) > 0)
+            .filter(|(_, unit)| config.dataset_retirement(unit.dataset_url()).is_none())
             .into_group_map_by(|(_, unit)| unit.dataset_url());
 
         for (dataset_url, mut dataset_units) in grouped_units {
@@ -315,18 +1367,208 @@ impl Scheduler {
                     .units_assignments
                     .get_mut(*unit_id)
                     .expect("no empty assignments");
-                let random_idx = thread_rng().gen_range(0..holder_ids.len());
+                // Pinned replicas are never mixed
+                let holder_idxs: Vec<usize> = (0..holder_ids.len())
+                    .filter(|i| !self.unit_overrides.is_pinned(unit_id, &holder_ids[*i]))
+                    .collect();
+                let random_idx = match holder_idxs.choose(&mut thread_rng()) {
+                    Some(idx) => *idx,
+                    None => continue,
+                };
                 let holder_id = holder_ids.remove(random_idx);
+                self.mixed_replicas.insert((**unit_id, holder_id));
+                prometheus_metrics::units_unassigned("mixing", 1);
                 self.worker_states
                     .get_mut(&holder_id)
                     .expect("Unknown worker")
                     .remove_unit(unit_id, unit.size_bytes());
+                self.audit
+                    .unassigned(**unit_id, holder_id, AssignmentCause::Mixing);
+            }
+        }
+    }
+
+    /// Make sure every pinned unit is assigned to the worker it's pinned to, as long as
+    /// the worker is eligible and has enough capacity. If the unit is already fully replicated,
+    /// one of the non-pinned replicas is removed. Pinned units are never held back by the budget,
+    /// but they use it up.
+    fn assign_pinned_units(&mut self, budget: &mut AssignmentBudget) {
+        let config = Config::get();
+        let rep_factor = config.replication_factor;
+        for (unit_id, pinned_workers) in self.unit_overrides.pinned.iter() {
+            let unit_size = match self.known_units.get(unit_id) {
+                Some(unit) if config.dataset_retirement(unit.dataset_url()).is_none() => {
+                    unit.size_bytes()
+                }
+                _ => continue,
+            };
+            for worker_id in pinned_workers {
+                match self.worker_states.get_mut(worker_id) {
+                    Some(w) if w.is_active() && !w.jailed => {
+                        let unit = &self.known_units[unit_id];
+                        let stored_bytes = w.stored_bytes_of(unit);
+                        if w.stores_outdated_data(unit) || !w.try_assign_unit(*unit_id, unit_size) {
+                            continue;
+                        }
+                        budget.spend(*worker_id, unit_size.saturating_sub(stored_bytes));
+                    }
+                    _ => continue,
+                }
+                log::debug!("Assigned pinned unit {unit_id} to worker {worker_id}");
+                self.audit
+                    .assigned(*unit_id, *worker_id, AssignmentCause::Pinned);
+                let holder_ids = self
+                    .units_assignments
+                    .get_mut(unit_id)
+                    .expect("No unit assignment");
+                holder_ids.push(*worker_id);
+                if holder_ids.len() <= rep_factor {
+                    continue;
+                }
+                if let Some(idx) = holder_ids
+                    .iter()
+                    .position(|id| !pinned_workers.contains(id))
+                {
+                    let holder_id = holder_ids.remove(idx);
+                    self.worker_states
+                        .get_mut(&holder_id)
+                        .expect("Unknown worker")
+                        .remove_unit(unit_id, unit_size);
+                    self.audit
+                        .unassigned(*unit_id, holder_id, AssignmentCause::ReplacedByPinned);
+                }
             }
         }
     }
 
+    /// Assign units which are missing replicas to workers that still store (some of) their
+    /// chunks, e.g. from a previous assignment. Workers storing the most data go first,
+    /// units with the fewest replicas get the budget first.
+    fn assign_units_to_holders(
+        &mut self,
+        unit_ids: &[UnitId],
+        budget: &mut AssignmentBudget,
+        paced_units: &mut HashSet<UnitId>,
+    ) {
+        let rep_factor = Config::get().replication_factor;
+        let mut unit_ids = unit_ids.to_vec();
+        unit_ids.sort_by_cached_key(|unit_id| self.num_replicas(unit_id));
+
+        let mut num_assigned = 0;
+        let mut saved_bytes = 0;
+        for unit_id in unit_ids {
+            if budget.exhausted() {
+                paced_units.insert(unit_id);
+                continue;
+            }
+            let unit = &self.known_units[&unit_id];
+            let unit_size = unit.size_bytes();
+            let mut candidates: Vec<(u64, PeerId)> = self
+                .worker_states
+                .values()
+                .filter(|w| w.is_active() && !w.jailed && !w.assigned_units.contains(&unit_id))
+                .filter(|w| !self.mixed_replicas.contains(&(unit_id, w.peer_id)))
+                .filter(|w| !w.stores_outdated_data(unit))
+                .filter_map(|w| {
+                    let stored_bytes = w.stored_bytes_of(unit);
+                    (stored_bytes > 0).then_some((stored_bytes, w.peer_id))
+                })
+                .collect();
+            candidates.sort_by_key(|(stored_bytes, _)| Reverse(*stored_bytes));
+
+            for (stored_bytes, worker_id) in candidates {
+                if self.num_replicas(&unit_id) >= rep_factor {
+                    break;
+                }
+                let download_bytes = unit_size.saturating_sub(stored_bytes);
+                if self.unit_overrides.is_forbidden(&unit_id, &worker_id)
+                    || self.shares_operator_with_replica(&unit_id, &worker_id)
+                {
+                    continue;
+                }
+                if !budget.allows(&worker_id, download_bytes) {
+                    paced_units.insert(unit_id);
+                    continue;
+                }
+                if !self
+                    .get_worker(&worker_id)
+                    .try_assign_unit(unit_id, unit_size)
+                {
+                    continue;
+                }
+                budget.spend(worker_id, download_bytes);
+                log::debug!("Assigned unit {unit_id} back to worker {worker_id}");
+                self.audit
+                    .assigned(unit_id, worker_id, AssignmentCause::StoredData);
+                self.units_assignments
+                    .get_mut(&unit_id)
+                    .expect("No unit assignment")
+                    .push(worker_id);
+                num_assigned += 1;
+                saved_bytes += stored_bytes;
+            }
+        }
+        log::info!(
+            "Assigned {num_assigned} units to workers already storing them. Saved {saved_bytes} bytes of downloads"
+        );
+        prometheus_metrics::reassignment_saved_bytes(num_assigned, saved_bytes);
+    }
+
     fn assign_units(&mut self) {
+        self.assign_missing_replicas(None);
+        self.prune_mixed_replicas();
+        self.worker_states
+            .values_mut()
+            .filter(|w| w.is_active() && !w.jailed)
+            .for_each(|w| {
+                w.reset_download_progress(&self.known_units, &self.bad_chunks);
+                log::info!("{w}")
+            });
+    }
+
+    /// Forget mixed replicas which have been deleted by their workers or aren't relevant anymore
+    fn prune_mixed_replicas(&mut self) {
+        let (known_units, worker_states) = (&self.known_units, &self.worker_states);
+        self.mixed_replicas.retain(|(unit_id, worker_id)| {
+            match (known_units.get(unit_id), worker_states.get(worker_id)) {
+                (Some(unit), Some(worker)) => {
+                    !worker.assigned_units.contains(unit_id) && worker.stored_bytes_of(unit) > 0
+                }
+                _ => false,
+            }
+        });
+    }
+
+    /// Assign missing replicas of the given units, or of all known units if `None`.
+    /// Pinned units are only placed in the full pass.
+    fn assign_missing_replicas(&mut self, unit_ids: Option<HashSet<UnitId>>) {
         log::info!("Assigning units");
+        let config = Config::get();
+        let mut budget = std::mem::take(&mut self.assignment_budget);
+        budget.refresh(self.audit.epoch(), &config);
+        let spent_before = budget.total;
+        // Units which didn't get a replica only because of the budget
+        let mut paced_units = HashSet::new();
+        if unit_ids.is_none() {
+            self.assign_pinned_units(&mut budget);
+        }
+
+        // Retiring datasets don't get any new replicas
+        let rep_factor = config.replication_factor;
+        let unit_ids: Vec<UnitId> = match unit_ids {
+            Some(unit_ids) => unit_ids.into_iter().collect(),
+            None => self.known_units.keys().copied().collect(),
+        };
+        let unit_ids: Vec<UnitId> = unit_ids
+            .into_iter()
+            .filter(|unit_id| self.num_replicas(unit_id) < rep_factor)
+            .filter(|unit_id| {
+                self.known_units
+                    .get(unit_id)
+                    .is_some_and(|unit| config.dataset_retirement(unit.dataset_url()).is_none())
+            })
+            .collect();
+        self.assign_units_to_holders(&unit_ids, &mut budget, &mut paced_units);
 
         // Only active and non-jailed workers are eligible for assignment
         let mut workers: Vec<&WorkerState> = self
@@ -344,14 +1586,13 @@ impl Scheduler {
             .collect();
 
         // Use a heap based on nuber of missing replicas so that units are assigned
-        // more evenly if there is not enough worker capacity for all
-        let rep_factor = Config::get().replication_factor;
-        let mut units: BinaryHeap<(usize, u64, UnitId)> = self
-            .known_units
-            .iter()
-            .filter_map(|(unit_id, unit)| {
-                let missing_replicas = rep_factor - self.num_replicas(unit_id);
-                (missing_replicas > 0).then_some((missing_replicas, unit.size_bytes(), *unit_id))
+        // more evenly if there is not enough worker capacity for all.
+        let mut units: BinaryHeap<(usize, u64, UnitId)> = unit_ids
+            .into_iter()
+            .filter_map(|unit_id| {
+                let missing_replicas = rep_factor.saturating_sub(self.num_replicas(&unit_id));
+                let unit_size = self.known_units[&unit_id].size_bytes();
+                (missing_replicas > 0).then_some((missing_replicas, unit_size, unit_id))
             })
             .collect();
 
@@ -361,17 +1602,42 @@ impl Scheduler {
             units.len()
         );
 
+        // Units with the most missing replicas go first, so they get the budget first
+        let mut workers_exhausted = false;
         while let Some((missing_replicas, unit_size, unit_id)) = units.pop() {
+            if budget.exhausted() {
+                paced_units.insert(unit_id);
+                continue;
+            }
             let mut rejected_workers = vec![];
             let mut found_worker = false;
+            let mut over_budget = false;
             while let Some((remaining_capacity, worker_id)) = workers.pop() {
-                if self
-                    .get_worker(&worker_id)
-                    .try_assign_unit(unit_id, unit_size)
+                let eligible = !self.unit_overrides.is_forbidden(&unit_id, &worker_id)
+                    && !self.shares_operator_with_replica(&unit_id, &worker_id)
+                    && !self.worker_states[&worker_id]
+                        .stores_outdated_data(&self.known_units[&unit_id]);
+                if eligible && !budget.allows(&worker_id, unit_size) {
+                    over_budget = true;
+                    rejected_workers.push((remaining_capacity, worker_id));
+                    continue;
+                }
+                if eligible
+                    && self
+                        .get_worker(&worker_id)
+                        .try_assign_unit(unit_id, unit_size)
                 {
                     log::debug!("Assigned unit {unit_id} to worker {worker_id}");
+                    self.audit
+                        .assigned(unit_id, worker_id, AssignmentCause::Scheduling);
                     found_worker = true;
-                    workers.push((remaining_capacity - unit_size, worker_id));
+                    budget.spend(worker_id, unit_size);
+                    // Workers which used up their budget get nothing more in this epoch
+                    if budget.worker_exhausted(&worker_id) {
+                        workers_exhausted = true;
+                    } else {
+                        workers.push((remaining_capacity - unit_size, worker_id));
+                    }
                     self.units_assignments
                         .get_mut(&unit_id)
                         .expect("No unit assignment")
@@ -385,22 +1651,567 @@ impl Scheduler {
                 log::debug!("Unit {unit_id} still has {missing_replicas} missing replicas");
                 units.push((missing_replicas - 1, unit_size, unit_id));
             }
+            if !found_worker && (over_budget || workers_exhausted) {
+                paced_units.insert(unit_id);
+            }
             workers.extend(rejected_workers);
         }
 
+        let num_missing = self
+            .units_assignments
+            .values()
+            .filter(|workers| workers.len() < rep_factor)
+            .count();
+        let newly_assigned = budget.total - spent_before;
         log::info!(
-            "Assignment complete. {} units are missing some replicas",
-            self.units_assignments
-                .values()
-                .filter(|workers| workers.len() < rep_factor)
-                .count()
+            "Assignment complete. {newly_assigned} bytes newly assigned, {num_missing} units are missing some replicas"
         );
-        self.worker_states
-            .values_mut()
-            .filter(|w| w.is_active() && !w.jailed)
-            .for_each(|w| {
-                w.reset_download_progress(&self.known_units);
-                log::info!("{w}")
-            });
+        if !paced_units.is_empty() {
+            log::info!(
+                "Assignment limits reached, {} units will get replicas in the next epoch",
+                paced_units.len()
+            );
+        }
+        self.assignment_budget = budget;
+        prometheus_metrics::assignment_paced(newly_assigned, paced_units.len());
+        // Paced units stay pending, so they are assigned as soon as the budget allows
+        self.pending_units = paced_units;
+        prometheus_metrics::pending_units(self.pending_units.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use contract_client::Address;
+    use subsquid_messages::Range;
+
+    use super::*;
+    use crate::worker_state::ChunkError;
+
+    fn test_unit(index: u32, size_bytes: u64) -> SchedulingUnit {
+        let first_block = index * 1000;
+        let chunk_str = format!(
+            "0000000000/{first_block:010}-{:010}-00000000",
+            first_block + 999
+        );
+        SchedulingUnit::from_slice(&[DataChunk::new("dataset", &chunk_str, size_bytes).unwrap()])
+    }
+
+    fn test_scheduler(num_workers: usize, units: Vec<SchedulingUnit>) -> Scheduler {
+        let worker_states = (0..num_workers)
+            .map(|_| {
+                let worker = WorkerState::new(PeerId::random(), Default::default());
+                (worker.peer_id, worker)
+            })
+            .collect();
+        Scheduler {
+            units_assignments: units.iter().map(|unit| (unit.id(), vec![])).collect(),
+            known_units: units.into_iter().map(|unit| (unit.id(), unit)).collect(),
+            worker_states,
+            ..Default::default()
+        }
+    }
+
+    fn num_assigned(scheduler: &Scheduler) -> usize {
+        scheduler.units_assignments.values().map(Vec::len).sum()
+    }
+
+    #[test]
+    fn test_worker_budget() {
+        let _config = Config::set_for_test(|config| {
+            config.replication_factor = 1;
+            config.spread_replicas_across_operators = false;
+            config.max_assigned_bytes_per_worker = Some(100);
+        });
+        let units = (0..5).map(|i| test_unit(i, 100)).collect();
+        let mut scheduler = test_scheduler(3, units);
+        scheduler.set_epoch(1);
+
+        scheduler.assign_missing_replicas(None);
+        assert_eq!(num_assigned(&scheduler), 3);
+        assert!(scheduler
+            .worker_states
+            .values()
+            .all(|w| w.assigned_bytes == 100));
+        assert_eq!(scheduler.pending_units.len(), 2);
+
+        // Another pass in the same epoch doesn't reset the budget
+        scheduler.assign_missing_replicas(None);
+        assert_eq!(num_assigned(&scheduler), 3);
+        assert_eq!(scheduler.pending_units.len(), 2);
+
+        scheduler.set_epoch(2);
+        scheduler.assign_missing_replicas(None);
+        assert_eq!(num_assigned(&scheduler), 5);
+        assert!(scheduler.pending_units.is_empty());
+    }
+
+    #[test]
+    fn test_total_budget() {
+        let _config = Config::set_for_test(|config| {
+            config.replication_factor = 1;
+            config.spread_replicas_across_operators = false;
+            config.max_assigned_bytes = Some(150);
+        });
+        let units = (0..4).map(|i| test_unit(i, 100)).collect();
+        let mut scheduler = test_scheduler(3, units);
+        scheduler.set_epoch(1);
+
+        scheduler.assign_missing_replicas(None);
+        assert_eq!(num_assigned(&scheduler), 1);
+        assert_eq!(scheduler.pending_units.len(), 3);
+        assert_eq!(scheduler.assignment_budget.total, 100);
+    }
+
+    #[test]
+    fn test_budget_goes_to_most_missing_replicas() {
+        let _config = Config::set_for_test(|config| {
+            config.replication_factor = 2;
+            config.spread_replicas_across_operators = false;
+            config.max_assigned_bytes = Some(100);
+        });
+        let replicated = test_unit(0, 100);
+        let unreplicated = test_unit(1, 100);
+        let (replicated_id, unreplicated_id) = (replicated.id(), unreplicated.id());
+        let mut scheduler = test_scheduler(3, vec![replicated, unreplicated]);
+        let holder_id = *scheduler.worker_states.keys().next().unwrap();
+        assert!(scheduler
+            .get_worker(&holder_id)
+            .try_assign_unit(replicated_id, 100));
+        scheduler
+            .units_assignments
+            .insert(replicated_id, vec![holder_id]);
+        scheduler.set_epoch(1);
+
+        scheduler.assign_missing_replicas(None);
+        assert_eq!(scheduler.num_replicas(&unreplicated_id), 1);
+        assert_eq!(scheduler.num_replicas(&replicated_id), 1);
+        assert_eq!(
+            scheduler.pending_units,
+            HashSet::from([replicated_id, unreplicated_id])
+        );
+    }
+
+    #[test]
+    fn test_incremental_schedule() {
+        let _config = Config::set_for_test(|config| {
+            config.replication_factor = 1;
+            config.spread_replicas_across_operators = false;
+            config.dataset_buckets = vec!["dataset".to_string()];
+        });
+        let (assigned, unassigned, new) = (test_unit(0, 100), test_unit(1, 100), test_unit(2, 100));
+        let (assigned_id, unassigned_id, new_id) = (assigned.id(), unassigned.id(), new.id());
+        let mut scheduler = test_scheduler(2, vec![assigned, unassigned]);
+        let holder_id = *scheduler.worker_states.keys().next().unwrap();
+        let holder = scheduler.get_worker(&holder_id);
+        assert!(holder.try_assign_unit(assigned_id, 100));
+        holder.num_missing_chunks = 1;
+        holder.last_assignment = UNIX_EPOCH;
+        scheduler
+            .units_assignments
+            .insert(assigned_id, vec![holder_id]);
+
+        scheduler.new_unit(new);
+        assert!(scheduler.has_pending_units());
+        scheduler.schedule_incremental();
+
+        assert_eq!(scheduler.num_replicas(&new_id), 1);
+        assert_eq!(scheduler.num_replicas(&unassigned_id), 0);
+        assert!(!scheduler.has_pending_units());
+        // The other worker got the new unit, the holder's progress isn't reset
+        let holder = &scheduler.worker_states[&holder_id];
+        assert!(!holder.assigned_units.contains(&new_id));
+        assert_eq!(holder.num_missing_chunks, 1);
+        assert_eq!(holder.last_assignment, UNIX_EPOCH);
+    }
+
+    #[test]
+    fn test_bad_chunks() {
+        let _config = Config::set_for_test(|config| {
+            config.bad_chunk_min_workers = 2;
+            config.bad_chunk_cooldown = Duration::from_secs(3600);
+        });
+        let unit = test_unit(0, 100);
+        let unit_id = unit.id();
+        let chunk = unit.chunks.first().clone();
+        let mut scheduler = test_scheduler(3, vec![unit]);
+        let worker_ids: Vec<PeerId> = scheduler.worker_states.keys().copied().collect();
+        for worker_id in worker_ids.iter() {
+            assert!(scheduler
+                .get_worker(worker_id)
+                .try_assign_unit(unit_id, 100));
+        }
+        let error = ChunkError {
+            chunk_id: chunk.id(),
+            chunk: chunk.to_string(),
+            failed_attempts: 3,
+            error: "Not found".to_string(),
+        };
+        scheduler.get_worker(&worker_ids[0]).download_errors = vec![error.clone()];
+        scheduler.update_bad_chunks();
+        assert!(scheduler.bad_chunks.is_empty());
+
+        scheduler.get_worker(&worker_ids[1]).download_errors = vec![error];
+        scheduler.update_bad_chunks();
+        assert_eq!(scheduler.bad_chunks, HashSet::from([chunk.id()]));
+        // Progress baseline doesn't include the bad chunk anymore
+        assert!(scheduler
+            .worker_states
+            .values()
+            .all(|w| w.num_missing_chunks == 0));
+
+        // The chunk stays bad during the cooldown even if fewer workers report it
+        scheduler.get_worker(&worker_ids[1]).download_errors.clear();
+        scheduler.update_bad_chunks();
+        assert_eq!(scheduler.bad_chunks, HashSet::from([chunk.id()]));
+
+        scheduler.bad_chunks_seen.insert(chunk.id(), UNIX_EPOCH);
+        scheduler.update_bad_chunks();
+        assert!(scheduler.bad_chunks.is_empty());
+        assert!(scheduler
+            .worker_states
+            .values()
+            .all(|w| w.num_missing_chunks == 1));
+    }
+
+    #[test]
+    fn test_repeated_chunk_error() {
+        let _config = Config::set_for_test(|config| {
+            config.bad_chunk_min_workers = 2;
+        });
+        let unit = test_unit(0, 100);
+        let unit_id = unit.id();
+        let chunk = unit.chunks.first().clone();
+        let mut scheduler = test_scheduler(2, vec![unit]);
+        let worker_id = *scheduler.worker_states.keys().next().unwrap();
+        let worker = scheduler.get_worker(&worker_id);
+        assert!(worker.try_assign_unit(unit_id, 100));
+        let error = ChunkError {
+            chunk_id: chunk.id(),
+            chunk: chunk.to_string(),
+            failed_attempts: 3,
+            error: "Not found".to_string(),
+        };
+        worker.download_errors = vec![error; 3];
+
+        // A single worker can't mark the chunk as bad, however many times it reports it
+        scheduler.update_bad_chunks();
+        assert!(scheduler.bad_chunks.is_empty());
+    }
+
+    #[test]
+    fn test_restore_pending_units() {
+        let _config = Config::set_for_test(|config| {
+            config.replication_factor = 1;
+        });
+        let (assigned, unassigned) = (test_unit(0, 100), test_unit(1, 100));
+        let (assigned_id, unassigned_id) = (assigned.id(), unassigned.id());
+        let mut scheduler = test_scheduler(1, vec![assigned, unassigned]);
+        let worker_id = *scheduler.worker_states.keys().next().unwrap();
+        scheduler
+            .units_assignments
+            .insert(assigned_id, vec![worker_id]);
+
+        let state = serde_json::to_vec(&scheduler).unwrap();
+        let mut scheduler: Scheduler = serde_json::from_slice(&state).unwrap();
+        assert!(!scheduler.has_pending_units());
+        scheduler.restore_pending_units();
+        assert_eq!(scheduler.pending_units, HashSet::from([unassigned_id]));
+    }
+
+    #[test]
+    fn test_follower_ping() {
+        let _config = Config::set_for_test(|_| {});
+        let mut scheduler = test_scheduler(1, vec![]);
+        let worker_id = *scheduler.worker_states.keys().next().unwrap();
+        let last_ping = scheduler.worker_states[&worker_id].last_ping;
+        let msg = Ping {
+            version: Some("0.2.3".to_string()),
+            stored_bytes: Some(100),
+            ..Default::default()
+        };
+
+        // Followers reply but don't change the worker state
+        scheduler.ping(worker_id, msg.clone(), false);
+        let worker = &scheduler.worker_states[&worker_id];
+        assert_eq!(worker.last_ping, last_ping);
+        assert_eq!(worker.stored_bytes, 0);
+
+        scheduler.ping(worker_id, msg, true);
+        assert_eq!(scheduler.worker_states[&worker_id].stored_bytes, 100);
+    }
+
+    fn leader_ping(scheduler: &mut Scheduler, worker_id: PeerId, applied: Option<u64>) -> Pong {
+        let msg = Ping {
+            version: Some("0.2.3".to_string()),
+            assignment_version: applied,
+            ..Default::default()
+        };
+        scheduler.ping(worker_id, msg, true).expect("no pong")
+    }
+
+    fn unit_ranges(indexes: &[u32]) -> subsquid_messages::WorkerState {
+        ranges_to_worker_state(indexes.iter().map(|i| {
+            let range = Range::new(i * 1000, i * 1000 + 999);
+            ("s3://dataset".to_string(), range)
+        }))
+    }
+
+    #[test]
+    fn test_assignment_delta() {
+        let _config = Config::set_for_test(|_| {});
+        let units: Vec<SchedulingUnit> = (0..3).map(|i| test_unit(i, 100)).collect();
+        let unit_ids: Vec<UnitId> = units.iter().map(SchedulingUnit::id).collect();
+        let mut scheduler = test_scheduler(1, units);
+        let worker_id = *scheduler.worker_states.keys().next().unwrap();
+        assert!(scheduler
+            .get_worker(&worker_id)
+            .try_assign_unit(unit_ids[0], 100));
+
+        // Worker without an assignment gets the full one
+        let pong = leader_ping(&mut scheduler, worker_id, None);
+        assert_eq!(pong.status, Some(WorkerStatus::Active(unit_ranges(&[0]))));
+        let v1 = pong.assignment_version.unwrap();
+
+        // Worker with the current version
+        let pong = leader_ping(&mut scheduler, worker_id, Some(v1));
+        assert_eq!(pong.status, Some(WorkerStatus::AssignmentUnchanged(())));
+        assert_eq!(pong.assignment_version, Some(v1));
+
+        // Worker with the previous version gets the changes
+        assert!(scheduler
+            .get_worker(&worker_id)
+            .try_assign_unit(unit_ids[1], 100));
+        let pong = leader_ping(&mut scheduler, worker_id, Some(v1));
+        let delta = WorkerStateDelta {
+            added: Some(unit_ranges(&[1])),
+            removed: Some(unit_ranges(&[])),
+        };
+        assert_eq!(pong.status, Some(WorkerStatus::AssignmentDelta(delta)));
+        let v2 = pong.assignment_version.unwrap();
+        assert!(v2 > v1);
+
+        // Assignment changed twice between pings, the delta covers both changes
+        let worker = scheduler.get_worker(&worker_id);
+        worker.remove_unit(&unit_ids[0], 100);
+        assert!(worker.try_assign_unit(unit_ids[2], 100));
+        let pong = leader_ping(&mut scheduler, worker_id, Some(v2));
+        let delta = WorkerStateDelta {
+            added: Some(unit_ranges(&[2])),
+            removed: Some(unit_ranges(&[0])),
+        };
+        assert_eq!(pong.status, Some(WorkerStatus::AssignmentDelta(delta)));
+        let v3 = pong.assignment_version.unwrap();
+
+        // Older, unknown and newer versions get the full assignment
+        for applied in [v1, 1, v3 + 1] {
+            let pong = leader_ping(&mut scheduler, worker_id, Some(applied));
+            assert_eq!(
+                pong.status,
+                Some(WorkerStatus::Active(unit_ranges(&[1, 2])))
+            );
+            assert_eq!(pong.assignment_version, Some(v3));
+        }
+    }
+
+    #[test]
+    fn test_release_excess_units() {
+        let _config = Config::set_for_test(|config| {
+            config.worker_storage_bytes = 1000;
+        });
+        let units: Vec<SchedulingUnit> = [300, 200, 100, 400]
+            .into_iter()
+            .enumerate()
+            .map(|(i, size)| test_unit(i as u32, size))
+            .collect();
+        let unit_ids: Vec<UnitId> = units.iter().map(SchedulingUnit::id).collect();
+        let mut scheduler = test_scheduler(1, units);
+        let worker_id = *scheduler.worker_states.keys().next().unwrap();
+        for (unit_id, size) in unit_ids.iter().zip([300, 200, 100, 400]) {
+            assert!(scheduler
+                .get_worker(&worker_id)
+                .try_assign_unit(*unit_id, size));
+            scheduler
+                .units_assignments
+                .get_mut(unit_id)
+                .unwrap()
+                .push(worker_id);
+        }
+
+        // Units 0 and 3 are downloaded, the capacity shrinks to 600 bytes
+        let msg = Ping {
+            version: Some("0.2.3".to_string()),
+            stored_ranges: vec![subsquid_messages::DatasetRanges {
+                url: "s3://dataset".to_string(),
+                ranges: vec![Range::new(0, 999), Range::new(3000, 3999)],
+            }],
+            capacity_bytes: Some(600),
+            ..Default::default()
+        };
+        scheduler.ping(worker_id, msg, true).expect("no pong");
+
+        // Undownloaded units are released first, then the largest downloaded ones
+        let worker = &scheduler.worker_states[&worker_id];
+        assert_eq!(worker.assigned_units, [unit_ids[0]].into_iter().collect());
+        assert_eq!(worker.assigned_bytes, 300);
+        assert!(worker.assigned_bytes <= worker.storage_capacity());
+        for unit_id in &unit_ids[1..] {
+            assert!(scheduler.units_assignments[unit_id].is_empty());
+        }
+        assert_eq!(scheduler.units_assignments[&unit_ids[0]], [worker_id]);
+    }
+
+    #[test]
+    fn test_replication_metrics() {
+        let _config = Config::set_for_test(|config| {
+            config.replication_factor = 2;
+        });
+        let other_chunk = "0000000000/0000000000-0000000999-00000000";
+        let mut units = vec![test_unit(0, 100), test_unit(1, 200), test_unit(2, 50)];
+        units.push(SchedulingUnit::from_slice(&[DataChunk::new(
+            "other",
+            other_chunk,
+            10,
+        )
+        .unwrap()]));
+        let unit_ids: Vec<UnitId> = units.iter().map(SchedulingUnit::id).collect();
+        let mut scheduler = test_scheduler(3, units);
+        let worker_ids: Vec<PeerId> = scheduler.worker_states.keys().copied().collect();
+        scheduler
+            .units_assignments
+            .insert(unit_ids[0], worker_ids[..2].to_vec());
+        scheduler
+            .units_assignments
+            .insert(unit_ids[1], worker_ids[..1].to_vec());
+
+        let (units_by_replicas, missing_bytes) = scheduler.replication_stats();
+        let dataset = "s3://dataset".to_string();
+        let other = "s3://other".to_string();
+        let expected = [
+            ((dataset.clone(), 2), 1),
+            ((dataset.clone(), 1), 1),
+            ((dataset.clone(), 0), 1),
+            ((other.clone(), 0), 1),
+        ];
+        assert_eq!(units_by_replicas, expected.into_iter().collect());
+        // One missing replica of the 200 bytes unit and two of the 50 bytes one
+        let expected = [(dataset, 300), (other, 20)];
+        assert_eq!(missing_bytes, expected.into_iter().collect());
+
+        assert!(scheduler.jailed_workers_by_reason().is_empty());
+        scheduler
+            .get_worker(&worker_ids[0])
+            .jail(JailReason::Inactive);
+        scheduler
+            .get_worker(&worker_ids[1])
+            .jail(JailReason::Inactive);
+        scheduler
+            .get_worker(&worker_ids[2])
+            .jail(JailReason::Manual("test".to_string()));
+        let expected = [("inactive", 2), ("manual", 1)];
+        assert_eq!(
+            scheduler.jailed_workers_by_reason(),
+            expected.into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn test_spread_colocated_replicas() {
+        let _config = Config::set_for_test(|config| {
+            config.replication_factor = 2;
+            config.spread_replicas_across_operators = true;
+        });
+        let unit = test_unit(0, 100);
+        let unit_id = unit.id();
+        let mut scheduler = test_scheduler(0, vec![unit]);
+        let operators = [
+            Address::repeat_byte(1),
+            Address::repeat_byte(1),
+            Address::repeat_byte(2),
+        ];
+        let worker_ids: Vec<PeerId> = operators
+            .into_iter()
+            .map(|address| {
+                let worker = WorkerState::new(PeerId::random(), address);
+                let worker_id = worker.peer_id;
+                scheduler.worker_states.insert(worker_id, worker);
+                worker_id
+            })
+            .collect();
+        for worker_id in &worker_ids[..2] {
+            assert!(scheduler
+                .get_worker(worker_id)
+                .try_assign_unit(unit_id, 100));
+        }
+        scheduler
+            .units_assignments
+            .insert(unit_id, worker_ids[..2].to_vec());
+        assert_eq!(scheduler.colocated_units(), HashSet::from([unit_id]));
+
+        scheduler.spread_colocated_replicas();
+        assert_eq!(scheduler.num_replicas(&unit_id), 1);
+        scheduler.assign_missing_replicas(None);
+
+        // The second replica went to the other operator
+        let holders = &scheduler.units_assignments[&unit_id];
+        assert_eq!(holders.len(), 2);
+        assert!(holders.contains(&worker_ids[2]));
+        assert!(scheduler.colocated_units().is_empty());
+        let assigned = |id: &PeerId| {
+            scheduler.worker_states[id]
+                .assigned_units
+                .contains(&unit_id)
+        };
+        assert_eq!(worker_ids.iter().filter(|id| assigned(id)).count(), 2);
+    }
+
+    #[test]
+    fn test_no_budget() {
+        let _config = Config::set_for_test(|config| {
+            config.replication_factor = 2;
+            config.spread_replicas_across_operators = false;
+        });
+        let units = (0..4).map(|i| test_unit(i, 100)).collect();
+        let mut scheduler = test_scheduler(3, units);
+
+        scheduler.assign_missing_replicas(None);
+        assert_eq!(num_assigned(&scheduler), 8);
+        assert!(scheduler.pending_units.is_empty());
+    }
+
+    #[test]
+    fn test_mixed_replicas() {
+        let _config = Config::set_for_test(|config| {
+            config.replication_factor = 1;
+            config.spread_replicas_across_operators = false;
+            config.mixed_units_ratio = 1.0;
+        });
+        let unit = test_unit(0, 100);
+        let unit_id = unit.id();
+        let mut scheduler = test_scheduler(1, vec![unit]);
+        let worker_id = *scheduler.worker_states.keys().next().unwrap();
+        let worker = scheduler.get_worker(&worker_id);
+        assert!(worker.try_assign_unit(unit_id, 100));
+        worker.stored_ranges =
+            HashMap::from([("s3://dataset".to_string(), vec![Range::new(0, 999)].into())]);
+        scheduler.units_assignments.insert(unit_id, vec![worker_id]);
+
+        scheduler.mix_random_units();
+        assert_eq!(scheduler.num_replicas(&unit_id), 0);
+
+        // The worker isn't given the replica back while it still stores the data
+        scheduler.prune_mixed_replicas();
+        scheduler.assign_units_to_holders(
+            &[unit_id],
+            &mut AssignmentBudget::default(),
+            &mut HashSet::new(),
+        );
+        assert_eq!(scheduler.num_replicas(&unit_id), 0);
+
+        scheduler.get_worker(&worker_id).stored_ranges.clear();
+        scheduler.prune_mixed_replicas();
+        assert!(scheduler.mixed_replicas.is_empty());
     }
 }
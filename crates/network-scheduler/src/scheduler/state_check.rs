@@ -0,0 +1,363 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{Display, Formatter};
+
+use serde::Serialize;
+
+use subsquid_network_transport::PeerId;
+
+use crate::cli::Config;
+use crate::scheduling_unit::UnitId;
+
+use super::Scheduler;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WorkerSummary {
+    pub peer_id: PeerId,
+    pub jailed: bool,
+    pub assigned_units: usize,
+    pub assigned_bytes: u64,
+    pub stored_bytes: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DatasetSummary {
+    pub units: usize,
+    pub bytes: u64,
+    /// Number of replicas -> number of units
+    pub replication: BTreeMap<usize, usize>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JailedWorker {
+    pub peer_id: PeerId,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StateSummary {
+    pub last_schedule_epoch: u32,
+    pub workers: Vec<WorkerSummary>,
+    pub datasets: BTreeMap<String, DatasetSummary>,
+    pub jailed_workers: Vec<JailedWorker>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Inconsistency {
+    /// Unit is assigned to a worker which is not in the state
+    UnknownWorker { unit_id: UnitId, worker_id: PeerId },
+    /// Assignment entry exists for a unit which is not known
+    UnknownUnit { unit_id: UnitId },
+    /// Known unit has no assignment entry
+    MissingAssignmentEntry { unit_id: UnitId },
+    /// Worker's assigned units contain a unit which is not known
+    WorkerUnknownUnit { worker_id: PeerId, unit_id: UnitId },
+    /// Unit lists the worker, but the worker doesn't have the unit assigned
+    MissingReplica { unit_id: UnitId, worker_id: PeerId },
+    /// Worker has the unit assigned, but the unit doesn't list the worker
+    ExtraReplica { unit_id: UnitId, worker_id: PeerId },
+    /// Unit lists the same worker more than once
+    DuplicateReplica { unit_id: UnitId, worker_id: PeerId },
+    /// Worker's assigned bytes don't match the sizes of its assigned units
+    AssignedBytes {
+        worker_id: PeerId,
+        recorded: u64,
+        actual: u64,
+    },
+    /// Jailed worker still has units assigned
+    JailedWithUnits { worker_id: PeerId, units: usize },
+    /// Unit is pinned or forbidden, but not known
+    UnknownOverride { unit_id: UnitId },
+}
+
+impl Display for Inconsistency {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownWorker { unit_id, worker_id } => {
+                write!(f, "Unit {unit_id} assigned to unknown worker {worker_id}")
+            }
+            Self::UnknownUnit { unit_id } => write!(f, "Assignment of unknown unit {unit_id}"),
+            Self::MissingAssignmentEntry { unit_id } => {
+                write!(f, "Unit {unit_id} has no assignment entry")
+            }
+            Self::WorkerUnknownUnit { worker_id, unit_id } => {
+                write!(f, "Worker {worker_id} has unknown unit {unit_id} assigned")
+            }
+            Self::MissingReplica { unit_id, worker_id } => write!(
+                f,
+                "Unit {unit_id} lists worker {worker_id} which doesn't have it assigned"
+            ),
+            Self::ExtraReplica { unit_id, worker_id } => write!(
+                f,
+                "Worker {worker_id} has unit {unit_id} assigned which doesn't list it"
+            ),
+            Self::DuplicateReplica { unit_id, worker_id } => {
+                write!(f, "Unit {unit_id} lists worker {worker_id} more than once")
+            }
+            Self::AssignedBytes {
+                worker_id,
+                recorded,
+                actual,
+            } => write!(
+                f,
+                "Worker {worker_id} has {recorded} assigned bytes recorded, but {actual} assigned"
+            ),
+            Self::JailedWithUnits { worker_id, units } => {
+                write!(f, "Jailed worker {worker_id} has {units} units assigned")
+            }
+            Self::UnknownOverride { unit_id } => {
+                write!(f, "Unknown unit {unit_id} is pinned or forbidden")
+            }
+        }
+    }
+}
+
+impl Scheduler {
+    pub fn summary(&self) -> StateSummary {
+        let mut workers: Vec<WorkerSummary> = self
+            .worker_states
+            .values()
+            .map(|w| WorkerSummary {
+                peer_id: w.peer_id,
+                jailed: w.jailed,
+                assigned_units: w.assigned_units.len(),
+                assigned_bytes: w.assigned_bytes,
+                stored_bytes: w.stored_bytes,
+            })
+            .collect();
+        workers.sort_by_key(|w| w.peer_id.to_string());
+
+        let mut datasets: BTreeMap<String, DatasetSummary> = BTreeMap::new();
+        for (unit_id, unit) in self.known_units.iter() {
+            let dataset = datasets.entry(unit.dataset_url().to_string()).or_default();
+            dataset.units += 1;
+            dataset.bytes += unit.size_bytes();
+            *dataset
+                .replication
+                .entry(self.num_replicas(unit_id))
+                .or_default() += 1;
+        }
+
+        let mut jailed_workers: Vec<JailedWorker> = self
+            .worker_states
+            .values()
+            .filter(|w| w.jailed)
+            .map(|w| JailedWorker {
+                peer_id: w.peer_id,
+                reason: w.jail_reason_str(),
+            })
+            .collect();
+        jailed_workers.sort_by_key(|w| w.peer_id.to_string());
+
+        StateSummary {
+            last_schedule_epoch: self.last_schedule_epoch,
+            workers,
+            datasets,
+            jailed_workers,
+        }
+    }
+
+    pub fn check_consistency(&self) -> Vec<Inconsistency> {
+        let mut issues = Vec::new();
+
+        for (unit_id, worker_ids) in self.units_assignments.iter() {
+            if !self.known_units.contains_key(unit_id) {
+                issues.push(Inconsistency::UnknownUnit { unit_id: *unit_id });
+                continue;
+            }
+            let mut seen = HashSet::new();
+            for worker_id in worker_ids {
+                if !seen.insert(worker_id) {
+                    issues.push(Inconsistency::DuplicateReplica {
+                        unit_id: *unit_id,
+                        worker_id: *worker_id,
+                    });
+                    continue;
+                }
+                match self.worker_states.get(worker_id) {
+                    None => issues.push(Inconsistency::UnknownWorker {
+                        unit_id: *unit_id,
+                        worker_id: *worker_id,
+                    }),
+                    Some(worker) if !worker.assigned_units.contains(unit_id) => {
+                        issues.push(Inconsistency::MissingReplica {
+                            unit_id: *unit_id,
+                            worker_id: *worker_id,
+                        })
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+
+        for unit_id in self.known_units.keys() {
+            if !self.units_assignments.contains_key(unit_id) {
+                issues.push(Inconsistency::MissingAssignmentEntry { unit_id: *unit_id });
+            }
+        }
+
+        for (worker_id, worker) in self.worker_states.iter() {
+            let mut actual_bytes = 0;
+            for unit_id in worker.assigned_units.iter() {
+                let unit = match self.known_units.get(unit_id) {
+                    Some(unit) => unit,
+                    None => {
+                        issues.push(Inconsistency::WorkerUnknownUnit {
+                            worker_id: *worker_id,
+                            unit_id: *unit_id,
+                        });
+                        continue;
+                    }
+                };
+                actual_bytes += unit.size_bytes();
+                let listed = self
+                    .units_assignments
+                    .get(unit_id)
+                    .map(|ids| ids.contains(worker_id))
+                    .unwrap_or_default();
+                if !listed {
+                    issues.push(Inconsistency::ExtraReplica {
+                        unit_id: *unit_id,
+                        worker_id: *worker_id,
+                    });
+                }
+            }
+            if worker.assigned_bytes != actual_bytes {
+                issues.push(Inconsistency::AssignedBytes {
+                    worker_id: *worker_id,
+                    recorded: worker.assigned_bytes,
+                    actual: actual_bytes,
+                });
+            }
+            if worker.jailed && !worker.assigned_units.is_empty() {
+                issues.push(Inconsistency::JailedWithUnits {
+                    worker_id: *worker_id,
+                    units: worker.assigned_units.len(),
+                });
+            }
+        }
+
+        let overridden: HashSet<&UnitId> = self
+            .unit_overrides
+            .pinned
+            .keys()
+            .chain(self.unit_overrides.forbidden.keys())
+            .collect();
+        for unit_id in overridden {
+            if !self.known_units.contains_key(unit_id) {
+                issues.push(Inconsistency::UnknownOverride { unit_id: *unit_id });
+            }
+        }
+
+        issues
+    }
+
+    /// Fix the inconsistencies found by `check_consistency` and return them.
+    /// A replica is kept only if both the unit and the worker agree on it, so that
+    /// the capacity of workers is never exceeded. Missing replicas will be assigned
+    /// by the next scheduling round.
+    pub fn repair(&mut self) -> Vec<Inconsistency> {
+        let issues = self.check_consistency();
+        if issues.is_empty() {
+            return issues;
+        }
+
+        let known_units = &self.known_units;
+        let worker_states = &self.worker_states;
+        self.units_assignments
+            .retain(|unit_id, _| known_units.contains_key(unit_id));
+        for (unit_id, worker_ids) in self.units_assignments.iter_mut() {
+            let mut seen = HashSet::new();
+            worker_ids.retain(|worker_id| {
+                seen.insert(*worker_id)
+                    && worker_states
+                        .get(worker_id)
+                        .map(|w| !w.jailed && w.assigned_units.contains(unit_id))
+                        .unwrap_or_default()
+            });
+        }
+        for unit_id in self.known_units.keys() {
+            self.units_assignments
+                .entry(*unit_id)
+                .or_insert_with(|| Vec::with_capacity(Config::get().replication_factor));
+        }
+
+        let units_assignments = &self.units_assignments;
+        for (worker_id, worker) in self.worker_states.iter_mut() {
+            let num_units = worker.assigned_units.len();
+            worker.assigned_units.retain(|unit_id| {
+                units_assignments
+                    .get(unit_id)
+                    .map(|ids| ids.contains(worker_id))
+                    .unwrap_or_default()
+            });
+            let actual_bytes = worker
+                .assigned_units
+                .iter()
+                .map(|unit_id| known_units[unit_id].size_bytes())
+                .sum();
+            if worker.assigned_units.len() != num_units || worker.assigned_bytes != actual_bytes {
+                worker.assigned_bytes = actual_bytes;
+                worker.assignment_changed();
+            }
+        }
+
+        let overrides = &mut self.unit_overrides;
+        overrides
+            .pinned
+            .retain(|unit_id, _| known_units.contains_key(unit_id));
+        overrides
+            .forbidden
+            .retain(|unit_id, _| known_units.contains_key(unit_id));
+
+        issues
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::data_chunk::DataChunk;
+    use crate::scheduling_unit::SchedulingUnit;
+    use crate::worker_state::WorkerState;
+
+    #[test]
+    fn test_repair() {
+        let chunk =
+            DataChunk::new("dataset", "0000000000/0000000000-0000000999-00000000", 100).unwrap();
+        let unit = SchedulingUnit::from_slice(&[chunk]);
+        let unit_id = unit.id();
+        let worker_id = PeerId::random();
+        let unknown_id = PeerId::random();
+        let mut worker = WorkerState::new(worker_id, Default::default());
+        worker.assigned_units.insert(unit_id);
+        worker.assigned_bytes = 50;
+
+        let mut scheduler = Scheduler {
+            known_units: HashMap::from([(unit_id, unit)]),
+            units_assignments: HashMap::from([(unit_id, vec![worker_id, worker_id, unknown_id])]),
+            worker_states: HashMap::from([(worker_id, worker)]),
+            ..Default::default()
+        };
+
+        let issues = scheduler.check_consistency();
+        assert_eq!(issues.len(), 3);
+        assert!(issues.contains(&Inconsistency::DuplicateReplica { unit_id, worker_id }));
+        assert!(issues.contains(&Inconsistency::UnknownWorker {
+            unit_id,
+            worker_id: unknown_id
+        }));
+        assert!(issues.contains(&Inconsistency::AssignedBytes {
+            worker_id,
+            recorded: 50,
+            actual: 100
+        }));
+
+        assert_eq!(scheduler.repair(), issues);
+        assert!(scheduler.check_consistency().is_empty());
+        assert_eq!(scheduler.units_assignments[&unit_id], vec![worker_id]);
+        assert_eq!(scheduler.worker_states[&worker_id].assigned_bytes, 100);
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::{BTreeMap, VecDeque};
 use std::fmt::{Display, Formatter};
 
 use nonempty::NonEmpty;
@@ -10,6 +11,13 @@ use crate::data_chunk::{ChunkId, DataChunk};
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SchedulingUnit {
     pub chunks: NonEmpty<DataChunk>,
+    /// Number of times the chunks of the unit starting at the same block were rewritten
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub generation: u32,
+}
+
+fn is_zero(x: &u32) -> bool {
+    *x == 0
 }
 
 pub type UnitId = ChunkId;
@@ -17,7 +25,10 @@ pub type UnitId = ChunkId;
 impl SchedulingUnit {
     pub fn from_slice(chunks: &[DataChunk]) -> Self {
         let chunks = NonEmpty::from_slice(chunks).expect("Empty slice");
-        Self { chunks }
+        Self {
+            chunks,
+            generation: 0,
+        }
     }
 
     pub fn num_chunks(&self) -> usize {
@@ -30,8 +41,12 @@ impl SchedulingUnit {
 
     pub fn id(&self) -> UnitId {
         // ID of the unit is just ID of the first chunk. This way, when an incomplete unit is filled
-        // later, it will still have the same ID.
-        self.chunks.first().id()
+        // later, it will still have the same ID. Rewritten units get a new ID for each generation.
+        let id = self.chunks.first().id();
+        match self.generation {
+            0 => id,
+            generation => id.with_generation(generation),
+        }
     }
 
     pub fn dataset_url(&self) -> &str {
@@ -41,6 +56,10 @@ impl SchedulingUnit {
     pub fn begin(&self) -> u32 {
         self.chunks.first().block_range.begin
     }
+
+    pub fn end(&self) -> u32 {
+        self.chunks.last().block_range.end
+    }
 }
 
 impl IntoIterator for SchedulingUnit {
@@ -65,35 +84,207 @@ impl Display for SchedulingUnit {
     }
 }
 
+/// Limits of a single scheduling unit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UnitLimits {
+    pub max_chunks: usize,
+    /// Target size of a unit. A single chunk bigger than that makes a unit on its own.
+    pub max_bytes: Option<u64>,
+}
+
+impl UnitLimits {
+    fn fits(&self, num_chunks: usize, size_bytes: u64, chunk: &DataChunk) -> bool {
+        if num_chunks == 0 {
+            return true;
+        }
+        num_chunks < self.max_chunks
+            && !matches!(self.max_bytes, Some(max_bytes) if size_bytes + chunk.size_bytes > max_bytes)
+    }
+}
+
+/// Group consecutive chunks into units, filling each unit up to the limits before starting
+/// the next one. The result only depends on the chunks, so bundling a dataset again from
+/// the beginning gives units with the same IDs.
+pub fn bundle(
+    chunks: impl IntoIterator<Item = DataChunk>,
+    limits: UnitLimits,
+) -> Vec<SchedulingUnit> {
+    let mut units = Vec::new();
+    let mut current: Vec<DataChunk> = Vec::new();
+    let mut current_size = 0;
+    for chunk in chunks {
+        if !limits.fits(current.len(), current_size, &chunk) {
+            units.push(SchedulingUnit::from_slice(&current));
+            current.clear();
+            current_size = 0;
+        }
+        current_size += chunk.size_bytes;
+        current.push(chunk);
+    }
+    if !current.is_empty() {
+        units.push(SchedulingUnit::from_slice(&current));
+    }
+    units
+}
+
+/// Chunk listed from a dataset bucket
+#[derive(Debug, Clone)]
+pub struct IncomingChunk {
+    pub chunk: DataChunk,
+    /// Key of the last object of the chunk
+    pub last_key: String,
+}
+
+/// Changes of the chunks listed from a dataset bucket
+#[derive(Debug, Clone)]
+pub enum ChunksUpdate {
+    /// New chunks. If they overlap recently listed chunks, those are replaced.
+    Listed(NonEmpty<IncomingChunk>),
+    /// Recently listed chunks starting from the block were deleted
+    Deleted { from_block: u32 },
+}
+
+/// Position in a dataset bucket. All chunks up to it are bundled into units which
+/// won't change anymore, so listing can be resumed after it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListingCursor {
+    pub last_key: String,
+    pub last_block: u32,
+}
+
+/// Where listing of a dataset is resumed after a restart
+#[derive(Debug, Clone, Default)]
+pub struct ListingResume {
+    pub cursor: Option<ListingCursor>,
+    /// Generations of the rewritten units after the cursor, by their first block
+    pub generations: BTreeMap<u32, u32>,
+}
+
+/// Changes of scheduling units produced by the chunks bundler
+#[derive(Debug, Clone)]
+pub enum UnitEvent {
+    /// New unit or new chunks added to an existing unit
+    Updated(SchedulingUnit),
+    /// Chunks of the unit starting from `rewritten_from` block were rewritten.
+    /// The unit is replaced with a new one, only the rewritten chunks need to be downloaded again.
+    Invalidated {
+        unit_id: UnitId,
+        rewritten_from: u32,
+    },
+    /// Units of the dataset are final up to the cursor
+    Checkpoint {
+        dataset_url: String,
+        cursor: ListingCursor,
+    },
+}
+
 pub async fn bundle_chunks(
-    mut chunk_receiver: Receiver<NonEmpty<DataChunk>>,
-    unit_sender: Sender<SchedulingUnit>,
-    unit_size: usize,
+    mut chunk_receiver: Receiver<ChunksUpdate>,
+    unit_sender: Sender<UnitEvent>,
+    limits: UnitLimits,
+    max_rewrite_depth: usize,
+    mut generations: BTreeMap<u32, u32>,
     cancel_token: CancellationToken,
 ) {
     log::info!("Starting chunks bundler");
-    let mut incomplete_unit: Option<SchedulingUnit> = None;
+    // Most recently sent units, covering at least `max_rewrite_depth` chunks.
+    // The last one can still be extended.
+    let mut recent_units: VecDeque<SchedulingUnit> = VecDeque::new();
+    // Last block -> last object key of the chunks in recent units
+    let mut chunk_keys: BTreeMap<u32, String> = BTreeMap::new();
     loop {
-        let mut chunks = tokio::select! {
-            Some(chunks) = chunk_receiver.recv() => chunks,
+        let update = tokio::select! {
+            update = chunk_receiver.recv() => match update {
+                Some(update) => update,
+                None => break,
+            },
             _ = cancel_token.cancelled() => break,
-            else => break,
         };
+        let (begin, chunks) = match update {
+            ChunksUpdate::Listed(chunks) => (chunks.first().chunk.block_range.begin, chunks.into()),
+            ChunksUpdate::Deleted { from_block } => (from_block, Vec::new()),
+        };
+
+        // If chunks were rewritten or deleted, all units containing them are invalidated.
+        // Chunks preceding the rewritten ones are bundled again together with the new chunks.
+        // The new units get the next generation, so their IDs differ from the invalidated ones.
+        let mut prev_chunks = Vec::new();
+        while let Some(unit) = recent_units.back() {
+            if unit.chunks.last().block_range.end < begin {
+                break;
+            }
+            let unit = recent_units.pop_back().expect("recent units not empty");
+            log::info!("Chunks of scheduling unit {unit} rewritten");
+            let invalidated = UnitEvent::Invalidated {
+                unit_id: unit.id(),
+                rewritten_from: begin,
+            };
+            if unit_sender.send(invalidated).await.is_err() {
+                log::info!("Scheduling unit receiver dropped");
+                return;
+            }
+            let generation = generations.entry(unit.begin()).or_default();
+            *generation = (*generation).max(unit.generation + 1);
+            prev_chunks = unit
+                .into_iter()
+                .filter(|chunk| chunk.block_range.end < begin)
+                .collect();
+        }
 
-        // Put all the remaining chunks from last round before the new ones.
-        // If there was an incomplete unit, it will be filled and sent again.
-        if let Some(unit) = incomplete_unit.take() {
-            let new_chunks = std::mem::replace(&mut chunks, unit.chunks);
-            chunks.append(&mut new_chunks.into())
+        // Put the chunks of the last unit before the new ones. Whether it's complete depends
+        // on the size of the next chunk, so it's bundled again and sent if extended.
+        let last_unit = recent_units.pop_back();
+        if let Some(unit) = &last_unit {
+            prev_chunks.splice(0..0, unit.chunks.iter().cloned());
+        }
+        chunk_keys.split_off(&begin);
+        for IncomingChunk { chunk, last_key } in chunks {
+            chunk_keys.insert(chunk.block_range.end, last_key);
+            prev_chunks.push(chunk);
         }
 
-        let chunks: Vec<DataChunk> = chunks.into();
-        for chunks in chunks.chunks(unit_size) {
-            let unit = SchedulingUnit::from_slice(chunks);
-            if unit.num_chunks() < unit_size {
-                incomplete_unit = Some(unit.clone())
+        for mut unit in bundle(prev_chunks, limits) {
+            unit.generation = generations.get(&unit.begin()).copied().unwrap_or_default();
+            recent_units.push_back(unit.clone());
+            if last_unit.as_ref() == Some(&unit) {
+                continue;
             }
-            if unit_sender.send(unit).await.is_err() {
+            if unit_sender.send(UnitEvent::Updated(unit)).await.is_err() {
+                log::info!("Scheduling unit receiver dropped");
+                return;
+            }
+        }
+
+        let mut final_unit = None;
+        while recent_units
+            .iter()
+            .skip(1)
+            .map(SchedulingUnit::num_chunks)
+            .sum::<usize>()
+            >= max_rewrite_depth
+            && recent_units.len() > 1
+        {
+            final_unit = recent_units.pop_front();
+        }
+
+        // Chunks of the units which are out of the rewrite window are never bundled again,
+        // so listing can be resumed after them
+        if let Some(unit) = final_unit {
+            generations = generations.split_off(&(unit.end() + 1));
+            let remaining_keys = chunk_keys.split_off(&(unit.end() + 1));
+            let last_key =
+                match std::mem::replace(&mut chunk_keys, remaining_keys).remove(&unit.end()) {
+                    Some(key) => key,
+                    None => continue,
+                };
+            let checkpoint = UnitEvent::Checkpoint {
+                dataset_url: unit.dataset_url().to_string(),
+                cursor: ListingCursor {
+                    last_key,
+                    last_block: unit.end(),
+                },
+            };
+            if unit_sender.send(checkpoint).await.is_err() {
                 log::info!("Scheduling unit receiver dropped");
                 return;
             }
@@ -101,3 +292,216 @@ pub async fn bundle_chunks(
     }
     log::info!("Stopping chunks bundler");
 }
+
+#[cfg(test)]
+mod tests {
+    use subsquid_messages::Range;
+
+    use super::*;
+
+    fn chunks(sizes: &[u64]) -> Vec<DataChunk> {
+        sizes
+            .iter()
+            .enumerate()
+            .map(|(i, size)| DataChunk {
+                dataset_url: "s3://squidnet".to_string(),
+                block_range: Range::new(i as u32 * 100, i as u32 * 100 + 99),
+                size_bytes: *size,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_bundle() {
+        let limits = UnitLimits {
+            max_chunks: 3,
+            max_bytes: Some(100),
+        };
+        let units = bundle(chunks(&[10, 20, 30, 40, 150, 50, 50, 1, 1, 1, 1]), limits);
+        let sizes: Vec<(usize, u64)> = units
+            .iter()
+            .map(|unit| (unit.num_chunks(), unit.size_bytes()))
+            .collect();
+        assert_eq!(
+            sizes,
+            [(3, 60), (1, 40), (1, 150), (2, 100), (3, 3), (1, 1)]
+        );
+
+        // Bundling a prefix gives the same IDs, the last unit can be extended later
+        let prefix_units = bundle(chunks(&[10, 20, 30, 40, 150, 50]), limits);
+        assert_eq!(prefix_units[..3], units[..3]);
+        assert_eq!(prefix_units[3].id(), units[3].id());
+
+        let units = bundle(
+            chunks(&[10, 20, 30, 40, 150]),
+            UnitLimits {
+                max_chunks: 2,
+                max_bytes: None,
+            },
+        );
+        assert_eq!(
+            units
+                .iter()
+                .map(SchedulingUnit::num_chunks)
+                .collect::<Vec<_>>(),
+            [2, 2, 1]
+        );
+    }
+
+    fn incoming(chunks: &[DataChunk]) -> ChunksUpdate {
+        let chunks = chunks
+            .iter()
+            .map(|chunk| IncomingChunk {
+                chunk: chunk.clone(),
+                last_key: format!("{:010}/last", chunk.block_range.end),
+            })
+            .collect();
+        ChunksUpdate::Listed(NonEmpty::from_vec(chunks).expect("no chunks"))
+    }
+
+    /// Run the bundler over the batches of chunks and collect all the events
+    async fn bundle_batches(
+        batches: Vec<ChunksUpdate>,
+        generations: BTreeMap<u32, u32>,
+    ) -> Vec<UnitEvent> {
+        let limits = UnitLimits {
+            max_chunks: 2,
+            max_bytes: None,
+        };
+        let (chunk_sender, chunk_receiver) = tokio::sync::mpsc::channel(batches.len());
+        let (unit_sender, mut unit_receiver) = tokio::sync::mpsc::channel(100);
+        for batch in batches {
+            chunk_sender.send(batch).await.unwrap();
+        }
+        drop(chunk_sender);
+        bundle_chunks(
+            chunk_receiver,
+            unit_sender,
+            limits,
+            4,
+            generations,
+            CancellationToken::new(),
+        )
+        .await;
+        let mut events = Vec::new();
+        while let Some(event) = unit_receiver.recv().await {
+            events.push(event);
+        }
+        events
+    }
+
+    fn unit_ids(events: &[UnitEvent]) -> Vec<UnitId> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                UnitEvent::Updated(unit) => Some(unit.id()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn last_checkpoint(events: &[UnitEvent]) -> Option<ListingCursor> {
+        events.iter().rev().find_map(|event| match event {
+            UnitEvent::Checkpoint { cursor, .. } => Some(cursor.clone()),
+            _ => None,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_resume_from_checkpoint() {
+        let chunks = chunks(&[1; 10]);
+        let events = bundle_batches(
+            vec![incoming(&chunks[..5]), incoming(&chunks[5..])],
+            BTreeMap::new(),
+        )
+        .await;
+        let cursor = last_checkpoint(&events).expect("no checkpoint");
+        assert_eq!(cursor.last_block, 599);
+        assert_eq!(cursor.last_key, "0000000599/last");
+
+        // Listing resumed after the cursor gives the same units
+        let resumed_chunks: Vec<DataChunk> = chunks
+            .iter()
+            .filter(|chunk| chunk.block_range.begin > cursor.last_block)
+            .cloned()
+            .collect();
+        let resumed_events = bundle_batches(vec![incoming(&resumed_chunks)], BTreeMap::new()).await;
+        let mut expected = unit_ids(&events);
+        expected.dedup();
+        let num_final = expected.len() - unit_ids(&resumed_events).len();
+        assert_eq!(unit_ids(&resumed_events), expected[num_final..]);
+    }
+
+    #[tokio::test]
+    async fn test_no_checkpoint_on_rewrite() {
+        let chunks = chunks(&[1; 6]);
+        let mut rewritten = chunks[5].clone();
+        rewritten.size_bytes = 2;
+        let events = bundle_batches(
+            vec![incoming(&chunks), incoming(&[rewritten.clone()])],
+            BTreeMap::new(),
+        )
+        .await;
+
+        let invalidated_at = events
+            .iter()
+            .position(|event| matches!(event, UnitEvent::Invalidated { .. }))
+            .expect("no invalidated unit");
+        assert_eq!(
+            last_checkpoint(&events[..invalidated_at])
+                .unwrap()
+                .last_block,
+            199
+        );
+        assert!(last_checkpoint(&events[invalidated_at..]).is_none());
+
+        // The rewritten unit gets a new ID
+        let original = SchedulingUnit::from_slice(&chunks[4..]);
+        let replacement = SchedulingUnit {
+            chunks: NonEmpty::from_vec(vec![chunks[4].clone(), rewritten]).unwrap(),
+            generation: 1,
+        };
+        assert!(matches!(
+            events[invalidated_at],
+            UnitEvent::Invalidated { unit_id, rewritten_from: 500 } if unit_id == original.id()
+        ));
+        assert_ne!(replacement.id(), original.id());
+        assert_eq!(unit_ids(&events[invalidated_at..]), [replacement.id()]);
+
+        // Listing resumed with the known generations gives the same IDs
+        let resumed_chunks: Vec<DataChunk> = chunks[2..4]
+            .iter()
+            .chain(&replacement.chunks)
+            .cloned()
+            .collect();
+        let generations = BTreeMap::from([(400, 1)]);
+        let resumed_events = bundle_batches(vec![incoming(&resumed_chunks)], generations).await;
+        assert_eq!(unit_ids(&resumed_events).last(), Some(&replacement.id()));
+    }
+
+    #[tokio::test]
+    async fn test_deleted_chunks() {
+        let chunks = chunks(&[1; 6]);
+        let events = bundle_batches(
+            vec![incoming(&chunks), ChunksUpdate::Deleted { from_block: 500 }],
+            BTreeMap::new(),
+        )
+        .await;
+
+        // The unit with the deleted chunk is replaced with the remaining ones
+        let original = SchedulingUnit::from_slice(&chunks[4..]);
+        let replacement = SchedulingUnit {
+            chunks: NonEmpty::new(chunks[4].clone()),
+            generation: 1,
+        };
+        let invalidated_at = events
+            .iter()
+            .position(|event| matches!(event, UnitEvent::Invalidated { .. }))
+            .expect("no invalidated unit");
+        assert!(matches!(
+            events[invalidated_at],
+            UnitEvent::Invalidated { unit_id, rewritten_from: 500 } if unit_id == original.id()
+        ));
+        assert_eq!(unit_ids(&events[invalidated_at..]), [replacement.id()]);
+    }
+}
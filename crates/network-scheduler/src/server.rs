@@ -12,24 +12,45 @@ use subsquid_messages::signatures::{msg_hash, SignedMessage};
 use subsquid_messages::{Envelope, Ping, Pong, ProstMsg};
 use subsquid_network_transport::task_manager::{CancellationToken, TaskManager};
 use subsquid_network_transport::transport::P2PTransportHandle;
-use subsquid_network_transport::{MsgContent as MsgContentT, PeerId};
+use subsquid_network_transport::{Keypair, MsgContent as MsgContentT, PeerId};
 
+use crate::audit_log::AuditLog;
 use crate::cli::Config;
 use crate::metrics::{MetricsEvent, MetricsWriter};
 use crate::metrics_server;
+use crate::prometheus_metrics;
 use crate::scheduler::Scheduler;
-use crate::scheduling_unit::SchedulingUnit;
+use crate::scheduling_unit::UnitEvent;
 use crate::storage::S3Storage;
+use crate::ASSIGNMENT_TOPIC;
 
 type MsgContent = Box<[u8]>;
 type Message = subsquid_network_transport::Message<Box<[u8]>>;
 
 const WORKER_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+const AUDIT_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Re-read the config file and apply the changes which need more than a new value:
+/// start or stop listing of dataset buckets and remove units of the dropped datasets.
+pub async fn reload_config(
+    scheduler: &RwLock<Scheduler>,
+    storage_client: &S3Storage,
+) -> anyhow::Result<()> {
+    Config::reload().await?;
+    let mut scheduler = scheduler.write().await;
+    scheduler.clear_deprecated_units();
+    storage_client
+        .update_datasets(&scheduler.listing_resume())
+        .await;
+    storage_client.save_scheduler(scheduler).await;
+    Ok(())
+}
 
 pub struct Server {
     incoming_messages: Receiver<Message>,
-    incoming_units: Receiver<SchedulingUnit>,
+    incoming_units: Receiver<UnitEvent>,
     transport_handle: P2PTransportHandle<MsgContent>,
+    keypair: Keypair,
     scheduler: Arc<RwLock<Scheduler>>,
     metrics_writer: Arc<RwLock<MetricsWriter>>,
     task_manager: TaskManager,
@@ -38,8 +59,9 @@ pub struct Server {
 impl Server {
     pub fn new(
         incoming_messages: Receiver<Message>,
-        incoming_units: Receiver<SchedulingUnit>,
+        incoming_units: Receiver<UnitEvent>,
         transport_handle: P2PTransportHandle<MsgContent>,
+        keypair: Keypair,
         scheduler: Scheduler,
         metrics_writer: MetricsWriter,
     ) -> Self {
@@ -49,6 +71,7 @@ impl Server {
             incoming_messages,
             incoming_units,
             transport_handle,
+            keypair,
             scheduler,
             metrics_writer,
             task_manager: Default::default(),
@@ -61,6 +84,8 @@ impl Server {
         storage_client: S3Storage,
         metrics_listen_addr: SocketAddr,
         metrics_registry: Registry,
+        admin_token: Option<String>,
+        audit_log: Option<AuditLog>,
     ) -> anyhow::Result<()> {
         log::info!("Starting scheduler server");
 
@@ -68,20 +93,42 @@ impl Server {
         let workers = contract_client.active_workers().await?;
         self.scheduler.write().await.update_workers(workers);
 
+        // Only the leader schedules and saves state, the others follow
+        if !storage_client.renew_lease().await? {
+            log::info!("Another instance is the leader. Starting as a follower");
+        }
+        prometheus_metrics::leader(storage_client.is_leader());
+        self.spawn_leader_election_task(storage_client.clone());
+
         self.spawn_scheduling_task(contract_client, storage_client.clone())
             .await?;
+        self.spawn_incremental_scheduling_task(storage_client.clone());
         self.spawn_worker_monitoring_task();
-        self.spawn_metrics_server_task(metrics_listen_addr, metrics_registry);
+        self.spawn_audit_log_task(storage_client.clone(), audit_log.clone());
+        self.spawn_assignment_snapshot_task(storage_client.clone());
+        self.spawn_metrics_server_task(
+            storage_client.clone(),
+            metrics_listen_addr,
+            metrics_registry,
+            admin_token,
+            audit_log,
+        );
         self.spawn_jail_inactive_workers_task(storage_client.clone());
         self.spawn_jail_stale_workers_task(storage_client.clone());
-        self.spawn_jail_unreachable_workers_task(storage_client);
+        self.spawn_jail_unreachable_workers_task(storage_client.clone());
 
         let mut sigint = signal(SignalKind::interrupt())?;
         let mut sigterm = signal(SignalKind::terminate())?;
+        let mut sighup = signal(SignalKind::hangup())?;
         loop {
             tokio::select! {
-                Some(msg) = self.incoming_messages.recv() => self.handle_message(msg).await,
-                Some(unit) = self.incoming_units.recv() => self.new_unit(unit).await,
+                Some(msg) = self.incoming_messages.recv() => {
+                    self.handle_message(msg, storage_client.is_leader()).await
+                }
+                Some(event) = self.incoming_units.recv() => self.unit_event(event).await,
+                _ = sighup.recv() => reload_config(&self.scheduler, &storage_client)
+                    .await
+                    .unwrap_or_else(|e| log::error!("Error reloading config: {e:?}")),
                 _ = sigint.recv() => break,
                 _ = sigterm.recv() => break,
                 else => break
@@ -90,10 +137,11 @@ impl Server {
 
         log::info!("Server shutting down");
         self.task_manager.await_stop().await;
+        self.metrics_writer.write().await.flush().await;
         Ok(())
     }
 
-    async fn handle_message(&mut self, msg: Message) {
+    async fn handle_message(&mut self, msg: Message, is_leader: bool) {
         let peer_id = match msg.peer_id {
             Some(peer_id) => peer_id,
             None => return log::warn!("Dropping anonymous message"),
@@ -103,14 +151,14 @@ impl Server {
             Err(e) => return log::warn!("Error decoding message: {e:?} peer_id={peer_id}"),
         };
         match envelope.msg {
-            Some(Msg::Ping(msg)) => self.ping(peer_id, msg).await,
+            Some(Msg::Ping(msg)) => self.ping(peer_id, msg, is_leader).await,
             Some(Msg::QuerySubmitted(msg)) => self.write_metrics(peer_id, msg).await,
             Some(Msg::QueryFinished(msg)) => self.write_metrics(peer_id, msg).await,
             _ => log::debug!("Unexpected msg received: {envelope:?}"),
         };
     }
 
-    async fn ping(&mut self, peer_id: PeerId, mut msg: Ping) {
+    async fn ping(&mut self, peer_id: PeerId, mut msg: Ping, is_leader: bool) {
         log::debug!("Got ping from {peer_id}");
         if !msg
             .worker_id
@@ -123,13 +171,16 @@ impl Server {
             return log::warn!("Invalid ping signature");
         }
         let ping_hash = msg_hash(&msg);
-        let status = self.scheduler.write().await.ping(peer_id, msg.clone());
+        let pong = self
+            .scheduler
+            .write()
+            .await
+            .ping(peer_id, msg.clone(), is_leader);
         self.write_metrics(peer_id, msg).await;
-        let pong = Msg::Pong(Pong {
-            ping_hash,
-            status: Some(status),
-        });
-        self.send_msg(peer_id, pong).await;
+        if let Some(pong) = pong {
+            let pong = Msg::Pong(Pong { ping_hash, ..pong });
+            self.send_msg(peer_id, pong).await;
+        }
     }
 
     async fn write_metrics(&mut self, peer_id: PeerId, msg: impl Into<MetricsEvent>) {
@@ -144,8 +195,19 @@ impl Server {
         }
     }
 
-    async fn new_unit(&self, unit: SchedulingUnit) {
-        self.scheduler.write().await.new_unit(unit)
+    async fn unit_event(&self, event: UnitEvent) {
+        let mut scheduler = self.scheduler.write().await;
+        match event {
+            UnitEvent::Updated(unit) => scheduler.new_unit(unit),
+            UnitEvent::Invalidated {
+                unit_id,
+                rewritten_from,
+            } => scheduler.invalidate_unit(unit_id, rewritten_from),
+            UnitEvent::Checkpoint {
+                dataset_url,
+                cursor,
+            } => scheduler.update_listing_cursor(dataset_url, cursor),
+        }
     }
 
     async fn send_msg(&mut self, peer_id: PeerId, msg: Msg) {
@@ -167,9 +229,10 @@ impl Server {
     ) -> anyhow::Result<()> {
         log::info!("Starting scheduling task");
         let scheduler = self.scheduler.clone();
-        let last_epoch = Arc::new(Mutex::new(contract_client.current_epoch().await?));
+        let current_epoch = contract_client.current_epoch().await?;
+        self.scheduler.write().await.set_epoch(current_epoch);
+        let last_epoch = Arc::new(Mutex::new(current_epoch));
         let contract_client: Arc<dyn contract_client::Client> = contract_client.into();
-        let schedule_interval = Config::get().schedule_interval_epochs;
 
         let task = move |_| {
             let scheduler = scheduler.clone();
@@ -186,15 +249,23 @@ impl Server {
                 // Update workers every epoch
                 let mut last_epoch = last_epoch.lock().await;
                 if current_epoch > *last_epoch {
-                    match contract_client.active_workers().await {
-                        Ok(workers) => scheduler.write().await.update_workers(workers),
+                    // Don't block pings while waiting for the RPC
+                    let workers = contract_client.active_workers().await;
+                    let mut scheduler = scheduler.write().await;
+                    scheduler.set_epoch(current_epoch);
+                    match workers {
+                        Ok(workers) => scheduler.update_workers(workers),
                         Err(e) => log::error!("Error getting workers: {e:?}"),
                     }
                     *last_epoch = current_epoch;
                 }
 
                 // Schedule chunks every `schedule_interval_epochs`
+                if !storage_client.is_leader() {
+                    return;
+                }
                 let last_schedule_epoch = scheduler.read().await.last_schedule_epoch();
+                let schedule_interval = Config::get().schedule_interval_epochs;
                 if current_epoch >= last_schedule_epoch + schedule_interval {
                     let mut scheduler = scheduler.write().await;
                     scheduler.schedule(current_epoch);
@@ -207,6 +278,30 @@ impl Server {
         Ok(())
     }
 
+    /// Assign new and resized units between full scheduling rounds. The interval limits
+    /// how often this happens, units arriving in the meantime are assigned together.
+    fn spawn_incremental_scheduling_task(&mut self, storage_client: S3Storage) {
+        let interval = Config::get().incremental_schedule_interval;
+        if interval.is_zero() {
+            return log::info!("Incremental scheduling disabled");
+        }
+        log::info!("Starting incremental scheduling task");
+        let scheduler = self.scheduler.clone();
+        let task = move |_| {
+            let scheduler = scheduler.clone();
+            let storage_client = storage_client.clone();
+            async move {
+                if !storage_client.is_leader() || !scheduler.read().await.has_pending_units() {
+                    return;
+                }
+                let mut scheduler = scheduler.write().await;
+                scheduler.schedule_incremental();
+                storage_client.save_scheduler(scheduler).await;
+            }
+        };
+        self.task_manager.spawn_periodic(task, interval);
+    }
+
     fn spawn_worker_monitoring_task(&mut self) {
         log::info!("Starting monitoring task");
         let scheduler = self.scheduler.clone();
@@ -255,15 +350,21 @@ impl Server {
 
     fn spawn_metrics_server_task(
         &mut self,
+        storage_client: S3Storage,
         metrics_listen_addr: SocketAddr,
         metrics_registry: Registry,
+        admin_token: Option<String>,
+        audit_log: Option<AuditLog>,
     ) {
         let scheduler = self.scheduler.clone();
         let task = move |cancel_token: CancellationToken| async move {
             metrics_server::run_server(
                 scheduler,
+                storage_client,
                 metrics_listen_addr,
                 metrics_registry,
+                admin_token,
+                audit_log,
                 cancel_token,
             )
             .await
@@ -272,6 +373,98 @@ impl Server {
         self.task_manager.spawn(task);
     }
 
+    /// Append assignment changes to the audit log. Changes made by followers are discarded,
+    /// their state gets overwritten by the leader's one anyway.
+    fn spawn_audit_log_task(&mut self, storage_client: S3Storage, audit_log: Option<AuditLog>) {
+        let scheduler = self.scheduler.clone();
+        let task = move |_| {
+            let scheduler = scheduler.clone();
+            let storage_client = storage_client.clone();
+            let audit_log = audit_log.clone();
+            async move {
+                let events = scheduler.write().await.take_audit_events();
+                let audit_log = match audit_log {
+                    Some(audit_log) if storage_client.is_leader() => audit_log,
+                    _ => return,
+                };
+                audit_log
+                    .append(&events)
+                    .await
+                    .unwrap_or_else(|e| log::error!("Error writing audit log: {e:?}"));
+            }
+        };
+        self.task_manager.spawn_periodic(task, AUDIT_FLUSH_INTERVAL);
+    }
+
+    /// Broadcast the signed assignment of all units in parts, so that gateways know where
+    /// the data should be without waiting for worker pings.
+    fn spawn_assignment_snapshot_task(&mut self, storage_client: S3Storage) {
+        let interval = Config::get().assignment_snapshot_interval;
+        if interval.is_zero() {
+            return log::info!("Assignment snapshots disabled");
+        }
+        log::info!("Starting assignment snapshot task");
+        let scheduler = self.scheduler.clone();
+        let transport_handle = self.transport_handle.clone();
+        let keypair = self.keypair.clone();
+        let task = move |_| {
+            let scheduler = scheduler.clone();
+            let storage_client = storage_client.clone();
+            let transport_handle = transport_handle.clone();
+            let keypair = keypair.clone();
+            async move {
+                if !storage_client.is_leader() {
+                    return;
+                }
+                let snapshots = scheduler.read().await.assignment_snapshots();
+                for mut snapshot in snapshots {
+                    if let Err(e) = snapshot.sign(&keypair) {
+                        return log::error!("Error signing assignment snapshot: {e:?}");
+                    }
+                    let envelope = Envelope {
+                        msg: Some(Msg::AssignmentSnapshot(snapshot)),
+                    };
+                    let msg_content = envelope.encode_to_vec().into();
+                    transport_handle
+                        .broadcast_msg(msg_content, ASSIGNMENT_TOPIC)
+                        .await
+                        .unwrap_or_else(|e| log::error!("Error broadcasting assignment: {e:?}"));
+                }
+            }
+        };
+        self.task_manager.spawn_periodic(task, interval);
+    }
+
+    /// Keep renewing the leader lease. Followers periodically load the state saved by the
+    /// leader, and a new leader loads it once more before taking over.
+    fn spawn_leader_election_task(&mut self, storage_client: S3Storage) {
+        let interval = Config::get().leader_lease_duration / 3;
+        let scheduler = self.scheduler.clone();
+        let task = move |_| {
+            let scheduler = scheduler.clone();
+            let storage_client = storage_client.clone();
+            async move {
+                let was_leader = storage_client.is_leader();
+                if let Err(e) = storage_client.renew_lease().await {
+                    log::error!("Error renewing leader lease: {e:?}");
+                }
+                let is_leader = storage_client.is_leader();
+                match (was_leader, is_leader) {
+                    (true, true) => return,
+                    (false, true) => log::info!("Became the leader"),
+                    (true, false) => log::warn!("Lost leadership"),
+                    (false, false) => log::debug!("Following the leader"),
+                }
+                prometheus_metrics::leader(is_leader);
+                match storage_client.load_scheduler().await {
+                    Ok(state) => *scheduler.write().await = state,
+                    Err(e) => log::error!("Error loading scheduler state: {e:?}"),
+                }
+            }
+        };
+        self.task_manager.spawn_periodic(task, interval);
+    }
+
     fn spawn_jail_inactive_workers_task(&mut self, storage_client: S3Storage) {
         let interval = Config::get().worker_inactive_timeout;
         let scheduler = self.scheduler.clone();
@@ -279,6 +472,9 @@ impl Server {
             let scheduler = scheduler.clone();
             let storage_client = storage_client.clone();
             async move {
+                if !storage_client.is_leader() {
+                    return;
+                }
                 let mut scheduler = scheduler.write().await;
                 scheduler.jail_inactive_workers();
                 storage_client.save_scheduler(scheduler).await;
@@ -294,6 +490,9 @@ impl Server {
             let scheduler = scheduler.clone();
             let storage_client = storage_client.clone();
             async move {
+                if !storage_client.is_leader() {
+                    return;
+                }
                 let mut scheduler = scheduler.write().await;
                 scheduler.jail_stale_workers();
                 storage_client.save_scheduler(scheduler).await;
@@ -309,6 +508,9 @@ impl Server {
             let scheduler = scheduler.clone();
             let storage_client = storage_client.clone();
             async move {
+                if !storage_client.is_leader() {
+                    return;
+                }
                 let mut scheduler = scheduler.write().await;
                 scheduler.jail_unreachable_workers();
                 storage_client.save_scheduler(scheduler).await;
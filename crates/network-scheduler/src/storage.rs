@@ -1,28 +1,42 @@
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt::Display;
-use std::ops::Deref;
+use std::ops::DerefMut;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use aws_sdk_s3 as s3;
 use aws_sdk_s3::error::SdkError;
 use aws_sdk_s3::types::Object;
+use futures::StreamExt;
 use itertools::Itertools;
 use nonempty::NonEmpty;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, TimestampMilliSeconds};
 use subsquid_network_transport::task_manager::{CancellationToken, TaskManager};
 use tokio::sync::mpsc::{self, Receiver, Sender};
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, OnceCell};
 
-use crate::cli::Config;
+use crate::audit_log::AuditLog;
+use crate::cli::{Config, DatasetManifest};
 use crate::data_chunk::DataChunk;
+use crate::prometheus_metrics;
 use crate::scheduler::Scheduler;
-use crate::scheduling_unit::{bundle_chunks, SchedulingUnit};
+use crate::scheduling_unit::{
+    bundle_chunks, ChunksUpdate, IncomingChunk, ListingCursor, ListingResume, UnitEvent,
+};
 
 #[derive(Clone)]
 struct DatasetStorage {
     bucket: String,
     client: s3::Client,
+    // listing starts after this key, the recent chunks are listed again to detect rewrites
     last_key: Option<String>,
     last_block: Option<u32>,
+    recent_chunks: VecDeque<ListedChunk>,
+    // first and last block of the gap holding back the following chunks
+    gap: Option<(u32, u32)>,
+    // all top-level prefixes, reused until the listing catches up
+    top_prefixes: Option<Vec<String>>,
 }
 
 #[derive(Debug, Clone)]
@@ -31,6 +45,7 @@ struct S3Object {
     // common prefix identifies all objects belonging to a single chunk
     file_name: String,
     size: u64,
+    e_tag: Option<String>,
 }
 
 impl S3Object {
@@ -53,102 +68,306 @@ impl TryFrom<Object> for S3Object {
             prefix,
             file_name,
             size,
+            e_tag: obj.e_tag,
         })
     }
 }
 
+/// Progress of verifying the recent chunks in a single listing round
+struct ListingRound {
+    num_to_verify: usize,
+    num_unchanged: usize,
+    gap: Option<(u32, u32)>,
+}
+
+impl ListingRound {
+    fn new(num_recent_chunks: usize) -> Self {
+        Self {
+            num_to_verify: num_recent_chunks,
+            num_unchanged: 0,
+            gap: None,
+        }
+    }
+}
+
+/// Chunk together with the information needed to tell if it was rewritten
+#[derive(Debug, Clone, PartialEq)]
+struct ListedChunk {
+    prefix: String,
+    last_key: String,
+    // ETags of all objects, only collected if checksum verification is enabled
+    checksums: Vec<String>,
+    chunk: DataChunk,
+}
+
 impl DatasetStorage {
-    pub fn new(bucket: impl ToString, client: s3::Client) -> Self {
+    pub fn new(bucket: impl ToString, client: s3::Client, cursor: Option<ListingCursor>) -> Self {
+        let (last_key, last_block) = match cursor {
+            Some(cursor) => (Some(cursor.last_key), Some(cursor.last_block)),
+            None => (None, None),
+        };
         Self {
             bucket: bucket.to_string(),
             client,
-            last_key: None,
-            last_block: None,
+            last_key,
+            last_block,
+            recent_chunks: VecDeque::new(),
+            gap: None,
+            top_prefixes: None,
+        }
+    }
+
+    fn set_gap(&mut self, gap: Option<(u32, u32)>) {
+        if gap == self.gap {
+            return;
+        }
+        if let Some((first_block, last_block)) = self.gap.take() {
+            log::info!(
+                "Gap {first_block}-{last_block} in bucket {} closed",
+                self.bucket
+            );
+            prometheus_metrics::dataset_gap(&self.bucket, 0);
+        }
+        if let Some((first_block, last_block)) = gap {
+            log::warn!(
+                "Blocks {first_block} to {last_block} missing from {}. Holding back the following chunks",
+                self.bucket
+            );
+            prometheus_metrics::dataset_gap(&self.bucket, last_block - first_block + 1);
+        }
+        self.gap = gap;
+    }
+
+    /// Top-level prefixes of the bucket which may contain objects after `last_key`.
+    /// The bucket is only listed again once the previous listing caught up.
+    async fn list_top_prefixes(&mut self) -> anyhow::Result<Vec<String>> {
+        let mut prefixes = match &self.top_prefixes {
+            Some(prefixes) => prefixes.clone(),
+            None => self.list_all_top_prefixes().await?,
+        };
+        self.top_prefixes = Some(prefixes.clone());
+        if let Some(last_key) = &self.last_key {
+            prefixes.retain(|prefix| prefix > last_key || last_key.starts_with(prefix.as_str()));
         }
+        Ok(prefixes)
     }
 
-    async fn list_all_new_objects(&self) -> anyhow::Result<Vec<S3Object>> {
-        let mut last_key = self.last_key.clone();
-        let mut result = Vec::new();
+    async fn list_all_top_prefixes(&self) -> anyhow::Result<Vec<String>> {
+        let mut prefixes = Vec::new();
+        let mut continuation_token = None;
         loop {
             let s3_result = self
                 .client
                 .list_objects_v2()
                 .bucket(&self.bucket)
-                .set_start_after(last_key)
+                .delimiter("/")
+                .set_continuation_token(continuation_token)
                 .send()
                 .await?;
-            let objects: NonEmpty<S3Object> = match s3_result
-                .contents
-                .and_then(|objects| {
-                    objects
-                        .into_iter()
-                        .map(TryInto::try_into)
-                        .collect::<Result<Vec<S3Object>, &'static str>>()
-                        .ok()
-                })
-                .and_then(NonEmpty::from_vec)
-            {
-                Some(objects) => objects,
+            prefixes.extend(
+                s3_result
+                    .common_prefixes
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter_map(|p| p.prefix),
+            );
+            continuation_token = match s3_result.next_continuation_token {
+                Some(token) => Some(token),
                 None => break,
             };
-            last_key = Some(objects.last().key());
-            result.extend(objects)
         }
+        Ok(prefixes)
+    }
+
+    /// List new objects of each top-level prefix in parallel and process the prefixes in order,
+    /// sending the new chunks as soon as they're verified. Returns the number of chunks sent.
+    async fn list_new_chunks(&mut self, sender: &Sender<ChunksUpdate>) -> anyhow::Result<usize> {
+        let config = Config::get();
+        let manifest = config.dataset_manifest(&self.bucket);
+        let prefixes = self.list_top_prefixes().await?;
+        let (client, bucket, last_key) = (
+            self.client.clone(),
+            self.bucket.clone(),
+            self.last_key.clone(),
+        );
+        let mut listings = futures::stream::iter(prefixes)
+            .map(|prefix| {
+                let start_after = last_key.clone().filter(|key| key.starts_with(&prefix));
+                list_objects(client.clone(), bucket.clone(), prefix, start_after)
+            })
+            .buffered(config.listing_concurrency);
 
-        Ok(result)
+        let mut round = ListingRound::new(self.recent_chunks.len());
+        let mut num_sent = 0;
+        while let Some(objects) = listings.next().await {
+            let chunks = objects?
+                .into_iter()
+                .group_by(|obj| obj.prefix.clone())
+                .into_iter()
+                .map(|(prefix, objects)| self.objects_to_chunk(prefix, objects, &manifest))
+                .collect::<anyhow::Result<Vec<ListedChunk>>>()?;
+            let updates =
+                self.process_chunks(chunks, &mut round, &manifest, config.reorg_check_depth)?;
+            for update in updates {
+                if let ChunksUpdate::Listed(new_chunks) = &update {
+                    num_sent += new_chunks.len();
+                    log::info!(
+                        "Listed {} new chunks from bucket {}",
+                        new_chunks.len(),
+                        self.bucket
+                    );
+                }
+                if sender.send(update).await.is_err() {
+                    return Ok(num_sent);
+                }
+            }
+            if round.gap.is_some() {
+                break;
+            }
+        }
+        if let Some(update) = self.finish_round(&round) {
+            if sender.send(update).await.is_err() {
+                return Ok(num_sent);
+            }
+        }
+        self.set_gap(round.gap);
+        Ok(num_sent)
     }
 
-    async fn list_all_new_chunks(&mut self) -> anyhow::Result<Vec<DataChunk>> {
-        let objects = match NonEmpty::from_vec(self.list_all_new_objects().await?) {
-            None => return Ok(Vec::new()),
-            Some(objects) => objects,
-        };
-        let last_key = objects.last().key();
-        let chunks = objects
-            .into_iter()
-            .group_by(|obj| obj.prefix.clone())
-            .into_iter()
-            .map(|(_, objects)| self.objects_to_chunk(objects))
-            .collect::<anyhow::Result<Vec<DataChunk>>>()?;
-
-        // Verify if chunks are continuous
-        let mut next_block = self.last_block.map(|x| x + 1).unwrap_or_default();
-        for chunk in chunks.iter() {
-            if chunk.block_range.begin != next_block {
-                anyhow::bail!(
-                    "Blocks {} to {} missing from {}",
-                    next_block,
-                    chunk.block_range.begin - 1,
-                    self.bucket
-                );
+    /// Compare the listed chunks with the recent ones and verify that the new ones continue
+    /// the dataset. Returns the updates for the bundler in the order they should be sent.
+    fn process_chunks(
+        &mut self,
+        chunks: Vec<ListedChunk>,
+        round: &mut ListingRound,
+        manifest: &DatasetManifest,
+        reorg_check_depth: usize,
+    ) -> anyhow::Result<Vec<ChunksUpdate>> {
+        let mut updates = Vec::new();
+        let mut new_chunks = Vec::new();
+        for chunk in chunks {
+            // Recent chunks are listed again. If any of them changed, it and all the following
+            // ones are replaced with the new versions.
+            if round.num_unchanged < round.num_to_verify {
+                if self.recent_chunks[round.num_unchanged] == chunk {
+                    round.num_unchanged += 1;
+                    continue;
+                }
+                updates.push(self.chunks_rewritten(round.num_unchanged));
+                round.num_to_verify = round.num_unchanged;
+            }
+
+            // A chunk overlapping recent ones replaces them. Blocks out of the reorg window
+            // are already bundled and can't be replaced anymore.
+            let begin = chunk.chunk.block_range.begin;
+            if begin < self.next_block() {
+                let num_unchanged = match self
+                    .recent_chunks
+                    .iter()
+                    .position(|c| c.chunk.block_range.end >= begin)
+                {
+                    Some(n) if !matches!(self.last_block, Some(last) if begin <= last) => n,
+                    _ => anyhow::bail!(
+                        "Chunk {} overlaps blocks listed before in bucket {}",
+                        chunk.chunk,
+                        self.bucket
+                    ),
+                };
+                if let Some(chunks) = NonEmpty::from_vec(std::mem::take(&mut new_chunks)) {
+                    updates.push(ChunksUpdate::Listed(chunks));
+                }
+                updates.push(self.chunks_rewritten(num_unchanged));
+            }
+
+            // Verify if chunks are continuous. Chunks after an unacknowledged gap are
+            // held back and will be listed again in the next round.
+            let next_block = self.next_block();
+            if begin > next_block && !manifest.is_gap_acknowledged(next_block, begin - 1) {
+                round.gap = Some((next_block, begin - 1));
+                break;
             }
-            next_block = chunk.block_range.end + 1;
+            new_chunks.push(IncomingChunk {
+                chunk: chunk.chunk.clone(),
+                last_key: chunk.last_key.clone(),
+            });
+            self.push_recent_chunk(chunk, reorg_check_depth);
+        }
+        if let Some(chunks) = NonEmpty::from_vec(new_chunks) {
+            updates.push(ChunksUpdate::Listed(chunks));
         }
+        Ok(updates)
+    }
 
-        self.last_key = Some(last_key);
-        self.last_block = chunks.last().map(|c| c.block_range.end);
-        Ok(chunks)
+    /// Recent chunks which weren't listed again in the round have been deleted
+    fn finish_round(&mut self, round: &ListingRound) -> Option<ChunksUpdate> {
+        (round.num_unchanged < round.num_to_verify)
+            .then(|| self.chunks_rewritten(round.num_unchanged))
+    }
+
+    /// First block expected in the next listed chunk
+    fn next_block(&self) -> u32 {
+        self.recent_chunks
+            .back()
+            .map(|c| c.chunk.block_range.end)
+            .or(self.last_block)
+            .map(|x| x + 1)
+            .unwrap_or_default()
+    }
+
+    /// Drop the recent chunks starting from `num_unchanged`. The returned update lets
+    /// the bundler invalidate the units containing them.
+    fn chunks_rewritten(&mut self, num_unchanged: usize) -> ChunksUpdate {
+        let first_rewritten = &self.recent_chunks[num_unchanged].chunk;
+        log::warn!(
+            "Chunks rewritten in bucket {} starting from {first_rewritten}",
+            self.bucket,
+        );
+        let from_block = first_rewritten.block_range.begin;
+        self.recent_chunks.truncate(num_unchanged);
+        ChunksUpdate::Deleted { from_block }
+    }
+
+    fn push_recent_chunk(&mut self, chunk: ListedChunk, reorg_check_depth: usize) {
+        self.recent_chunks.push_back(chunk);
+        while self.recent_chunks.len() > reorg_check_depth {
+            let chunk = self
+                .recent_chunks
+                .pop_front()
+                .expect("recent chunks not empty");
+            self.last_key = Some(chunk.last_key);
+            self.last_block = Some(chunk.chunk.block_range.end);
+        }
     }
 
     fn objects_to_chunk(
         &self,
+        prefix: String,
         objs: impl IntoIterator<Item = S3Object>,
-    ) -> anyhow::Result<DataChunk> {
+        manifest: &DatasetManifest,
+    ) -> anyhow::Result<ListedChunk> {
         let mut last_key = None;
-        let mut blocks_file_present = false;
+        let mut file_names = HashSet::new();
+        let mut checksums = Vec::new();
         let mut size_bytes = 0u64;
         for obj in objs {
             last_key = Some(obj.key());
-            blocks_file_present |= obj.file_name == "blocks.parquet";
             size_bytes += obj.size;
+            if manifest.verify_checksums {
+                match &obj.e_tag {
+                    Some(e_tag) => checksums.push(e_tag.clone()),
+                    None => anyhow::bail!("Checksum missing for {}", obj.key()),
+                }
+            }
+            file_names.insert(obj.file_name);
         }
         let last_key = match last_key {
             Some(key) => key,
             None => anyhow::bail!("Empty object group"),
         };
-        if !blocks_file_present {
-            anyhow::bail!("blocks.parquet missing")
+        for file_name in manifest.required_files.iter() {
+            if !file_names.contains(file_name) {
+                anyhow::bail!("{file_name} missing from {prefix}")
+            }
         }
         let chunk = match DataChunk::new(&self.bucket, &last_key, size_bytes) {
             Ok(chunk) => chunk,
@@ -156,137 +375,608 @@ impl DatasetStorage {
         };
         log::debug!("Downloaded chunk {chunk:?}");
 
-        Ok(chunk)
+        Ok(ListedChunk {
+            prefix,
+            last_key,
+            checksums,
+            chunk,
+        })
     }
 
     pub async fn get_incoming_chunks(
         mut self,
-        sender: Sender<NonEmpty<DataChunk>>,
+        sender: Sender<ChunksUpdate>,
         cancel_token: CancellationToken,
     ) {
-        log::info!("Reading chunks from bucket {}", self.bucket);
-        loop {
-            let chunks_res = tokio::select! {
-                res = self.list_all_new_chunks() => res,
+        log::info!(
+            "Reading chunks from bucket {} starting after {:?}",
+            self.bucket,
+            self.last_key
+        );
+        while !sender.is_closed() {
+            let listing_res = tokio::select! {
+                res = self.list_new_chunks(&sender) => res,
                 _ = cancel_token.cancelled() => break,
             };
-            let chunks = match chunks_res {
-                Ok(chunks) => chunks,
+            if !matches!(listing_res, Ok(n) if n > 0) {
+                // New top-level prefixes may have appeared in the meantime
+                self.top_prefixes = None;
+            }
+            let wait_time = match listing_res {
+                Ok(0) => {
+                    log::info!("Now more chunks for now. Waiting...");
+                    Duration::from_secs(300)
+                }
+                Ok(num_chunks) => {
+                    log::info!(
+                        "Downloaded {num_chunks} new chunks from bucket {}",
+                        self.bucket
+                    );
+                    continue;
+                }
                 Err(e) => {
                     log::error!("Error getting data chunks: {e:?}");
-                    tokio::select! {
-                        _ = tokio::time::sleep(Duration::from_secs(60)) => continue,
-                        _ = cancel_token.cancelled() => break,
-                    }
-                }
-            };
-            let chunks = match NonEmpty::from_vec(chunks) {
-                Some(chunks) => chunks,
-                None => {
-                    log::info!("Now more chunks for now. Waiting...");
-                    tokio::select! {
-                        _ = tokio::time::sleep(Duration::from_secs(300)) => continue,
-                        _ = cancel_token.cancelled() => break,
-                    }
+                    Duration::from_secs(60)
                 }
             };
-            log::info!(
-                "Downloaded {} new chunks from bucket {}",
-                chunks.len(),
-                self.bucket
-            );
-
-            match sender.send(chunks).await {
-                Err(_) => break,
-                Ok(_) => continue,
+            tokio::select! {
+                _ = tokio::time::sleep(wait_time) => continue,
+                _ = cancel_token.cancelled() => break,
             }
         }
         log::info!("Chunks stream ended");
     }
 }
 
+async fn list_objects(
+    client: s3::Client,
+    bucket: String,
+    prefix: String,
+    mut last_key: Option<String>,
+) -> anyhow::Result<Vec<S3Object>> {
+    let mut result = Vec::new();
+    loop {
+        let s3_result = client
+            .list_objects_v2()
+            .bucket(&bucket)
+            .prefix(&prefix)
+            .set_start_after(last_key)
+            .send()
+            .await?;
+        let objects: NonEmpty<S3Object> = match s3_result
+            .contents
+            .and_then(|objects| {
+                objects
+                    .into_iter()
+                    .map(TryInto::try_into)
+                    .collect::<Result<Vec<S3Object>, &'static str>>()
+                    .ok()
+            })
+            .and_then(NonEmpty::from_vec)
+        {
+            Some(objects) => objects,
+            None => break,
+        };
+        last_key = Some(objects.last().key());
+        result.extend(objects)
+    }
+    Ok(result)
+}
+
+/// Lease on the scheduler state. Only the holder of a valid lease schedules and saves state.
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Lease {
+    holder: String,
+    #[serde_as(as = "TimestampMilliSeconds")]
+    expires_at: SystemTime,
+}
+
+/// Conditional write of the lease object
+#[derive(Debug, PartialEq, Eq)]
+enum LeaseWrite {
+    /// A valid lease is held by another instance
+    HeldBy(String),
+    /// Write if the object still has the given ETag or, with `*`, doesn't exist
+    IfMatch(String),
+    IfNoneMatch,
+}
+
+impl LeaseWrite {
+    fn header(&self) -> Option<(&'static str, String)> {
+        match self {
+            LeaseWrite::HeldBy(_) => None,
+            LeaseWrite::IfMatch(e_tag) => Some(("If-Match", e_tag.clone())),
+            LeaseWrite::IfNoneMatch => Some(("If-None-Match", "*".to_string())),
+        }
+    }
+}
+
+/// Decide how the lease is acquired or renewed given the current lease object and its ETag.
+/// Without an ETag the existing object can't be replaced safely.
+fn lease_write(
+    current: Option<(Lease, Option<String>)>,
+    instance_id: &str,
+    now: SystemTime,
+) -> anyhow::Result<LeaseWrite> {
+    match current {
+        None => Ok(LeaseWrite::IfNoneMatch),
+        Some((lease, _)) if lease.holder != instance_id && lease.expires_at > now => {
+            Ok(LeaseWrite::HeldBy(lease.holder))
+        }
+        Some((_, Some(e_tag))) if !e_tag.is_empty() => Ok(LeaseWrite::IfMatch(e_tag)),
+        Some(_) => anyhow::bail!("Lease object has no ETag"),
+    }
+}
+
 #[derive(Clone)]
 pub struct S3Storage {
     client: s3::Client,
-    config: &'static Config,
     scheduler_state_key: String,
+    lease_key: String,
+    instance_id: String,
+    // local deadline of the lease held by this instance
+    leader_until: Arc<std::sync::Mutex<Option<Instant>>>,
     task_manager: Arc<Mutex<TaskManager>>,
+    unit_sender: Arc<OnceCell<Sender<UnitEvent>>>,
+    // bucket -> token for stopping the listing
+    dataset_listings: Arc<Mutex<HashMap<String, CancellationToken>>>,
+    audit_log: Option<AuditLog>,
 }
 
 impl S3Storage {
-    pub async fn new(scheduler_id: impl Display) -> Self {
-        let config = Config::get();
+    pub async fn new(scheduler_id: impl Display, instance_id: String) -> Self {
         let s3_config = aws_config::from_env()
-            .endpoint_url(&config.s3_endpoint)
+            .endpoint_url(&Config::get().s3_endpoint)
             .load()
             .await;
         let client = s3::Client::new(&s3_config);
         let scheduler_state_key = format!("scheduler_{scheduler_id}.json");
+        let lease_key = format!("scheduler_{scheduler_id}.lease");
         Self {
             client,
-            config,
             scheduler_state_key,
+            lease_key,
+            instance_id,
+            leader_until: Default::default(),
+            task_manager: Default::default(),
+            unit_sender: Default::default(),
+            dataset_listings: Default::default(),
+            audit_log: None,
+        }
+    }
+
+    /// Storage with an unconfigured S3 client, holding the leader lease if `leader` is set
+    #[cfg(test)]
+    pub(crate) fn for_test(leader: bool) -> Self {
+        let s3_config = s3::Config::builder()
+            .behavior_version(s3::config::BehaviorVersion::latest())
+            .region(s3::config::Region::new("us-east-1"))
+            .build();
+        let leader_until = leader.then(|| Instant::now() + Duration::from_secs(60));
+        Self {
+            client: s3::Client::from_conf(s3_config),
+            scheduler_state_key: "scheduler_test.json".to_string(),
+            lease_key: "scheduler_test.lease".to_string(),
+            instance_id: "test".to_string(),
+            leader_until: Arc::new(std::sync::Mutex::new(leader_until)),
             task_manager: Default::default(),
+            unit_sender: Default::default(),
+            dataset_listings: Default::default(),
+            audit_log: None,
         }
     }
 
-    pub async fn get_incoming_units(&self) -> Receiver<SchedulingUnit> {
+    /// Assignment changes are appended to the audit log before each save of the state,
+    /// so that the saved assignment is never ahead of the log
+    pub fn with_audit_log(mut self, audit_log: Option<AuditLog>) -> Self {
+        self.audit_log = audit_log;
+        self
+    }
+
+    /// Start listing the datasets, resuming after the cursors saved in the scheduler state
+    pub async fn get_incoming_units(
+        &self,
+        resume: &HashMap<String, ListingResume>,
+    ) -> Receiver<UnitEvent> {
         let (unit_sender, unit_receiver) = mpsc::channel(100);
+        self.unit_sender
+            .set(unit_sender)
+            .expect("Incoming units requested twice");
+        self.update_datasets(resume).await;
+        unit_receiver
+    }
+
+    /// Start listing buckets which were added to `dataset_buckets` and stop listing
+    /// the ones which were removed.
+    pub async fn update_datasets(&self, resume: &HashMap<String, ListingResume>) {
+        let unit_sender = match self.unit_sender.get() {
+            Some(sender) => sender,
+            None => return,
+        };
+        let config = Config::get();
         let mut task_manager = self.task_manager.lock().await;
-        let scheduling_unit_size = self.config.scheduling_unit_size;
-        for bucket in self.config.dataset_buckets.iter() {
+        let mut dataset_listings = self.dataset_listings.lock().await;
+
+        dataset_listings.retain(|bucket, listing_token| {
+            if config.dataset_buckets.contains(bucket) {
+                return true;
+            }
+            log::info!("Stopping listing of bucket {bucket}");
+            listing_token.cancel();
+            false
+        });
+
+        for bucket in config.dataset_buckets.iter() {
+            if dataset_listings.contains_key(bucket) {
+                continue;
+            }
             let (chunk_sender, chunk_receiver) = mpsc::channel(100);
-            let storage = DatasetStorage::new(bucket, self.client.clone());
-            task_manager
-                .spawn(|cancel_token| storage.get_incoming_chunks(chunk_sender, cancel_token));
+            let ListingResume {
+                cursor,
+                generations,
+            } = resume
+                .get(&format!("s3://{bucket}"))
+                .cloned()
+                .unwrap_or_default();
+            let storage = DatasetStorage::new(bucket, self.client.clone(), cursor);
+            let listing_token = CancellationToken::new();
+            let stop_listing = listing_token.clone();
+            // When listing stops, chunk sender is dropped and the bundler task ends too
+            task_manager.spawn(|cancel_token| async move {
+                tokio::select! {
+                    _ = storage.get_incoming_chunks(chunk_sender, cancel_token) => (),
+                    _ = stop_listing.cancelled() => (),
+                }
+            });
             task_manager.spawn(|cancel_token| {
                 bundle_chunks(
                     chunk_receiver,
                     unit_sender.clone(),
-                    scheduling_unit_size,
+                    config.unit_limits(),
+                    config.reorg_check_depth,
+                    generations,
                     cancel_token,
                 )
             });
+            dataset_listings.insert(bucket.clone(), listing_token);
         }
-        unit_receiver
     }
 
-    pub async fn load_scheduler(&self) -> anyhow::Result<Scheduler> {
+    /// Raw scheduler state as saved by the leader, if there is any
+    pub async fn load_state(&self) -> anyhow::Result<Option<Vec<u8>>> {
         let api_result = self
             .client
             .get_object()
-            .bucket(&self.config.scheduler_state_bucket)
+            .bucket(&Config::get().scheduler_state_bucket)
             .key(&self.scheduler_state_key)
             .send()
             .await;
-        let bytes = match api_result {
-            Ok(res) => res.body.collect().await?.to_vec(),
-            Err(SdkError::ServiceError(e)) if e.err().is_no_such_key() => {
+        match api_result {
+            Ok(res) => Ok(Some(res.body.collect().await?.to_vec())),
+            Err(SdkError::ServiceError(e)) if e.err().is_no_such_key() => Ok(None),
+            Err(e) => Err(anyhow::anyhow!(e)),
+        }
+    }
+
+    /// Overwrite the scheduler state, regardless of the leader lease
+    pub async fn save_state(&self, state: Vec<u8>) -> anyhow::Result<()> {
+        self.client
+            .put_object()
+            .bucket(&Config::get().scheduler_state_bucket)
+            .key(&self.scheduler_state_key)
+            .body(state.into())
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    /// Save a copy of the scheduler state next to the original one. Returns the object key.
+    pub async fn save_state_backup(&self, state: Vec<u8>) -> anyhow::Result<String> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time after epoch")
+            .as_millis();
+        let key = format!("{}.backup-{timestamp}", self.scheduler_state_key);
+        self.client
+            .put_object()
+            .bucket(&Config::get().scheduler_state_bucket)
+            .key(&key)
+            .body(state.into())
+            .send()
+            .await?;
+        Ok(key)
+    }
+
+    pub async fn load_scheduler(&self) -> anyhow::Result<Scheduler> {
+        let bytes = match self.load_state().await? {
+            Some(bytes) => bytes,
+            None => {
                 log::warn!("Scheduler state not found. Initializing with blank state.");
                 return Ok(Scheduler::default());
             }
-            Err(e) => return Err(anyhow::anyhow!(e)),
         };
         let mut scheduler: Scheduler = serde_json::from_slice(&bytes)?;
         // List of datasets could have changed since last run, need to clear deprecated units
         scheduler.clear_deprecated_units();
+        // Units are bundled from scratch after restart, existing ones need to match
+        scheduler.rebundle_units(Config::get().unit_limits());
+        scheduler.restore_pending_units();
         Ok(scheduler)
     }
 
-    pub async fn save_scheduler<T: Deref<Target = Scheduler>>(&self, scheduler: T) {
-        log::debug!("Saving scheduler state");
-        let state = match serde_json::to_vec(scheduler.deref()) {
-            Ok(state) => state,
-            Err(e) => return log::error!("Error serializing scheduler state: {e:?}"),
+    pub fn is_leader(&self) -> bool {
+        self.leader_until
+            .lock()
+            .expect("lock not poisoned")
+            .is_some_and(|deadline| Instant::now() < deadline)
+    }
+
+    /// Instance currently holding the leader lease, if it hasn't expired
+    pub async fn lease_holder(&self) -> anyhow::Result<Option<String>> {
+        Ok(self
+            .get_lease()
+            .await?
+            .filter(|(lease, _)| lease.expires_at > SystemTime::now())
+            .map(|(lease, _)| lease.holder))
+    }
+
+    async fn get_lease(&self) -> anyhow::Result<Option<(Lease, Option<String>)>> {
+        let api_result = self
+            .client
+            .get_object()
+            .bucket(&Config::get().scheduler_state_bucket)
+            .key(&self.lease_key)
+            .send()
+            .await;
+        match api_result {
+            Ok(res) => {
+                let e_tag = res.e_tag.clone();
+                let bytes = res.body.collect().await?.to_vec();
+                Ok(Some((serde_json::from_slice(&bytes)?, e_tag)))
+            }
+            Err(SdkError::ServiceError(e)) if e.err().is_no_such_key() => Ok(None),
+            Err(e) => Err(anyhow::anyhow!(e)),
+        }
+    }
+
+    /// Acquire or renew the leader lease. The lease object is written conditionally,
+    /// so only one of the competing instances can succeed. Returns true iff this instance
+    /// is the leader.
+    pub async fn renew_lease(&self) -> anyhow::Result<bool> {
+        let start = Instant::now();
+        let lease_duration = Config::get().leader_lease_duration;
+        let write = lease_write(
+            self.get_lease().await?,
+            &self.instance_id,
+            SystemTime::now(),
+        )?;
+        let (condition, value) = match write.header() {
+            Some(header) => header,
+            None => {
+                log::debug!("Lease held by another instance: {write:?}");
+                *self.leader_until.lock().expect("lock not poisoned") = None;
+                return Ok(false);
+            }
+        };
+        let lease = Lease {
+            holder: self.instance_id.clone(),
+            expires_at: SystemTime::now() + lease_duration,
         };
-        let _ = self
+        let api_result = self
             .client
             .put_object()
-            .bucket(&self.config.scheduler_state_bucket)
-            .key(&self.scheduler_state_key)
-            .body(state.into())
+            .bucket(&Config::get().scheduler_state_bucket)
+            .key(&self.lease_key)
+            .body(serde_json::to_vec(&lease)?.into())
+            .customize()
+            .mutate_request(move |req| {
+                req.headers_mut().insert(condition, value.clone());
+            })
             .send()
+            .await;
+        match api_result {
+            Ok(_) => {
+                *self.leader_until.lock().expect("lock not poisoned") =
+                    Some(start + lease_duration);
+                Ok(true)
+            }
+            Err(SdkError::ServiceError(e)) if e.raw().status().as_u16() == 412 => {
+                log::debug!("Lease taken by another instance");
+                *self.leader_until.lock().expect("lock not poisoned") = None;
+                Ok(false)
+            }
+            Err(e) => Err(anyhow::anyhow!(e)),
+        }
+    }
+
+    pub async fn save_scheduler<T: DerefMut<Target = Scheduler>>(&self, mut scheduler: T) {
+        if !self.is_leader() {
+            return log::debug!("Not a leader. Skipping saving scheduler state");
+        }
+        if let Some(audit_log) = &self.audit_log {
+            audit_log
+                .append(&scheduler.take_audit_events())
+                .await
+                .unwrap_or_else(|e| log::error!("Error writing audit log: {e:?}"));
+        }
+        log::debug!("Saving scheduler state");
+        let state = match serde_json::to_vec(&*scheduler) {
+            Ok(state) => state,
+            Err(e) => return log::error!("Error serializing scheduler state: {e:?}"),
+        };
+        self.save_state(state)
             .await
-            .map_err(|e| log::error!("Error saving scheduler state: {e:?}"));
+            .unwrap_or_else(|e| log::error!("Error saving scheduler state: {e:?}"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn lease(holder: &str, expires_in: Duration, now: SystemTime) -> Lease {
+        Lease {
+            holder: holder.to_string(),
+            expires_at: now + expires_in,
+        }
+    }
+
+    #[test]
+    fn test_lease_write() {
+        let now = SystemTime::now();
+        let valid = Duration::from_secs(10);
+        let e_tag = || Some("\"abc\"".to_string());
+
+        // Nobody holds the lease, it's created only if still missing
+        assert_eq!(
+            lease_write(None, "me", now).unwrap(),
+            LeaseWrite::IfNoneMatch
+        );
+        assert_eq!(
+            LeaseWrite::IfNoneMatch.header(),
+            Some(("If-None-Match", "*".to_string()))
+        );
+
+        // Own lease is renewed, an expired one is taken over, both only if unchanged
+        let own = lease("me", valid, now);
+        assert_eq!(
+            lease_write(Some((own, e_tag())), "me", now).unwrap(),
+            LeaseWrite::IfMatch("\"abc\"".to_string())
+        );
+        let expired = lease("other", Duration::ZERO, now - valid);
+        let write = lease_write(Some((expired, e_tag())), "me", now).unwrap();
+        assert_eq!(write.header(), Some(("If-Match", "\"abc\"".to_string())));
+
+        // Valid lease of another instance isn't touched
+        let other = lease("other", valid, now);
+        let write = lease_write(Some((other, e_tag())), "me", now).unwrap();
+        assert_eq!(write, LeaseWrite::HeldBy("other".to_string()));
+        assert_eq!(write.header(), None);
+
+        // Without an ETag the lease can't be written conditionally
+        let own = lease("me", valid, now);
+        assert!(lease_write(Some((own.clone(), None)), "me", now).is_err());
+        assert!(lease_write(Some((own, Some(String::new()))), "me", now).is_err());
+    }
+
+    fn test_storage() -> DatasetStorage {
+        let client = S3Storage::for_test(false).client;
+        DatasetStorage::new("dataset", client, None)
+    }
+
+    fn listed_chunk(begin: u32, end: u32, hash: &str) -> ListedChunk {
+        let prefix = format!("0000000000/{begin:010}-{end:010}-{hash}");
+        let last_key = format!("{prefix}/blocks.parquet");
+        ListedChunk {
+            chunk: DataChunk::new("dataset", &last_key, 100).unwrap(),
+            prefix,
+            last_key,
+            checksums: Vec::new(),
+        }
+    }
+
+    fn listed_blocks(updates: &[ChunksUpdate]) -> Vec<(u32, u32)> {
+        updates
+            .iter()
+            .flat_map(|update| match update {
+                ChunksUpdate::Listed(chunks) => chunks.iter().collect(),
+                ChunksUpdate::Deleted { .. } => Vec::new(),
+            })
+            .map(|c| (c.chunk.block_range.begin, c.chunk.block_range.end))
+            .collect()
+    }
+
+    /// Process one listing round of the chunks, like `list_new_chunks` does
+    fn list_round(
+        storage: &mut DatasetStorage,
+        chunks: Vec<ListedChunk>,
+        manifest: &DatasetManifest,
+    ) -> Vec<ChunksUpdate> {
+        let mut round = ListingRound::new(storage.recent_chunks.len());
+        let mut updates = storage
+            .process_chunks(chunks, &mut round, manifest, 10)
+            .unwrap();
+        updates.extend(storage.finish_round(&round));
+        storage.gap = round.gap;
+        updates
+    }
+
+    #[test]
+    fn test_gap() {
+        let mut storage = test_storage();
+        let manifest = DatasetManifest::default();
+        let chunks = vec![
+            listed_chunk(0, 99, "a"),
+            listed_chunk(100, 199, "a"),
+            listed_chunk(300, 399, "a"),
+        ];
+
+        // Chunks after the gap are held back
+        let updates = list_round(&mut storage, chunks.clone(), &manifest);
+        assert_eq!(listed_blocks(&updates), [(0, 99), (100, 199)]);
+        assert_eq!(storage.gap, Some((200, 299)));
+        assert_eq!(storage.next_block(), 200);
+
+        // Once the gap is filled, the following chunks are listed
+        let mut chunks = chunks;
+        chunks.insert(2, listed_chunk(200, 299, "a"));
+        let updates = list_round(&mut storage, chunks, &manifest);
+        assert_eq!(listed_blocks(&updates), [(200, 299), (300, 399)]);
+        assert_eq!(storage.gap, None);
+    }
+
+    #[test]
+    fn test_acknowledged_gap() {
+        let mut storage = test_storage();
+        let manifest = DatasetManifest {
+            acknowledged_gaps: vec![(150, 299)],
+            ..Default::default()
+        };
+        let chunks = vec![
+            listed_chunk(0, 99, "a"),
+            listed_chunk(100, 199, "a"),
+            listed_chunk(300, 399, "a"),
+            listed_chunk(500, 599, "a"),
+        ];
+        let updates = list_round(&mut storage, chunks, &manifest);
+        // Only gaps within the acknowledged range are skipped
+        assert_eq!(listed_blocks(&updates), [(0, 99), (100, 199), (300, 399)]);
+        assert_eq!(storage.gap, Some((400, 499)));
+    }
+
+    #[test]
+    fn test_rewritten_chunks() {
+        let mut storage = test_storage();
+        let manifest = DatasetManifest::default();
+        let chunks = vec![
+            listed_chunk(0, 99, "a"),
+            listed_chunk(100, 199, "a"),
+            listed_chunk(200, 299, "a"),
+        ];
+        list_round(&mut storage, chunks.clone(), &manifest);
+
+        // Unchanged chunks aren't sent again
+        assert!(list_round(&mut storage, chunks.clone(), &manifest).is_empty());
+
+        // A rewritten chunk replaces the old version and all the following ones
+        let rewritten = vec![chunks[0].clone(), listed_chunk(100, 199, "b")];
+        let updates = list_round(&mut storage, rewritten, &manifest);
+        assert!(matches!(
+            updates[0],
+            ChunksUpdate::Deleted { from_block: 100 }
+        ));
+        assert_eq!(listed_blocks(&updates), [(100, 199)]);
+        assert_eq!(storage.next_block(), 200);
+
+        // Deleted chunks without a replacement are reported too
+        let updates = list_round(&mut storage, vec![chunks[0].clone()], &manifest);
+        assert!(matches!(
+            updates[..],
+            [ChunksUpdate::Deleted { from_block: 100 }]
+        ));
+        assert_eq!(storage.next_block(), 100);
     }
 }
@@ -1,16 +1,18 @@
+use std::cmp::max;
 use std::collections::{HashMap, HashSet};
 use std::fmt::{Display, Formatter};
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, TimestampMilliSeconds};
 
 use contract_client::Address;
-use subsquid_messages::{Ping, RangeSet};
+use subsquid_messages::{ChunkDownloadError, Ping, RangeSet};
 use subsquid_network_transport::PeerId;
 
 use crate::cli::Config;
-use crate::data_chunk::DataChunk;
+use crate::data_chunk::{ChunkId, DataChunk};
 use crate::scheduling_unit::{SchedulingUnit, UnitId};
 
 #[serde_as]
@@ -37,13 +39,87 @@ pub struct WorkerState {
     pub unreachable_since: Option<SystemTime>,
     #[serde(default)]
     pub jail_reason: Option<JailReason>,
+    #[serde(default)]
+    pub reported_capacity: Option<u64>,
+    #[serde(default)]
+    pub jail_history: Vec<JailRecord>,
+    // Rewritten chunks which the worker may still store in the old version
+    #[serde(default)]
+    pub outdated_chunks: Vec<DataChunk>,
+    // Changes every time the set of assigned chunks changes
+    #[serde(default)]
+    pub assignment_version: u64,
+    // Moving average of download speed (bytes/s), measured while the worker is missing data
+    #[serde(default)]
+    pub download_throughput: f64,
+    // Time and stored bytes of the previous ping, the base for the next throughput sample
+    #[serde(skip)]
+    last_download_sample: Option<(SystemTime, u64)>,
+    // Chunks the worker failed to download, as reported in the last ping
+    #[serde(default)]
+    pub download_errors: Vec<ChunkError>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChunkError {
+    pub chunk_id: ChunkId,
+    /// Dataset URL and block range of the chunk
+    pub chunk: String,
+    pub failed_attempts: u32,
+    pub error: String,
+}
+
+impl From<ChunkDownloadError> for ChunkError {
+    fn from(report: ChunkDownloadError) -> Self {
+        // Size doesn't affect the chunk ID
+        let chunk = DataChunk {
+            dataset_url: report.dataset,
+            block_range: report.block_range.unwrap_or_default(),
+            size_bytes: 0,
+        };
+        Self {
+            chunk_id: chunk.id(),
+            chunk: chunk.to_string(),
+            failed_attempts: report.failed_attempts,
+            error: report.error,
+        }
+    }
+}
+
+const MAX_JAIL_HISTORY_LEN: usize = 100;
+const MAX_DOWNLOAD_ERRORS: usize = 100;
+const THROUGHPUT_SMOOTHING: f64 = 0.1;
+
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JailRecord {
+    pub reason: JailReason,
+    #[serde_as(as = "TimestampMilliSeconds")]
+    pub start: SystemTime,
+    #[serde_as(as = "Option<TimestampMilliSeconds>")]
+    pub end: Option<SystemTime>,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum JailReason {
     Inactive,
     Unreachable,
     Stale,
+    Slow,
+    Manual(String),
+}
+
+impl JailReason {
+    /// Reason without details, used as a metric label
+    pub fn kind(&self) -> &'static str {
+        match self {
+            JailReason::Inactive => "inactive",
+            JailReason::Unreachable => "unreachable",
+            JailReason::Stale => "stale",
+            JailReason::Slow => "slow",
+            JailReason::Manual(_) => "manual",
+        }
+    }
 }
 
 impl Display for JailReason {
@@ -60,6 +136,12 @@ impl Display for JailReason {
                 "Worker didn't download any of the assigned chunks trough {} seconds",
                 Config::get().worker_stale_timeout.as_secs()
             ),
+            JailReason::Slow => write!(
+                f,
+                "Worker downloads too slowly to sync the assigned chunks within {} seconds",
+                Config::get().worker_max_sync_time.as_secs()
+            ),
+            JailReason::Manual(msg) => write!(f, "Worker jailed by the operator: {msg}"),
         }
     }
 }
@@ -82,6 +164,13 @@ impl WorkerState {
             last_dial_ok: false,
             unreachable_since: None,
             jail_reason: None,
+            reported_capacity: None,
+            jail_history: Vec::new(),
+            outdated_chunks: Vec::new(),
+            assignment_version: 0,
+            download_throughput: 0.0,
+            last_download_sample: None,
+            download_errors: Vec::new(),
         }
     }
 
@@ -96,9 +185,11 @@ impl WorkerState {
         self.last_ping.elapsed().expect("Time doesn't go backwards")
     }
 
-    /// Register ping msg from a worker.
-    pub fn ping(&mut self, msg: Ping) {
+    /// Register ping msg from a worker. Returns true if the set of chunks the worker
+    /// fails to download changed. Only errors for the chunks assigned to the worker count.
+    pub fn ping(&mut self, msg: Ping, units: &HashMap<UnitId, SchedulingUnit>) -> bool {
         self.last_ping = SystemTime::now();
+        self.update_throughput(msg.stored_bytes.unwrap_or_default());
         self.version = msg.version;
         self.stored_ranges = msg
             .stored_ranges
@@ -106,6 +197,69 @@ impl WorkerState {
             .map(|r| (r.url, r.ranges.into()))
             .collect();
         self.stored_bytes = msg.stored_bytes.unwrap_or_default();
+        self.reported_capacity = msg.capacity_bytes;
+        let outdated_chunks = std::mem::take(&mut self.outdated_chunks);
+        self.outdated_chunks = outdated_chunks
+            .into_iter()
+            .filter(|chunk| self.has_chunk(chunk))
+            .collect();
+
+        let mut download_errors: Vec<ChunkError> = msg
+            .download_errors
+            .into_iter()
+            .map(ChunkError::from)
+            .unique_by(|e| e.chunk_id)
+            .take(MAX_DOWNLOAD_ERRORS)
+            .collect();
+        if !download_errors.is_empty() {
+            let assigned_chunks: HashSet<ChunkId> = self
+                .assigned_chunks(units)
+                .map(|chunk| chunk.id())
+                .collect();
+            download_errors.retain(|e| assigned_chunks.contains(&e.chunk_id));
+        }
+        for e in download_errors.iter() {
+            log::debug!(
+                "Worker {} failed to download chunk {} {} times: {}",
+                self.peer_id,
+                e.chunk,
+                e.failed_attempts,
+                e.error
+            );
+        }
+        let failing_chunks = |errors: &[ChunkError]| -> HashSet<ChunkId> {
+            errors.iter().map(|e| e.chunk_id).collect()
+        };
+        let changed = failing_chunks(&download_errors) != failing_chunks(&self.download_errors);
+        self.download_errors = download_errors;
+        changed
+    }
+
+    /// Take a throughput sample from the growth of stored data since the previous ping.
+    /// Samples are only taken while the worker is missing some of the assigned chunks,
+    /// a fully synced worker isn't expected to download anything. Pings without growth
+    /// aren't sampled either, stored data also shrinks when the worker drops unassigned chunks.
+    fn update_throughput(&mut self, stored_bytes: u64) {
+        let previous = self
+            .last_download_sample
+            .replace((self.last_ping, stored_bytes));
+        let (prev_time, prev_bytes) = match previous {
+            Some(sample) => sample,
+            None => return,
+        };
+        let elapsed = match self.last_ping.duration_since(prev_time) {
+            Ok(elapsed) if !elapsed.is_zero() => elapsed,
+            _ => return,
+        };
+        if self.num_missing_chunks == 0 || stored_bytes <= prev_bytes {
+            return;
+        }
+        let sample = (stored_bytes - prev_bytes) as f64 / elapsed.as_secs_f64();
+        self.download_throughput = if self.download_throughput > 0.0 {
+            THROUGHPUT_SMOOTHING * sample + (1.0 - THROUGHPUT_SMOOTHING) * self.download_throughput
+        } else {
+            sample
+        };
     }
 
     pub fn dialed(&mut self, reachable: bool) {
@@ -132,10 +286,30 @@ impl WorkerState {
         })
     }
 
+    /// Storage capacity reported by the worker (or the default one),
+    /// clamped by the configured maximum.
+    pub fn storage_capacity(&self) -> u64 {
+        let config = Config::get();
+        self.reported_capacity
+            .unwrap_or(config.worker_storage_bytes)
+            .min(config.max_worker_storage_bytes())
+    }
+
     pub fn remaining_capacity(&self) -> u64 {
-        Config::get()
-            .worker_storage_bytes
-            .saturating_sub(self.assigned_bytes)
+        self.storage_capacity().saturating_sub(self.assigned_bytes)
+    }
+
+    pub fn is_over_capacity(&self) -> bool {
+        self.assigned_bytes > self.storage_capacity()
+    }
+
+    /// Versions are based on time, so that they don't repeat even if the state is lost.
+    pub fn assignment_changed(&mut self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time after epoch")
+            .as_millis() as u64;
+        self.assignment_version = max(self.assignment_version + 1, now);
     }
 
     pub fn try_assign_unit(&mut self, unit_id: UnitId, unit_size: u64) -> bool {
@@ -144,6 +318,7 @@ impl WorkerState {
         }
         if self.assigned_units.insert(unit_id) {
             self.assigned_bytes += unit_size;
+            self.assignment_changed();
             return true; // Successfully assigned
         }
         false // Unit was already assigned before
@@ -152,13 +327,37 @@ impl WorkerState {
     pub fn remove_unit(&mut self, unit_id: &UnitId, unit_size: u64) {
         if self.assigned_units.remove(unit_id) {
             self.assigned_bytes -= unit_size;
+            self.assignment_changed();
         }
     }
 
+    /// Unit's chunks were rewritten. Unassign it and remember the old chunks, so that the new
+    /// version is not assigned until the worker deletes them.
+    pub fn invalidate_unit(&mut self, unit: &SchedulingUnit, rewritten_from: u32) {
+        self.remove_unit(&unit.id(), unit.size_bytes());
+        let rewritten = unit
+            .chunks
+            .iter()
+            .filter(|chunk| chunk.block_range.end >= rewritten_from);
+        self.outdated_chunks.extend(rewritten.cloned());
+    }
+
+    /// Check if the worker still stores an old version of some chunks overlapping the unit.
+    pub fn stores_outdated_data(&self, unit: &SchedulingUnit) -> bool {
+        self.outdated_chunks.iter().any(|outdated| {
+            unit.chunks.iter().any(|chunk| {
+                chunk.dataset_url == outdated.dataset_url
+                    && chunk.block_range.begin <= outdated.block_range.end
+                    && outdated.block_range.begin <= chunk.block_range.end
+            })
+        })
+    }
+
     /// Assigned unit's size has increased. Unassing the unit if it doesn't fit anymore.
     /// Return true iff the unit remained assigned.
     pub fn try_expand_unit(&mut self, unit_id: &UnitId, old_size: u64, new_size: u64) -> bool {
         let size_diff = new_size - old_size;
+        self.assignment_changed();
         if self.remaining_capacity() > size_diff {
             self.assigned_bytes += size_diff;
             true
@@ -181,72 +380,205 @@ impl WorkerState {
         })
     }
 
-    fn count_missing_chunks<'a>(&'a self, units: &'a HashMap<UnitId, SchedulingUnit>) -> u32 {
-        self.assigned_chunks(units)
-            .map(|chunk| match self.stored_ranges.get(&chunk.dataset_url) {
-                Some(range_set) if range_set.includes(chunk.block_range) => 0,
-                _ => 1,
-            })
+    pub fn has_chunk(&self, chunk: &DataChunk) -> bool {
+        self.stored_ranges
+            .get(&chunk.dataset_url)
+            .is_some_and(|range_set| range_set.includes(chunk.block_range))
+    }
+
+    /// Total size of the unit's chunks which the worker has already downloaded.
+    pub fn stored_bytes_of(&self, unit: &SchedulingUnit) -> u64 {
+        if !self.stored_ranges.contains_key(unit.dataset_url()) {
+            return 0;
+        }
+        unit.chunks
+            .iter()
+            .filter(|chunk| self.has_chunk(chunk))
+            .map(|chunk| chunk.size_bytes)
+            .sum()
+    }
+
+    /// Check if the worker has already downloaded all chunks of the unit.
+    pub fn has_unit(&self, unit: &SchedulingUnit) -> bool {
+        unit.chunks.iter().all(|chunk| self.has_chunk(chunk))
+    }
+
+    /// Assigned chunks the worker doesn't have yet, except for the bad ones
+    /// which can't be downloaded by anyone.
+    fn missing_chunks<'a>(
+        &'a self,
+        units: &'a HashMap<UnitId, SchedulingUnit>,
+        bad_chunks: &'a HashSet<ChunkId>,
+    ) -> impl Iterator<Item = DataChunk> + 'a {
+        self.assigned_chunks(units).filter(|chunk| {
+            !self.has_chunk(chunk) && (bad_chunks.is_empty() || !bad_chunks.contains(&chunk.id()))
+        })
+    }
+
+    fn count_missing_chunks<'a>(
+        &'a self,
+        units: &'a HashMap<UnitId, SchedulingUnit>,
+        bad_chunks: &'a HashSet<ChunkId>,
+    ) -> u32 {
+        self.missing_chunks(units, bad_chunks).count() as u32
+    }
+
+    fn count_missing_bytes<'a>(
+        &'a self,
+        units: &'a HashMap<UnitId, SchedulingUnit>,
+        bad_chunks: &'a HashSet<ChunkId>,
+    ) -> u64 {
+        self.missing_chunks(units, bad_chunks)
+            .map(|chunk| chunk.size_bytes)
             .sum()
     }
 
-    /// Check if the worker is making progress with downloading missing chunks.
-    /// Returns true iff the worker is fully synced or making progress.
+    /// Check if the worker is making progress with downloading missing chunks, fast enough
+    /// to download all of them within `worker_max_sync_time`. Bad chunks are not counted.
+    /// Returns the reason to jail the worker if it's not.
     pub fn check_download_progress<'a>(
         &'a mut self,
         units: &'a HashMap<UnitId, SchedulingUnit>,
-    ) -> bool {
+        bad_chunks: &'a HashSet<ChunkId>,
+    ) -> Option<JailReason> {
         assert!(!self.jailed);
         if self
             .last_assignment
             .elapsed()
             .is_ok_and(|d| d < Config::get().worker_stale_timeout)
         {
-            return true;
+            return None;
         }
 
-        let num_missing_chunks = self.count_missing_chunks(units);
+        let num_missing_chunks = self.count_missing_chunks(units, bad_chunks);
         if num_missing_chunks == 0 {
             log::debug!("Worker {} is fully synced", self.peer_id);
             self.num_missing_chunks = num_missing_chunks;
-            true
-        } else if num_missing_chunks < self.num_missing_chunks {
-            log::debug!(
-                "Worker {} is making progress {} -> {} chunks missing",
-                self.peer_id,
-                self.num_missing_chunks,
-                num_missing_chunks
-            );
-            self.num_missing_chunks = num_missing_chunks;
-            true
-        } else {
+            return None;
+        }
+        if num_missing_chunks >= self.num_missing_chunks {
             log::debug!(
                 "Worker {} has not downloaded any chunks since last check",
                 self.peer_id
             );
-            false
+            return Some(JailReason::Stale);
+        }
+        log::debug!(
+            "Worker {} is making progress {} -> {} chunks missing",
+            self.peer_id,
+            self.num_missing_chunks,
+            num_missing_chunks
+        );
+        self.num_missing_chunks = num_missing_chunks;
+
+        // Without a throughput estimate there's no way to tell if the worker is too slow
+        if self.download_throughput <= 0.0 {
+            return None;
+        }
+        let missing_bytes = self.count_missing_bytes(units, bad_chunks);
+        let expected_sync_time =
+            Duration::from_secs_f64(missing_bytes as f64 / self.download_throughput);
+        if expected_sync_time > Config::get().worker_max_sync_time {
+            log::debug!(
+                "Worker {} is too slow: {missing_bytes} bytes missing, throughput {:.0} B/s",
+                self.peer_id,
+                self.download_throughput
+            );
+            return Some(JailReason::Slow);
         }
+        None
     }
 
-    pub fn reset_download_progress<'a>(&'a mut self, units: &'a HashMap<UnitId, SchedulingUnit>) {
-        self.num_missing_chunks = self.count_missing_chunks(units);
+    pub fn reset_download_progress<'a>(
+        &'a mut self,
+        units: &'a HashMap<UnitId, SchedulingUnit>,
+        bad_chunks: &'a HashSet<ChunkId>,
+    ) {
+        self.recount_missing_chunks(units, bad_chunks);
         self.last_assignment = SystemTime::now();
     }
 
+    /// Update the baseline for the next progress check without restarting the stale timeout.
+    pub fn recount_missing_chunks<'a>(
+        &'a mut self,
+        units: &'a HashMap<UnitId, SchedulingUnit>,
+        bad_chunks: &'a HashSet<ChunkId>,
+    ) {
+        self.num_missing_chunks = self.count_missing_chunks(units, bad_chunks);
+    }
+
     /// Jail the worker, unassign all units and return their IDs.
     pub fn jail(&mut self, reason: JailReason) -> Vec<UnitId> {
         log::info!("Jailing worker {}", self.peer_id);
+        match self.jail_history.last_mut() {
+            // Worker is already jailed, only the reason changes
+            Some(record) if self.jailed && record.end.is_none() => record.reason = reason.clone(),
+            _ => {
+                self.jail_history.push(JailRecord {
+                    reason: reason.clone(),
+                    start: SystemTime::now(),
+                    end: None,
+                });
+                if self.jail_history.len() > MAX_JAIL_HISTORY_LEN {
+                    self.jail_history.remove(0);
+                }
+            }
+        }
         self.jailed = true;
         self.jail_reason = Some(reason);
         self.assigned_bytes = 0;
         self.num_missing_chunks = 0;
+        self.assignment_changed();
         self.assigned_units.drain().collect()
     }
 
+    pub fn is_jailed_manually(&self) -> bool {
+        matches!(self.jail_reason, Some(JailReason::Manual(_)))
+    }
+
+    /// Number of automatic jailings within the back-off window, including the current one
+    fn num_recent_offences(&self) -> u32 {
+        let window = Config::get().jail_backoff_window;
+        self.jail_history
+            .iter()
+            .filter(|r| !matches!(r.reason, JailReason::Manual(_)))
+            .filter(|r| r.start.elapsed().is_ok_and(|d| d < window))
+            .count() as u32
+    }
+
+    /// Time at which the worker can be released from jail. The first offence within
+    /// the back-off window has no extra penalty, the penalty doubles with each next one.
+    pub fn release_time(&self) -> Option<SystemTime> {
+        if !self.jailed {
+            return None;
+        }
+        let jail_start = self
+            .jail_history
+            .last()
+            .map(|r| r.start)
+            .unwrap_or(UNIX_EPOCH);
+        let config = Config::get();
+        let penalty = match self.num_recent_offences() {
+            0 | 1 => Duration::ZERO,
+            n => config
+                .jail_backoff_base
+                .saturating_mul(2u32.saturating_pow(n - 2))
+                .min(config.jail_backoff_max),
+        };
+        Some(jail_start + penalty)
+    }
+
+    pub fn can_be_released(&self) -> bool {
+        self.release_time().is_some_and(|t| t <= SystemTime::now())
+    }
+
     pub fn release(&mut self) {
         log::info!("Releasing worker {}", self.peer_id);
         self.jailed = false;
         self.jail_reason = None;
+        if let Some(record) = self.jail_history.last_mut() {
+            record.end.get_or_insert_with(SystemTime::now);
+        }
     }
 }
 
@@ -261,3 +593,179 @@ impl Display for WorkerState {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use subsquid_messages::Range;
+
+    use super::*;
+
+    fn download_error(begin: u32, end: u32) -> ChunkDownloadError {
+        ChunkDownloadError {
+            dataset: "s3://dataset".to_string(),
+            block_range: Some(Range { begin, end }),
+            failed_attempts: 3,
+            error: "Not found".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_throughput() {
+        let _config = Config::set_for_test(|_| {});
+        let mut worker = WorkerState::new(PeerId::random(), Default::default());
+        worker.num_missing_chunks = 1;
+        let start = SystemTime::now();
+        let mut sample = |secs, stored_bytes| {
+            worker.last_ping = start + Duration::from_secs(secs);
+            worker.update_throughput(stored_bytes);
+            worker.download_throughput
+        };
+        assert_eq!(sample(0, 0), 0.0);
+        // The first sample is taken as is
+        assert_eq!(sample(10, 1000), 100.0);
+        // Deleted data doesn't count as a slowdown
+        assert_eq!(sample(20, 500), 100.0);
+        assert_eq!(sample(30, 500), 100.0);
+        assert!((sample(40, 2500) - 110.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_slow_worker() {
+        let _config = Config::set_for_test(|config| {
+            config.worker_max_sync_time = Duration::from_secs(3600);
+        });
+        let chunks = [
+            DataChunk::new(
+                "dataset",
+                "0000000000/0000000000-0000000999-00000000",
+                1 << 20,
+            )
+            .unwrap(),
+            DataChunk::new(
+                "dataset",
+                "0000000000/0000001000-0000001999-00000000",
+                1 << 20,
+            )
+            .unwrap(),
+        ];
+        let unit = SchedulingUnit::from_slice(&chunks);
+        let unit_id = unit.id();
+        let units = HashMap::from([(unit_id, unit)]);
+        let no_bad_chunks = HashSet::new();
+        let mut worker = WorkerState::new(PeerId::random(), Default::default());
+        assert!(worker.try_assign_unit(unit_id, 2 << 20));
+        worker.last_assignment = UNIX_EPOCH;
+
+        // Without a throughput estimate the worker is only checked for progress
+        worker.num_missing_chunks = 3;
+        assert_eq!(worker.check_download_progress(&units, &no_bad_chunks), None);
+        assert_eq!(worker.num_missing_chunks, 2);
+
+        // 2 MiB at 100 B/s take longer than an hour
+        worker.num_missing_chunks = 3;
+        worker.download_throughput = 100.0;
+        assert_eq!(
+            worker.check_download_progress(&units, &no_bad_chunks),
+            Some(JailReason::Slow)
+        );
+
+        worker.num_missing_chunks = 3;
+        worker.download_throughput = 10_000.0;
+        assert_eq!(worker.check_download_progress(&units, &no_bad_chunks), None);
+
+        // Bad chunks are not expected to be downloaded
+        worker.num_missing_chunks = 3;
+        worker.download_throughput = 500.0;
+        assert_eq!(
+            worker.check_download_progress(&units, &no_bad_chunks),
+            Some(JailReason::Slow)
+        );
+        worker.num_missing_chunks = 3;
+        let bad_chunks = HashSet::from([chunks[0].id()]);
+        assert_eq!(worker.check_download_progress(&units, &bad_chunks), None);
+    }
+
+    #[test]
+    fn test_download_errors() {
+        let _config = Config::set_for_test(|_| {});
+        let chunk =
+            DataChunk::new("dataset", "0000000000/0000000000-0000000999-00000000", 100).unwrap();
+        let chunk_id = chunk.id();
+        let unit = SchedulingUnit::from_slice(&[chunk]);
+        let unit_id = unit.id();
+        let units = HashMap::from([(unit_id, unit)]);
+        let mut worker = WorkerState::new(PeerId::random(), Default::default());
+        assert!(worker.try_assign_unit(unit_id, 100));
+
+        // Errors for chunks which aren't assigned to the worker are dropped
+        let msg = Ping {
+            download_errors: vec![download_error(0, 999), download_error(1000, 1999)],
+            ..Default::default()
+        };
+        assert!(worker.ping(msg, &units));
+        assert_eq!(worker.download_errors.len(), 1);
+        assert_eq!(worker.download_errors[0].chunk_id, chunk_id);
+
+        let msg = Ping {
+            download_errors: vec![download_error(0, 999)],
+            ..Default::default()
+        };
+        assert!(!worker.ping(msg, &units));
+
+        // Repeated errors for the same chunk collapse into one
+        let msg = Ping {
+            download_errors: vec![download_error(0, 999); 2 * MAX_DOWNLOAD_ERRORS],
+            ..Default::default()
+        };
+        worker.ping(msg, &units);
+        assert_eq!(worker.download_errors.len(), 1);
+
+        assert!(worker.ping(Ping::default(), &units));
+        assert!(worker.download_errors.is_empty());
+    }
+
+    #[test]
+    fn test_invalidate_unit() {
+        let _config = Config::set_for_test(|_| {});
+        let chunk = |begin: u32| {
+            let chunk_str = format!("0000000000/{begin:010}-{:010}-00000000", begin + 999);
+            DataChunk::new("dataset", &chunk_str, 100).unwrap()
+        };
+        let unit = SchedulingUnit::from_slice(&[chunk(0), chunk(1000)]);
+        let mut worker = WorkerState::new(PeerId::random(), Default::default());
+        assert!(worker.try_assign_unit(unit.id(), 200));
+
+        worker.invalidate_unit(&unit, 1000);
+        assert!(worker.assigned_units.is_empty());
+        assert_eq!(worker.outdated_chunks, [chunk(1000)]);
+
+        // The unit bundled again with the unchanged prefix can be assigned right away
+        assert!(!worker.stores_outdated_data(&SchedulingUnit::from_slice(&[chunk(0)])));
+        assert!(worker.stores_outdated_data(&SchedulingUnit::from_slice(&[chunk(1000)])));
+    }
+
+    #[test]
+    fn test_assignment_version() {
+        let mut worker = WorkerState::new(PeerId::random(), Default::default());
+        worker.assignment_changed();
+        let version = worker.assignment_version;
+
+        // Versions keep growing after the state is saved and loaded
+        let state = serde_json::to_string(&worker).unwrap();
+        let mut restored: WorkerState = serde_json::from_str(&state).unwrap();
+        assert_eq!(restored.assignment_version, version);
+        restored.assignment_changed();
+        assert!(restored.assignment_version > version);
+
+        // Even if they were set by a clock running ahead
+        let ahead = restored.assignment_version + 3_600_000;
+        restored.assignment_version = ahead;
+        restored.assignment_changed();
+        assert_eq!(restored.assignment_version, ahead + 1);
+
+        // If the state is lost, versions start from the current time
+        let mut fresh = WorkerState::new(worker.peer_id, Default::default());
+        fresh.assignment_changed();
+        assert!(fresh.assignment_version >= version);
+    }
+}
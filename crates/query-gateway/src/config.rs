@@ -6,6 +6,7 @@ use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
 use std::path::Path;
 use std::time::Duration;
+use subsquid_messages::version_policy::WorkerVersionPolicy;
 use subsquid_network_transport::PeerId;
 use tokio::sync::OnceCell;
 
@@ -31,6 +32,10 @@ fn default_workers_update_interval() -> Duration {
     Duration::from_secs(180)
 }
 
+fn default_worker_versions() -> WorkerVersionPolicy {
+    WorkerVersionPolicy::new(">=0.2.2".parse().unwrap())
+}
+
 /// This struct exists not to confuse dataset name with it's encoded ID
 #[derive(Debug, Clone, Hash, PartialEq, Eq, Deserialize)]
 pub struct DatasetId(pub String);
@@ -92,6 +97,8 @@ pub struct Config {
     )]
     pub workers_update_interval: Duration,
     pub available_datasets: HashMap<String, DatasetId>,
+    #[serde(default = "default_worker_versions")]
+    pub worker_versions: WorkerVersionPolicy,
 }
 
 impl Config {
@@ -107,6 +114,13 @@ impl Config {
         CONFIG.get().expect("Config not initialized")
     }
 
+    /// Initialize the config from the bundled config file, if it's not yet initialized
+    #[cfg(test)]
+    pub(crate) fn init_for_test() {
+        let config = serde_yaml::from_str(include_str!("../config.yml")).unwrap();
+        let _ = CONFIG.set(config);
+    }
+
     pub fn dataset_id(&self, dataset: &str) -> Option<DatasetId> {
         self.available_datasets.get(dataset).cloned()
     }
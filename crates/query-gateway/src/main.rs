@@ -50,6 +50,7 @@ struct Cli {
 }
 
 const PING_TOPIC: &str = "worker_ping";
+const ASSIGNMENT_TOPIC: &str = "assignment_snapshot";
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -66,6 +67,8 @@ async fn main() -> anyhow::Result<()> {
 
     // Subscribe to dataset state updates (from p2p pub-sub)
     transport_handle.subscribe(PING_TOPIC).await?;
+    // Subscribe to assignments published by the scheduler
+    transport_handle.subscribe(ASSIGNMENT_TOPIC).await?;
 
     // Instantiate contract client and check RPC connection
     let contract_client = contract_client::get_client(&args.rpc).await?;
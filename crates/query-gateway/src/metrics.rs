@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use lazy_static::lazy_static;
 use prometheus::{register_int_gauge, register_int_gauge_vec, IntGauge, IntGaugeVec, TextEncoder};
 
@@ -14,6 +16,12 @@ lazy_static! {
         &["worker_id"]
     )
     .unwrap();
+    static ref WORKERS_BY_VERSION: IntGaugeVec = register_int_gauge_vec!(
+        "workers_by_version",
+        "number of active workers running the given version",
+        &["version"]
+    )
+    .unwrap();
     static ref CURRENT_EPOCH: IntGauge =
         register_int_gauge!("current_epoch", "current epoch number").unwrap();
 }
@@ -38,6 +46,15 @@ pub fn spend_comp_units(worker_id: &str, spent_cus: u32) {
         .add(spent_cus as i64);
 }
 
+pub fn update_workers_by_version(by_version: HashMap<String, i64>) {
+    WORKERS_BY_VERSION.reset();
+    for (version, num_workers) in by_version {
+        WORKERS_BY_VERSION
+            .with_label_values(&[&version])
+            .set(num_workers);
+    }
+}
+
 pub fn gather_metrics() -> anyhow::Result<String> {
     Ok(TextEncoder::new().encode_to_string(&prometheus::gather())?)
 }
@@ -3,11 +3,12 @@ use std::collections::{HashMap, HashSet};
 use std::time::Instant;
 
 use rand::prelude::IteratorRandom;
+use semver::Version;
 use tabled::Tabled;
 
 use crate::config::{Config, DatasetId};
 use contract_client::Worker;
-use subsquid_messages::RangeSet;
+use subsquid_messages::{AssignmentSnapshot, RangeSet};
 use subsquid_network_transport::PeerId;
 
 #[derive(Default)]
@@ -44,6 +45,111 @@ impl DatasetState {
     }
 }
 
+/// Assignment of a dataset's data to workers, as announced by the scheduler
+struct DatasetAssignment {
+    timestamp_ms: u64,
+    received: Instant,
+    // worker -> assigned ranges
+    workers: HashMap<PeerId, RangeSet>,
+}
+
+/// Assignment of data to workers. The scheduler splits each snapshot into messages
+/// covering a part of a single dataset, all parts of a snapshot have the same timestamp.
+#[derive(Default)]
+struct Assignment {
+    datasets: HashMap<DatasetId, DatasetAssignment>,
+}
+
+impl Assignment {
+    fn from_snapshot(snapshot: AssignmentSnapshot) -> Self {
+        let worker_ids: Vec<Option<PeerId>> = snapshot
+            .worker_ids
+            .iter()
+            .map(|id| id.parse().ok())
+            .collect();
+        let received = Instant::now();
+        let datasets = snapshot
+            .datasets
+            .into_iter()
+            .map(|dataset| {
+                let mut worker_ranges: HashMap<PeerId, Vec<_>> = HashMap::new();
+                for unit in dataset.units {
+                    let range = match unit.range {
+                        Some(range) => range,
+                        None => continue,
+                    };
+                    for idx in unit.workers {
+                        if let Some(Some(worker_id)) = worker_ids.get(idx as usize) {
+                            worker_ranges.entry(*worker_id).or_default().push(range);
+                        }
+                    }
+                }
+                let workers = worker_ranges
+                    .into_iter()
+                    .map(|(worker_id, ranges)| (worker_id, ranges.into()))
+                    .collect();
+                let assignment = DatasetAssignment {
+                    timestamp_ms: snapshot.timestamp_ms,
+                    received,
+                    workers,
+                };
+                (DatasetId::from_url(dataset.url), assignment)
+            })
+            .collect();
+        Self { datasets }
+    }
+
+    /// Replace datasets with newer assignments, and add up parts of the same snapshot
+    fn merge(&mut self, other: Assignment) {
+        for (dataset_id, update) in other.datasets {
+            let current = match self.datasets.get_mut(&dataset_id) {
+                Some(current) if current.timestamp_ms == update.timestamp_ms => current,
+                Some(current) if current.timestamp_ms > update.timestamp_ms => {
+                    log::debug!("Ignoring outdated assignment of dataset {dataset_id}");
+                    continue;
+                }
+                _ => {
+                    self.datasets.insert(dataset_id, update);
+                    continue;
+                }
+            };
+            for (worker_id, ranges) in update.workers {
+                current
+                    .workers
+                    .entry(worker_id)
+                    .or_default()
+                    .extend(ranges.ranges);
+            }
+        }
+    }
+
+    /// Workers assigned to the dataset, unless the assignment is outdated
+    fn dataset_workers(&self, dataset_id: &DatasetId) -> Option<&HashMap<PeerId, RangeSet>> {
+        let inactive_threshold = Config::get().worker_inactive_threshold;
+        self.datasets
+            .get(dataset_id)
+            .filter(|a| a.received.elapsed() < inactive_threshold)
+            .map(|a| &a.workers)
+    }
+
+    fn is_assigned(&self, dataset_id: &DatasetId, worker_id: &PeerId, block: u32) -> bool {
+        self.dataset_workers(dataset_id)
+            .and_then(|workers| workers.get(worker_id))
+            .is_some_and(|ranges| ranges.has(block))
+    }
+
+    fn workers_with_block<'a>(
+        &'a self,
+        dataset_id: &DatasetId,
+        block: u32,
+    ) -> impl Iterator<Item = PeerId> + 'a {
+        self.dataset_workers(dataset_id)
+            .into_iter()
+            .flatten()
+            .filter_map(move |(worker_id, ranges)| ranges.has(block).then_some(*worker_id))
+    }
+}
+
 #[derive(Tabled)]
 pub struct DatasetSummary<'a> {
     #[tabled(rename = "dataset")]
@@ -78,33 +184,67 @@ pub struct NetworkState {
     worker_greylist: HashMap<PeerId, Instant>,
     workers_without_allocation: HashSet<PeerId>,
     registered_workers: HashSet<PeerId>,
+    worker_versions: HashMap<PeerId, Version>,
+    assignment: Assignment,
 }
 
 impl NetworkState {
     pub fn find_worker(&self, dataset_id: &DatasetId, start_block: u32) -> Option<PeerId> {
         log::debug!("Looking for worker dataset_id={dataset_id}, start_block={start_block}");
         let dataset_state = match self.dataset_states.get(dataset_id) {
-            None => return None,
+            None => return self.find_assigned_worker(dataset_id, start_block),
             Some(state) => state,
         };
+        let choose = |allow_greylisted: bool, assigned_only: bool| {
+            dataset_state
+                .get_workers_with_block(start_block)
+                .filter(|peer_id| self.worker_available(peer_id, allow_greylisted))
+                .filter(|peer_id| {
+                    !assigned_only
+                        || self
+                            .assignment
+                            .is_assigned(dataset_id, peer_id, start_block)
+                })
+                .choose(&mut rand::thread_rng())
+        };
 
-        // Choose a random active worker having the requested start_block
-        let mut worker = dataset_state
-            .get_workers_with_block(start_block)
-            .filter(|peer_id| self.worker_available(peer_id, false))
-            .choose(&mut rand::thread_rng());
+        // Choose a random active worker having the requested start_block. Workers which
+        // are still assigned the block go first, the data of the others may be stale.
+        let mut worker = choose(false, true).or_else(|| choose(false, false));
 
         // If no worker is found, try grey-listed workers
         if worker.is_none() {
-            worker = dataset_state
-                .get_workers_with_block(start_block)
-                .filter(|peer_id| self.worker_available(peer_id, true))
-                .choose(&mut rand::thread_rng());
+            worker = choose(true, false);
+        }
+
+        // Workers which haven't sent a ping yet (e.g. right after start) may have the block too
+        if worker.is_none() {
+            worker = self.find_assigned_worker(dataset_id, start_block);
         }
 
         worker
     }
 
+    fn find_assigned_worker(&self, dataset_id: &DatasetId, start_block: u32) -> Option<PeerId> {
+        self.assignment
+            .workers_with_block(dataset_id, start_block)
+            .filter(|peer_id| {
+                !self.last_pings.contains_key(peer_id)
+                    && self.registered_workers.contains(peer_id)
+                    && self.worker_has_allocation(peer_id)
+                    && !self.worker_greylisted(peer_id)
+            })
+            .choose(&mut rand::thread_rng())
+    }
+
+    pub fn update_assignment(&mut self, snapshot: AssignmentSnapshot) {
+        log::debug!(
+            "Updating assignment from snapshot {}",
+            snapshot.timestamp_ms
+        );
+        self.assignment.merge(Assignment::from_snapshot(snapshot));
+    }
+
     fn worker_available(&self, worker_id: &PeerId, allow_greylisted: bool) -> bool {
         self.registered_workers.contains(worker_id)
             && self.worker_has_allocation(worker_id)
@@ -155,9 +295,26 @@ impl NetworkState {
         }
     }
 
+    pub fn update_worker_version(&mut self, worker_id: PeerId, version: Version) {
+        self.worker_versions.insert(worker_id, version);
+    }
+
+    /// Number of active workers running each version
+    pub fn workers_by_version(&self) -> HashMap<String, i64> {
+        let mut result: HashMap<String, i64> = HashMap::new();
+        for (worker_id, version) in self.worker_versions.iter() {
+            if self.worker_active(worker_id) {
+                *result.entry(version.to_string()).or_default() += 1;
+            }
+        }
+        result
+    }
+
     pub fn update_registered_workers(&mut self, workers: Vec<Worker>) {
         log::debug!("Updating registered workers: {workers:?}");
         self.registered_workers = workers.into_iter().map(|w| w.peer_id).collect();
+        self.worker_versions
+            .retain(|worker_id, _| self.registered_workers.contains(worker_id));
     }
 
     pub fn greylist_worker(&mut self, worker_id: PeerId) {
@@ -178,3 +335,109 @@ impl NetworkState {
             .map(|(name, id)| DatasetSummary::new(name, self.dataset_states.get(id)))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use subsquid_messages::{DatasetAssignment as DatasetMsg, Range, UnitAssignment};
+
+    use super::*;
+
+    const DATASET_URL: &str = "s3://ethereum-mainnet-1";
+
+    fn snapshot(
+        timestamp_ms: u64,
+        workers: &[PeerId],
+        units: &[(u32, u32, &[u32])],
+    ) -> AssignmentSnapshot {
+        AssignmentSnapshot {
+            timestamp_ms,
+            worker_ids: workers.iter().map(ToString::to_string).collect(),
+            datasets: vec![DatasetMsg {
+                url: DATASET_URL.to_string(),
+                units: units
+                    .iter()
+                    .map(|(begin, end, workers)| UnitAssignment {
+                        range: Some(Range::new(*begin, *end)),
+                        workers: workers.to_vec(),
+                    })
+                    .collect(),
+            }],
+            signature: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_assignment_from_snapshot() {
+        Config::init_for_test();
+        let dataset_id = DatasetId::from_url(DATASET_URL);
+        let workers = [PeerId::random(), PeerId::random()];
+        let mut assignment = Assignment::from_snapshot(snapshot(
+            1,
+            &workers,
+            &[(0, 999, &[0, 1]), (1000, 1999, &[1]), (2000, 2999, &[7])],
+        ));
+        assert!(assignment.is_assigned(&dataset_id, &workers[0], 500));
+        assert!(!assignment.is_assigned(&dataset_id, &workers[0], 1500));
+        assert!(assignment.is_assigned(&dataset_id, &workers[1], 1500));
+        // Unknown worker indexes are ignored
+        assert_eq!(assignment.workers_with_block(&dataset_id, 2500).count(), 0);
+
+        // Parts of the same snapshot are merged
+        assignment.merge(Assignment::from_snapshot(snapshot(
+            1,
+            &workers,
+            &[(2000, 2999, &[0])],
+        )));
+        assert!(assignment.is_assigned(&dataset_id, &workers[0], 500));
+        assert!(assignment.is_assigned(&dataset_id, &workers[0], 2500));
+
+        // Older snapshots are ignored, newer ones replace the dataset's assignment
+        assignment.merge(Assignment::from_snapshot(snapshot(
+            0,
+            &workers,
+            &[(3000, 3999, &[0])],
+        )));
+        assert!(!assignment.is_assigned(&dataset_id, &workers[0], 3500));
+        assignment.merge(Assignment::from_snapshot(snapshot(
+            2,
+            &workers,
+            &[(3000, 3999, &[0])],
+        )));
+        assert!(assignment.is_assigned(&dataset_id, &workers[0], 3500));
+        assert!(!assignment.is_assigned(&dataset_id, &workers[0], 500));
+    }
+
+    #[test]
+    fn test_find_worker() {
+        Config::init_for_test();
+        let dataset_id = DatasetId::from_url(DATASET_URL);
+        let (assigned, unassigned, silent) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let mut state = NetworkState {
+            registered_workers: HashSet::from([assigned, unassigned, silent]),
+            ..Default::default()
+        };
+        for worker_id in [assigned, unassigned] {
+            let ranges =
+                HashMap::from([(dataset_id.clone(), RangeSet::from([Range::new(0, 999)]))]);
+            state.update_dataset_states(worker_id, ranges);
+        }
+
+        // Without an assignment any worker having the block is chosen
+        let found: HashSet<PeerId> = (0..100)
+            .filter_map(|_| state.find_worker(&dataset_id, 500))
+            .collect();
+        assert_eq!(found, HashSet::from([assigned, unassigned]));
+
+        // Workers still assigned the block go first
+        state.update_assignment(snapshot(1, &[assigned, silent], &[(0, 1999, &[0, 1])]));
+        for _ in 0..100 {
+            assert_eq!(state.find_worker(&dataset_id, 500), Some(assigned));
+        }
+        state.greylist_worker(assigned);
+        assert_eq!(state.find_worker(&dataset_id, 500), Some(unassigned));
+
+        // Workers which haven't pinged yet are only chosen by the assignment
+        assert_eq!(state.find_worker(&dataset_id, 1500), Some(silent));
+        assert_eq!(state.find_worker(&dataset_id, 2500), None);
+    }
+}
@@ -3,17 +3,16 @@ use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use lazy_static::lazy_static;
-use semver::VersionReq;
 use tabled::settings::Style;
 use tabled::Table;
 use tokio::sync::{mpsc, oneshot, RwLock};
 use tokio::task::JoinHandle;
 
 use subsquid_messages::signatures::SignedMessage;
+use subsquid_messages::version_policy::VersionStatus;
 use subsquid_messages::{
-    envelope::Msg, query_finished, query_result, Envelope, Ping, ProstMsg, Query as QueryMsg,
-    QueryFinished, QueryResult as QueryResultMsg, QuerySubmitted, SizeAndHash,
+    envelope::Msg, query_finished, query_result, AssignmentSnapshot, Envelope, Ping, ProstMsg,
+    Query as QueryMsg, QueryFinished, QueryResult as QueryResultMsg, QuerySubmitted, SizeAndHash,
 };
 use subsquid_network_transport::task_manager::{CancellationToken, TaskManager};
 use subsquid_network_transport::transport::P2PTransportHandle;
@@ -23,17 +22,13 @@ use crate::allocations::AllocationsManager;
 use crate::config::{Config, DatasetId};
 use crate::network_state::NetworkState;
 use crate::query::{Query, QueryResult};
-use crate::PING_TOPIC;
+use crate::{metrics, ASSIGNMENT_TOPIC, PING_TOPIC};
 
 pub type MsgContent = Box<[u8]>;
 pub type Message = subsquid_network_transport::Message<MsgContent>;
 
 const COMP_UNITS_PER_QUERY: u32 = 1;
 
-lazy_static! {
-    pub static ref SUPPORTED_WORKER_VERSIONS: VersionReq = ">=0.2.2".parse().unwrap();
-}
-
 #[derive(Debug)]
 struct Task {
     worker_id: PeerId,
@@ -127,6 +122,7 @@ impl Server {
         if !summary_print_interval.is_zero() {
             self.spawn_summary_task(summary_print_interval);
         }
+        self.spawn_versions_metrics_task(Config::get().worker_inactive_threshold / 2);
         loop {
             tokio::select! {
                 Some(query) = self.query_receiver.recv() => self.handle_query(query)
@@ -160,6 +156,17 @@ impl Server {
         self.task_manager.spawn_periodic(task, interval);
     }
 
+    fn spawn_versions_metrics_task(&mut self, interval: Duration) {
+        let network_state = self.network_state.clone();
+        let task = move |_| {
+            let network_state = network_state.clone();
+            async move {
+                metrics::update_workers_by_version(network_state.read().await.workers_by_version());
+            }
+        };
+        self.task_manager.spawn_periodic(task, interval);
+    }
+
     fn generate_query_id() -> String {
         uuid::Uuid::new_v4().to_string()
     }
@@ -304,6 +311,11 @@ impl Server {
             Some(Msg::Ping(ping)) if topic.as_ref().is_some_and(|t| t == PING_TOPIC) => {
                 self.ping(peer_id, ping).await
             }
+            Some(Msg::AssignmentSnapshot(snapshot))
+                if topic.as_ref().is_some_and(|t| t == ASSIGNMENT_TOPIC) =>
+            {
+                self.assignment_snapshot(peer_id, snapshot).await
+            }
             _ => log::debug!("Unexpected message received: {msg:?}"),
         }
         Ok(())
@@ -314,8 +326,14 @@ impl Server {
         log::trace!("Ping from {peer_id}: {ping:?}");
 
         let version = ping.sem_version();
-        if !SUPPORTED_WORKER_VERSIONS.matches(&version) {
-            return log::debug!("Worker {peer_id} version not supported: {}", version);
+        match Config::get().worker_versions.check(&version) {
+            VersionStatus::Supported => {}
+            VersionStatus::Deprecated { .. } => {
+                log::debug!("Worker {peer_id} runs deprecated version {version}")
+            }
+            VersionStatus::Unsupported => {
+                return log::debug!("Worker {peer_id} version not supported: {}", version);
+            }
         }
 
         let worker_state = ping
@@ -323,11 +341,21 @@ impl Server {
             .into_iter()
             .map(|r| (DatasetId::from_url(r.url), r.ranges.into()))
             .collect();
-        self.network_state
-            .write()
-            .await
-            .update_dataset_states(peer_id, worker_state);
+        let mut network_state = self.network_state.write().await;
+        network_state.update_worker_version(peer_id, version);
+        network_state.update_dataset_states(peer_id, worker_state);
     }
+
+    async fn assignment_snapshot(&mut self, peer_id: PeerId, mut snapshot: AssignmentSnapshot) {
+        if peer_id != Config::get().scheduler_id {
+            return log::warn!("Assignment snapshot received from {peer_id}, not the scheduler");
+        }
+        if !snapshot.verify_signature(&peer_id) {
+            return log::warn!("Invalid assignment snapshot signature");
+        }
+        self.network_state.write().await.update_assignment(snapshot);
+    }
+
     async fn query_result(
         &mut self,
         peer_id: PeerId,
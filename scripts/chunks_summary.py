@@ -3,13 +3,26 @@ import pprint
 import requests
 
 CHUNKS_URL = "https://scheduler.testnet.subsquid.io/chunks"
+PAGE_SIZE = 10000
+
+
+def get_all_chunks():
+    result = collections.defaultdict(list)
+    offset = 0
+    while True:
+        response = requests.get(CHUNKS_URL, params={'offset': offset, 'limit': PAGE_SIZE})
+        response.raise_for_status()
+        for dataset, ds_chunks in response.json().items():
+            result[dataset].extend(ds_chunks)
+        offset += PAGE_SIZE
+        if offset >= int(response.headers['X-Total-Count']):
+            return result
 
 
 def main():
     summary = {}
     replication = collections.Counter()
-    chunks = requests.get(CHUNKS_URL)
-    for dataset, ds_chunks in chunks.json().items():
+    for dataset, ds_chunks in get_all_chunks().items():
         summary[dataset] = {
             'num_chunks': len(ds_chunks),
             'total_size_gb': sum(chunk['size_bytes'] for chunk in ds_chunks) // (1024 ** 3)
@@ -3,44 +3,31 @@ import requests
 import sys
 from datetime import datetime
 
-PINGS_URL = "https://scheduler.testnet.subsquid.io/workers/pings"
-CHUNKS_URL = "https://scheduler.testnet.subsquid.io/chunks"
+WORKER_URL = "https://scheduler.testnet.subsquid.io/workers/{}"
 
 
 def main(worker_id):
-    print("Getting active workers...")
-    pings = requests.get(PINGS_URL)
-    pings.raise_for_status()
-    for ping in pings.json():
-        if ping['peer_id'] == worker_id:
-            status = {
-                'last_ping': datetime.fromtimestamp(ping['last_ping'] / 1000.0).isoformat(),
-                'version': ping['version'],
-                'stored_bytes': ping['stored_bytes'],
-                'jailed': ping['jailed'],
-                'reachable': ping['last_dial_ok'],
-            }
-            break
-    else:
-        print("Worker not active")
+    print("Getting worker status...")
+    response = requests.get(WORKER_URL.format(worker_id))
+    if response.status_code == 404:
+        print("Worker not registered")
         exit(1)
-
-    status['assigned_chunks_count'] = 0
-    status['assigned_chunks_size'] = 0
-    status['downloaded_chunks_count'] = 0
-    status['downloaded_chunks_size'] = 0
-
-    print("Getting assigned chunks...")
-    chunks = requests.get(CHUNKS_URL)
-    for ds_chunks in chunks.json().values():
-        for chunk in ds_chunks:
-            if worker_id in chunk['assigned_to']:
-                status['assigned_chunks_count'] += 1
-                status['assigned_chunks_size'] += chunk['size_bytes']
-            if worker_id in chunk['downloaded_by']:
-                status['downloaded_chunks_count'] += 1
-                status['downloaded_chunks_size'] += chunk['size_bytes']
-
+    response.raise_for_status()
+    worker = response.json()
+
+    status = {
+        'last_ping': datetime.fromtimestamp(worker['last_ping'] / 1000.0).isoformat(),
+        'version': worker['version'],
+        'stored_bytes': worker['stored_bytes'],
+        'jailed': worker['jailed'],
+        'jail_reason': worker['jail_reason_message'],
+        'reachable': worker['last_dial_ok'],
+        'assigned_chunks_count': worker['assigned_chunks_count'],
+        'assigned_chunks_size': worker['assigned_chunks_size'],
+        'downloaded_chunks_count': worker['downloaded_chunks_count'],
+        'downloaded_chunks_size': worker['downloaded_chunks_size'],
+        'missing_chunks': worker['missing_chunks'],
+    }
     pprint.pprint(status)
 
 